@@ -1,8 +1,9 @@
 //! Toor for generating a nix-index database.
+use std::collections::HashSet;
 use std::ffi::OsString;
 use std::io::{self, Write};
 use std::os::unix::ffi::OsStringExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 
 use clap::Parser;
@@ -56,8 +57,17 @@ async fn update_index(args: &Args) -> Result<()> {
     };
 
     eprint!("+ querying available packages");
-    let (files, watch) =
-        fetch_listings(&fetcher, args.jobs, &args.nixpkgs, systems, args.show_trace)?;
+    let skip = HashSet::new();
+    let (files, watch) = fetch_listings(
+        &fetcher,
+        args.jobs,
+        &args.nixpkgs,
+        Path::new("nix-env"),
+        systems,
+        args.show_trace,
+        &skip,
+        None,
+    )?;
 
     // Treat request errors as if the file list were missing
     let files = files.map(|r| {
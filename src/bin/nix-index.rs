@@ -1,21 +1,24 @@
 //! Tool for generating a nix-index database.
+// See the comment in lib.rs, the same applies to the error-chain errors of this binary.
+#![allow(clippy::result_large_err)]
 use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 
 use clap::Parser;
 use error_chain::ChainedError;
 use futures::future::Either;
 use futures::{future, StreamExt};
-use nix_index::database::Writer;
+use nix_index::database::{self, Codec, Reader, Writer, WriterBuilder, DEFAULT_APPLICATION_TAG};
 use nix_index::errors::*;
 use nix_index::files::FileTree;
 use nix_index::hydra::Fetcher;
 use nix_index::listings::{fetch_listings, try_load_paths_cache};
 use nix_index::package::StorePath;
 use nix_index::CACHE_URL;
+use regex::Regex;
 use separator::Separatable;
 
 /// The main function of this module: creates a new nix-index database.
@@ -30,6 +33,43 @@ async fn update_index(args: &Args) -> Result<()> {
         None
     };
 
+    // When updating, open the existing database first so that we only fetch the file listings
+    // of store paths that it does not contain yet.
+    let db_path = args.database.join("files");
+    let existing = if args.update && db_path.exists() {
+        eprintln!("+ reading existing index");
+        match Writer::append(&db_path, args.compression_level) {
+            Ok(db) => {
+                if db.codec() != args.codec {
+                    eprintln!(
+                        "warning: the existing index is compressed with {}, which is also used for the new packages instead of {}",
+                        db.codec(),
+                        args.codec
+                    );
+                }
+                if args.exact_index && !db.has_exact_index() {
+                    eprintln!("warning: the existing index has no exact path index and --exact-index only applies when it is rebuilt without --update");
+                }
+                Some(db)
+            }
+            // the entries of older databases cannot be appended to, so build a new one instead
+            Err(database::Error(database::ErrorKind::UnsupportedVersion(version), _)) => {
+                eprintln!(
+                    "+ existing index has the old format version {}, rebuilding it",
+                    version
+                );
+                None
+            }
+            Err(e) => return Err(e).chain_err(|| ErrorKind::ReadDatabase(args.database.clone())),
+        }
+    } else {
+        None
+    };
+    let skip = existing
+        .as_ref()
+        .map(|db| db.store_path_hashes().clone())
+        .unwrap_or_default();
+
     eprintln!("+ querying available packages");
     let fetcher = Fetcher::new(CACHE_URL.to_string()).map_err(ErrorKind::ParseProxy)?;
     let (files, watch) = match cached {
@@ -39,8 +79,11 @@ async fn update_index(args: &Args) -> Result<()> {
                 &fetcher,
                 args.jobs,
                 &args.nixpkgs,
+                Path::new("nix-env"),
                 vec![args.system.as_deref()],
                 args.show_trace,
+                &skip,
+                args.filter_attr.as_ref(),
             )?;
             (Either::Right(f), w)
         }
@@ -76,10 +119,19 @@ async fn update_index(args: &Args) -> Result<()> {
         eprint!(" (filtering by `{}`)", args.filter_prefix);
     }
     eprint!("\r");
-    fs::create_dir_all(&args.database)
-        .chain_err(|| ErrorKind::CreateDatabaseDir(args.database.clone()))?;
-    let mut db = Writer::create(args.database.join("files"), args.compression_level)
-        .chain_err(|| ErrorKind::CreateDatabase(args.database.clone()))?;
+    let mut db = match existing {
+        Some(db) => db,
+        None => {
+            fs::create_dir_all(&args.database)
+                .chain_err(|| ErrorKind::CreateDatabaseDir(args.database.clone()))?;
+            WriterBuilder::new()
+                .codec(args.codec)
+                .level(args.compression_level)
+                .exact_index(args.exact_index)
+                .build(&db_path)
+                .chain_err(|| ErrorKind::CreateDatabase(args.database.clone()))?
+        }
+    };
 
     let mut results: Vec<(StorePath, String, FileTree)> = Vec::new();
     while let Some(entry) = files.next().await {
@@ -87,6 +139,9 @@ async fn update_index(args: &Args) -> Result<()> {
             results.push(entry.clone());
         }
         let (path, _, files) = entry;
+        if db.store_path_hashes().contains(path.hash().as_ref()) {
+            continue;
+        }
         db.add(path, files, args.filter_prefix.as_bytes())
             .chain_err(|| ErrorKind::WriteDatabase(args.database.clone()))?;
     }
@@ -108,6 +163,26 @@ async fn update_index(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Prints statistics about the existing database, for `--info`.
+fn print_info(args: &Args) -> Result<()> {
+    let stats = Reader::open(args.database.join("files"))
+        .and_then(|mut db| db.stats())
+        .chain_err(|| ErrorKind::ReadDatabase(args.database.clone()))?;
+
+    println!("format version:  {}", stats.version);
+    if stats.application_tag != DEFAULT_APPLICATION_TAG {
+        println!("application tag: {}", stats.application_tag.escape_ascii());
+    }
+    println!("codec:           {}", stats.codec);
+    println!(
+        "size:            {} bytes",
+        stats.compressed_size.separated_string()
+    );
+    println!("packages:        {}", stats.packages.separated_string());
+    println!("files:           {}", stats.files.separated_string());
+    Ok(())
+}
+
 fn cache_dir() -> &'static OsStr {
     let base = xdg::BaseDirectories::with_prefix("nix-index").unwrap();
     let cache_dir = Box::new(base.get_cache_home());
@@ -135,18 +210,41 @@ struct Args {
     #[clap(short = 's', long, value_name = "platform")]
     system: Option<String>,
 
-    /// Zstandard compression level
+    /// Compression level (0 to 22 for zstd, 0 to 9 for xz and gzip, ignored for none)
     #[clap(short, long = "compression", default_value = "22")]
     compression_level: i32,
 
+    /// Compression codec used for the database
+    #[clap(long, value_enum, default_value = "zstd")]
+    codec: Codec,
+
+    /// Also build an index for fast lookups of exact paths. Makes the database slightly larger.
+    #[clap(long)]
+    exact_index: bool,
+
+    /// Only add store paths that are not in the existing database yet, instead of rebuilding it.
+    ///
+    /// Store paths that are no longer part of nixpkgs are kept in the database.
+    #[clap(long)]
+    update: bool,
+
+    /// Print statistics about the existing database instead of building a new one
+    #[clap(long)]
+    info: bool,
+
     /// Show a stack trace in the case of a Nix evaluation error
     #[clap(long)]
     show_trace: bool,
 
-    /// Only add paths starting with PREFIX (e.g. `/bin/`)
+    /// Only add paths starting with PREFIX (e.g. `/bin/`, which also keeps the `/bin` directory)
     #[clap(long, default_value = "")]
     filter_prefix: String,
 
+    /// Only index packages whose attribute path matches REGEX (e.g. `^python3Packages\.`),
+    /// and the store paths they depend on
+    #[clap(long, value_name = "REGEX", value_parser = Regex::new)]
+    filter_attr: Option<Regex>,
+
     /// Store and load results of fetch phase in a file called paths.cache. This speeds up testing
     /// different database formats / compression.
     ///
@@ -159,7 +257,13 @@ struct Args {
 async fn main() {
     let args = Args::parse();
 
-    if let Err(e) = update_index(&args).await {
+    let result = if args.info {
+        print_info(&args)
+    } else {
+        update_index(&args).await
+    };
+
+    if let Err(e) = result {
         eprintln!("error: {}", e);
 
         for e in e.iter().skip(1) {
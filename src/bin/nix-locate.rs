@@ -9,7 +9,7 @@ use std::str::FromStr;
 
 use clap::{value_parser, Parser};
 use error_chain::error_chain;
-use nix_index::database;
+use nix_index::database::{self, Match};
 use nix_index::files::{self, FileTreeEntry, FileType};
 use owo_colors::{OwoColorize, Stream};
 use regex::bytes::Regex;
@@ -64,11 +64,16 @@ fn locate(args: &Args) -> Result<()> {
         .query(&pattern)
         .package_pattern(package_pattern.as_ref())
         .hash(args.hash.clone())
+        .sorted(true)
         .run()
         .chain_err(|| ErrorKind::Grep(args.pattern.clone()))?
         .filter(|v| {
-            v.as_ref().ok().map_or(true, |v| {
-                let &(ref store_path, FileTreeEntry { ref path, ref node }) = v;
+            v.as_ref().ok().is_none_or(|v| {
+                let Match {
+                    store_path,
+                    entry: FileTreeEntry { path, node },
+                    ..
+                } = v;
                 let m = pattern
                     .find_iter(path)
                     .last()
@@ -86,8 +91,11 @@ fn locate(args: &Args) -> Result<()> {
 
     let mut printed_attrs = HashSet::new();
     for v in results {
-        let (store_path, FileTreeEntry { path, node }) =
-            v.chain_err(|| ErrorKind::ReadDatabase(index_file.clone()))?;
+        let Match {
+            store_path,
+            entry: FileTreeEntry { path, node },
+            ..
+        } = v.chain_err(|| ErrorKind::ReadDatabase(index_file.clone()))?;
 
         use crate::files::FileNode::*;
         let (typ, size) = match node {
@@ -117,7 +125,7 @@ fn locate(args: &Args) -> Result<()> {
                 attr,
                 size.separated_string(),
                 typ,
-                store_path.as_str()
+                store_path
             );
 
             let path = String::from_utf8_lossy(&path);
@@ -139,9 +147,15 @@ fn locate(args: &Args) -> Result<()> {
                     );
                     prev = mat.end();
                 }
-                println!("{}", &path[prev..]);
+                print!("{}", &path[prev..]);
             } else {
-                println!("{}", path);
+                print!("{}", path);
+            }
+
+            if let Symlink { ref target } = node {
+                println!(" -> {}", String::from_utf8_lossy(target));
+            } else {
+                println!();
             }
         }
     }
@@ -1,93 +1,848 @@
-use std::fs::File;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 /// Creating and searching file databases.
 ///
 /// This module implements an abstraction for creating an index of files with meta information
 /// and searching that index for paths matching a specific pattern.
-use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
-use std::path::Path;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use error_chain::error_chain;
 use grep;
-use grep::matcher::{LineMatchKind, Match, Matcher, NoError};
-use memchr::{memchr, memrchr};
-use regex::bytes::Regex;
+use grep::matcher::{LineMatchKind, Match as LineMatch, Matcher, NoError};
+use indexmap::IndexMap;
+use memchr::{memchr, memmem, memrchr};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use regex::bytes::{Regex, RegexBuilder};
 use regex_syntax::ast::{
-    Alternation, Assertion, AssertionKind, Ast, Concat, Group, Literal, Repetition,
+    Alternation, Assertion, AssertionKind, Ast, Concat, Flags, Group, GroupKind, Literal,
+    Repetition,
 };
+use regex_syntax::hir::{self, HirKind};
+use serde::Serialize;
 use serde_json;
+use sha2::{Digest, Sha256};
 use zstd;
 
-use crate::files::{FileTree, FileTreeEntry};
+use crate::files::{unescape_path, FileNode, FileTree, FileTreeEntry, FileType};
 use crate::frcode;
-use crate::package::StorePath;
+use crate::package::{PathOrigin, StorePath};
 
 /// The version of the database format supported by this nix-index version.
 ///
 /// This should be updated whenever you make an incompatible change to the database format.
-const FORMAT_VERSION: u64 = 1;
+const FORMAT_VERSION: u64 = 10;
+
+/// The oldest version of the database format that can still be read.
+///
+/// Version 1 databases are always compressed with zstd, version 2 added the codec tag to the
+/// header, version 3 added the exact path index offset, version 4 started escaping NUL bytes
+/// and newlines in paths, version 5 added the block size to the header, version 6 stores the
+/// entries in checksummed blocks, version 7 added the footer, version 8 added the
+/// application tag, version 9 added the number of packages and entries to the footer and
+/// version 10 added the content hash to the footer.
+const MIN_FORMAT_VERSION: u64 = 1;
+
+/// The first version of the database format that escapes paths (see `files::escape_path`).
+const ESCAPED_PATHS_VERSION: u64 = 4;
 
 /// The magic for nix-index database files, used to ensure that the file we're passed is
 /// actually a file generated by nix-index.
 const FILE_MAGIC: &[u8] = b"NIXI";
 
+/// The first version of the database format that records the block size in the header.
+const BLOCK_SIZE_VERSION: u64 = 5;
+
+/// The first version of the database format that stores the entries in checksummed blocks
+/// (see `frcode::BlockWriter`).
+const CHECKSUMS_VERSION: u64 = 6;
+
+/// The first version of the database format that has a footer (see `Footer`).
+const FOOTER_VERSION: u64 = 7;
+
+/// The first version of the database format that records an application tag in the header.
+const APPLICATION_TAG_VERSION: u64 = 8;
+
+/// The first version of the database format that records the number of packages and entries
+/// in the footer.
+const COUNTS_VERSION: u64 = 9;
+
+/// The first version of the database format that records a hash of the compressed entries in
+/// the footer.
+const CONTENT_HASH_VERSION: u64 = 10;
+
+/// The application tag of databases created by nix-index itself.
+///
+/// Tools that store additional data in their databases can use a different tag with
+/// `Writer::create_with_tag`, so that `Reader::application_tag` tells their databases apart.
+/// Databases created before the tag was added to the format also have this tag.
+pub const DEFAULT_APPLICATION_TAG: [u8; 4] = [0; 4];
+
+/// The position of the exact path index offset in the header.
+///
+/// The header consists of the file magic, the format version, the application tag, the codec
+/// tag, the offset of the exact path index (or zero, if the database has no such index), the
+/// block size and the offset of the footer (or zero, if the database was not finished).
+const INDEX_OFFSET_POS: u64 = 17;
+
+/// The position of the footer offset in the header.
+const FOOTER_OFFSET_POS: u64 = INDEX_OFFSET_POS + 8 + 4;
+
+/// The length of the header. The compressed entries start right after it.
+const HEADER_LEN: u64 = FOOTER_OFFSET_POS + 8;
+
+/// The approximate amount of uncompressed data in each independently compressed frame when
+/// an exact path index is built.
+///
+/// Smaller frames make exact lookups faster, but reduce the compression ratio.
+const EXACT_INDEX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// The compression codec used for the contents of a database.
+///
+/// The codec is recorded in the header of the database, so the reader can pick the matching
+/// decoder automatically.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Codec {
+    /// Zstandard compression. This is the default, since it decompresses very fast.
+    Zstd,
+    /// xz (LZMA2) compression. Produces smaller databases, but queries are much slower.
+    Xz,
+    /// gzip compression, for environments where zstd is not available.
+    Gzip,
+    /// No compression: the frcode blocks are stored as they are.
+    ///
+    /// Uncompressed databases are many times larger and slower to query from disk, so this is
+    /// only meant for debugging the frcode layer, for tests and for tools that want to read the
+    /// raw entries themselves.
+    None,
+}
+
+impl Codec {
+    /// The byte that identifies this codec in the database header.
+    fn tag(self) -> u8 {
+        match self {
+            Codec::Zstd => 1,
+            Codec::Xz => 2,
+            Codec::Gzip => 3,
+            Codec::None => 4,
+        }
+    }
+
+    /// Returns the codec identified by the given header byte, if it is known.
+    fn from_tag(tag: u8) -> Option<Codec> {
+        match tag {
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Xz),
+            3 => Some(Codec::Gzip),
+            4 => Some(Codec::None),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+            Codec::Gzip => "gzip",
+            Codec::None => "none",
+        })
+    }
+}
+
+/// Sets the number of worker threads that compress the data of `encoder`.
+#[cfg(not(target_arch = "wasm32"))]
+fn set_zstd_threads(
+    encoder: &mut zstd::Encoder<'static, HashingFile>,
+    threads: u32,
+) -> io::Result<()> {
+    encoder.multithread(threads)
+}
+
+/// There are no threads on WebAssembly, so zstd always compresses on the calling thread.
+#[cfg(target_arch = "wasm32")]
+fn set_zstd_threads(_: &mut zstd::Encoder<'static, HashingFile>, _: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Returns the default number of zstd worker threads, which is the number of CPUs.
+#[cfg(not(target_arch = "wasm32"))]
+fn default_threads() -> u32 {
+    num_cpus::get() as u32
+}
+
+/// Returns the default number of zstd worker threads. WebAssembly has no threads, so
+/// multithreading is disabled there.
+#[cfg(target_arch = "wasm32")]
+fn default_threads() -> u32 {
+    0
+}
+
+/// The file of a database that is being written, which hashes the compressed entries while
+/// they are written, for the content hash in the footer.
+struct HashingFile {
+    file: File,
+    /// The hash of everything that was written through this writer.
+    hasher: Sha256,
+}
+
+impl Write for HashingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.file.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The compressing encoder for one of the supported codecs.
+enum Compressor {
+    Zstd(zstd::Encoder<'static, HashingFile>),
+    Xz(xz2::write::XzEncoder<HashingFile>),
+    Gzip(flate2::write::GzEncoder<HashingFile>),
+    None(BufWriter<HashingFile>),
+}
+
+impl Compressor {
+    /// Creates a new encoder for `codec` with the given compression level.
+    ///
+    /// xz and gzip only support levels from 0 to 9, so the level is clamped to that range for them.
+    /// `threads` is the number of zstd worker threads and is ignored for the other codecs.
+    /// Without compression, the data is only buffered before it is written to `file`.
+    fn new(file: HashingFile, codec: Codec, level: i32, threads: u32) -> io::Result<Compressor> {
+        let basic_level = level.clamp(0, 9) as u32;
+        Ok(match codec {
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, level)?;
+                set_zstd_threads(&mut encoder, threads)?;
+                Compressor::Zstd(encoder)
+            }
+            Codec::Xz => Compressor::Xz(xz2::write::XzEncoder::new(file, basic_level)),
+            Codec::Gzip => Compressor::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::new(basic_level),
+            )),
+            Codec::None => Compressor::None(BufWriter::new(file)),
+        })
+    }
+
+    /// Finishes the compressed stream and returns the underlying file.
+    fn finish(self) -> io::Result<HashingFile> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.finish(),
+            Compressor::Xz(encoder) => encoder.finish(),
+            Compressor::Gzip(encoder) => encoder.finish(),
+            Compressor::None(writer) => writer.into_inner().map_err(io::IntoInnerError::into_error),
+        }
+    }
+}
+
+impl Write for Compressor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.write(buf),
+            Compressor::Xz(encoder) => encoder.write(buf),
+            Compressor::Gzip(encoder) => encoder.write(buf),
+            Compressor::None(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.flush(),
+            Compressor::Xz(encoder) => encoder.flush(),
+            Compressor::Gzip(encoder) => encoder.flush(),
+            Compressor::None(writer) => writer.flush(),
+        }
+    }
+}
+
 /// A writer for creating a new file database.
+///
+/// The database must be completed by calling `finish`, which reports any IO error that occurs
+/// while flushing the compressed data. A writer that is dropped without being finished is still
+/// completed, but errors are then ignored.
 pub struct Writer {
-    /// The encoder used to compress the database. Will be set to `None` when the value
-    /// is dropped.
-    writer: Option<BufWriter<zstd::Encoder<'static, File>>>,
+    /// The encoder used to compress the database, wrapped in a writer that adds checksums to
+    /// the encoded data. Will be set to `None` when the writer is finished.
+    writer: Option<frcode::BlockWriter<Compressor>>,
+    /// The hashes of all store paths that were added so far, used to skip duplicates when
+    /// appending other databases.
+    hashes: HashSet<String>,
+    /// The compression settings, needed to start new compressed frames.
+    codec: Codec,
+    level: i32,
+    threads: u32,
+    /// A block is ended after the first package that makes it at least this large.
+    block_size: usize,
+    /// The offsets of the blocks in the decompressed entries, and the length of the
+    /// decompressed entries written so far (not counting the current frame).
+    footer: Footer,
+    /// The exact path index, if one is built for this database.
+    exact_index: Option<ExactIndex>,
+    /// The approximate amount of uncompressed data written to the current frame.
+    frame_size: usize,
+    /// The file and the end of its entries after `checkpoint` was called, until more
+    /// packages are added. `writer` is `None` in the meantime.
+    checkpointed: Option<(HashingFile, u64)>,
+    /// For writers created by `append`, the temporary file that is written to and the
+    /// database that it replaces once the writer is finished.
+    replaces: Option<(PathBuf, PathBuf)>,
 }
 
 // We need to make sure that the encoder is `finish`ed in all cases, so we need
-// a custom Drop.
+// a custom Drop. Panicking here would abort the process if we're already unwinding,
+// so errors are only reported.
 impl Drop for Writer {
     fn drop(&mut self) {
-        if self.writer.is_some() {
-            self.finish_encoder().unwrap();
+        // after `finish`, there is neither an encoder nor a checkpoint left. A writer that was
+        // only checkpointed must still be finished, so that an appended database replaces the
+        // original one.
+        if self.writer.is_none() && self.checkpointed.is_none() {
+            return;
+        }
+        if std::thread::panicking() {
+            // the database is incomplete anyway, so there is no point in finishing it. When
+            // appending, the original database is left as it was.
+            if let Some((temp, _)) = self.replaces.take() {
+                let _ = fs::remove_file(temp);
+            }
+            return;
+        }
+        // errors cannot be reported from here, use `finish` to handle them
+        let _ = self.finish_encoder();
+    }
+}
+
+/// Options for creating a new database with `WriterBuilder::build`.
+///
+/// By default, databases are compressed with zstd at level 22 using one worker thread per
+/// CPU (or none on WebAssembly), are decoded in blocks of `frcode::DEFAULT_BLOCK_SIZE`, have no exact path index and
+/// record `DEFAULT_APPLICATION_TAG`.
+///
+/// # Example
+///
+/// ```no_run
+/// use nix_index::database::{Codec, WriterBuilder};
+///
+/// let writer = WriterBuilder::new()
+///     .codec(Codec::Xz)
+///     .level(9)
+///     .exact_index(true)
+///     .build("/tmp/files")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriterBuilder {
+    codec: Codec,
+    level: i32,
+    threads: u32,
+    block_size: usize,
+    exact_index: bool,
+    application_tag: [u8; 4],
+}
+
+impl Default for WriterBuilder {
+    fn default() -> WriterBuilder {
+        WriterBuilder {
+            codec: Codec::Zstd,
+            level: 22,
+            threads: default_threads(),
+            block_size: frcode::DEFAULT_BLOCK_SIZE,
+            exact_index: false,
+            application_tag: DEFAULT_APPLICATION_TAG,
+        }
+    }
+}
+
+impl WriterBuilder {
+    /// Creates a builder with the default options.
+    pub fn new() -> WriterBuilder {
+        WriterBuilder::default()
+    }
+
+    /// Compress the database with `codec`.
+    pub fn codec(self, codec: Codec) -> WriterBuilder {
+        WriterBuilder { codec, ..self }
+    }
+
+    /// Compress the database at the given level.
+    ///
+    /// For zstd, supported levels range from 0 to 22. xz and gzip support levels from 0 to 9,
+    /// higher levels are treated as 9. The level is ignored for `Codec::None`.
+    pub fn level(self, level: i32) -> WriterBuilder {
+        WriterBuilder { level, ..self }
+    }
+
+    /// Use `threads` worker threads for zstd compression.
+    ///
+    /// Passing 0 disables multithreading, so compression happens on the calling thread. The
+    /// other codecs always compress on the calling thread.
+    pub fn threads(self, threads: u32) -> WriterBuilder {
+        WriterBuilder { threads, ..self }
+    }
+
+    /// Record a different size for the blocks in which the database is decoded when it is
+    /// searched.
+    ///
+    /// Larger blocks make broad queries slightly faster, while smaller blocks need less memory
+    /// and let limited or cancelled queries stop sooner. The entries are also stored in
+    /// checksummed blocks of about this size. The block size barely changes the compression
+    /// ratio. It must fit into a `u32` and cannot be zero.
+    pub fn block_size(self, block_size: usize) -> WriterBuilder {
+        WriterBuilder { block_size, ..self }
+    }
+
+    /// Build an exact path index if `true`, see `Writer::enable_exact_index`.
+    pub fn exact_index(self, exact_index: bool) -> WriterBuilder {
+        WriterBuilder {
+            exact_index,
+            ..self
         }
     }
+
+    /// Record `application_tag` in the header instead of `DEFAULT_APPLICATION_TAG`.
+    ///
+    /// Tools that produce modified databases, for example with extra metadata, can use their
+    /// own tag, so that they can recognize their databases with `Reader::application_tag`.
+    /// Databases are readable regardless of their tag.
+    pub fn application_tag(self, application_tag: [u8; 4]) -> WriterBuilder {
+        WriterBuilder {
+            application_tag,
+            ..self
+        }
+    }
+
+    /// Creates a new database at the given path with these options.
+    pub fn build<P: AsRef<Path>>(&self, path: P) -> io::Result<Writer> {
+        let block_size = u32::try_from(self.block_size)
+            .ok()
+            .filter(|&size| size > 0)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid database block size")
+            })?;
+
+        let mut file = File::create(path)?;
+        file.write_all(FILE_MAGIC)?;
+        file.write_u64::<LittleEndian>(FORMAT_VERSION)?;
+        file.write_all(&self.application_tag)?;
+        file.write_u8(self.codec.tag())?;
+        file.write_u64::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(block_size)?;
+        file.write_u64::<LittleEndian>(0)?;
+        let file = HashingFile {
+            file,
+            hasher: Sha256::new(),
+        };
+        let encoder = Compressor::new(file, self.codec, self.level, self.threads)?;
+
+        let mut writer = Writer {
+            writer: Some(frcode::BlockWriter::new(encoder)),
+            hashes: HashSet::new(),
+            codec: self.codec,
+            level: self.level,
+            threads: self.threads,
+            block_size: block_size as usize,
+            footer: Footer {
+                counts: Some((0, 0)),
+                ..Footer::default()
+            },
+            exact_index: None,
+            frame_size: 0,
+            checkpointed: None,
+            replaces: None,
+        };
+        if self.exact_index {
+            writer.enable_exact_index()?;
+        }
+        Ok(writer)
+    }
 }
 
 impl Writer {
     /// Creates a new database at the given path with the specified zstd compression level
     /// (currently, supported values range from 0 to 22).
+    ///
+    /// This is a shortcut for `WriterBuilder::new().level(level).build(path)`, see
+    /// `WriterBuilder` for more options.
     pub fn create<P: AsRef<Path>>(path: P, level: i32) -> io::Result<Writer> {
-        let mut file = File::create(path)?;
-        file.write_all(FILE_MAGIC)?;
-        file.write_u64::<LittleEndian>(FORMAT_VERSION)?;
-        let mut encoder = zstd::Encoder::new(file, level)?;
-        encoder.multithread(num_cpus::get() as u32)?;
+        WriterBuilder::new().level(level).build(path)
+    }
+
+    /// Creates a new zstd compressed database like `create`, but uses `threads` worker threads
+    /// for compression instead of one per CPU.
+    ///
+    /// Passing 0 disables multithreading, so compression happens on the calling thread.
+    pub fn create_with_threads<P: AsRef<Path>>(
+        path: P,
+        level: i32,
+        threads: u32,
+    ) -> io::Result<Writer> {
+        WriterBuilder::new()
+            .level(level)
+            .threads(threads)
+            .build(path)
+    }
+
+    /// Creates a new database at the given path that is compressed with `codec` at the
+    /// specified compression level.
+    ///
+    /// For zstd, supported levels range from 0 to 22. xz and gzip support levels from 0 to 9,
+    /// higher levels are treated as 9. The level is ignored for `Codec::None`.
+    pub fn create_with_codec<P: AsRef<Path>>(
+        path: P,
+        codec: Codec,
+        level: i32,
+    ) -> io::Result<Writer> {
+        WriterBuilder::new().codec(codec).level(level).build(path)
+    }
+
+    /// Creates a new zstd compressed database like `create`, but records a different size for
+    /// the blocks in which the database is decoded when it is searched.
+    ///
+    /// The default is `frcode::DEFAULT_BLOCK_SIZE`, see `WriterBuilder::block_size`.
+    pub fn create_with_block_size<P: AsRef<Path>>(
+        path: P,
+        level: i32,
+        block_size: usize,
+    ) -> io::Result<Writer> {
+        WriterBuilder::new()
+            .level(level)
+            .block_size(block_size)
+            .build(path)
+    }
+
+    /// Creates a new zstd compressed database like `create`, but records `application_tag`
+    /// in the header instead of `DEFAULT_APPLICATION_TAG`, see `WriterBuilder::application_tag`.
+    pub fn create_with_tag<P: AsRef<Path>>(
+        path: P,
+        level: i32,
+        application_tag: [u8; 4],
+    ) -> io::Result<Writer> {
+        WriterBuilder::new()
+            .level(level)
+            .application_tag(application_tag)
+            .build(path)
+    }
+
+    /// Opens the existing database at the given path for appending more packages.
+    ///
+    /// New packages are compressed with the codec of the existing database at the given level.
+    /// The hashes of the store paths that are already in the database are available through
+    /// `store_path_hashes`, so only new store paths need to be added.
+    ///
+    /// The database is copied to a temporary file next to it, which the new packages are
+    /// written to. Once the writer is finished, the temporary file replaces the database, so
+    /// the database stays intact if the process fails before that.
+    pub fn append<P: AsRef<Path>>(path: P, level: i32) -> Result<Writer> {
+        let path = path.as_ref();
+        let mut reader = Reader::open(path)?;
+        // older databases store paths differently, so new entries cannot be appended to them
+        if reader.version != FORMAT_VERSION {
+            return Err(ErrorKind::UnsupportedVersion(reader.version).into());
+        }
+        let hashes = reader.store_path_hashes()?;
+        let mut exact_index = reader.exact_index()?.cloned();
+        let footer = reader.footer()?.clone();
+
+        // All supported codecs can decode a concatenation of compressed streams, and the
+        // frcode stream ends with a package footer that resets the shared prefix, so we can
+        // just start a new compressed stream at the end of the entries.
+        //
+        // An existing exact path index and the footer are dropped from the copy and written
+        // again when the writer is finished.
+        let file_name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "database path has no file name",
+            )
+        })?;
+        let mut temp_name = file_name.to_owned();
+        temp_name.push(format!(".append-{}", std::process::id()));
+        let temp = path.with_file_name(temp_name);
+        fs::copy(path, &temp)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(&temp)?;
+        if let Some(end) = data_end(reader.index_offset, reader.footer_offset) {
+            file.set_len(end)?;
+        }
+        file.seek(SeekFrom::Start(INDEX_OFFSET_POS))?;
+        file.write_u64::<LittleEndian>(0)?;
+        file.seek(SeekFrom::Start(FOOTER_OFFSET_POS))?;
+        file.write_u64::<LittleEndian>(0)?;
+        if let Some(index) = exact_index.as_mut() {
+            index.frames.push(reader.index_offset);
+        }
+        // the new entries are hashed while they are written, so only the existing entries
+        // need to be read again
+        let mut hasher = Sha256::new();
+        file.seek(SeekFrom::Start(HEADER_LEN))?;
+        io::copy(&mut file, &mut hasher)?;
+        let file = HashingFile { file, hasher };
+
+        let threads = default_threads();
+        let encoder = Compressor::new(file, reader.codec, level, threads)?;
 
         Ok(Writer {
-            writer: Some(BufWriter::new(encoder)),
+            writer: Some(frcode::BlockWriter::new(encoder)),
+            hashes,
+            codec: reader.codec,
+            level,
+            threads,
+            block_size: reader.block_size,
+            footer,
+            exact_index,
+            frame_size: 0,
+            checkpointed: None,
+            replaces: Some((temp, path.to_path_buf())),
         })
     }
 
+    /// Builds an exact path index for this database, which makes `Reader::lookup_exact` fast.
+    ///
+    /// The index is written when the writer is finished. With an index, the database is
+    /// compressed in multiple independent frames, so it becomes slightly larger.
+    ///
+    /// This must be called before any packages are added.
+    pub fn enable_exact_index(&mut self) -> io::Result<()> {
+        if self.exact_index.is_some() {
+            return Ok(());
+        }
+        if !self.hashes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the exact path index must be enabled before adding packages",
+            ));
+        }
+        self.exact_index = Some(ExactIndex {
+            frames: vec![HEADER_LEN],
+            entries: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Returns the codec that the packages are compressed with.
+    ///
+    /// For a writer created with `append`, this is the codec of the existing database.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns whether an exact path index is written, see `enable_exact_index`.
+    pub fn has_exact_index(&self) -> bool {
+        self.exact_index.is_some()
+    }
+
+    /// Returns the hashes of all store paths that were added to this database so far.
+    pub fn store_path_hashes(&self) -> &HashSet<String> {
+        &self.hashes
+    }
+
     /// Add a new package to the database for the given store path with its corresponding
     /// file tree. Entries are only added if they match `filter_prefix`.
+    ///
+    /// Directories are added as entries of their own, see `FileTree::to_list`. Returns the
+    /// number of entries that were added.
     pub fn add(
         &mut self,
         path: StorePath,
         files: FileTree,
         filter_prefix: &[u8],
-    ) -> io::Result<()> {
+    ) -> io::Result<u64> {
+        self.add_entries(&path, files.to_list(filter_prefix))
+    }
+
+    /// Adds all packages from the database at `other` to this database.
+    ///
+    /// Packages whose store path hash was already added to this database are skipped, so
+    /// appending the indexes of two channels that share packages does not duplicate entries.
+    pub fn append_database<P: AsRef<Path>>(&mut self, other: P) -> Result<()> {
+        let mut reader = Reader::open(other)?;
+        reader.for_each_package(|path, entries| {
+            if !self.hashes.contains(path.hash().as_ref()) {
+                self.add_entries(&path, entries)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn add_entries<I>(&mut self, path: &StorePath, entries: I) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = FileTreeEntry>,
+    {
+        self.resume()?;
+        self.hashes.insert(path.hash().into_owned());
+        if self.exact_index.is_some() && self.frame_size >= EXACT_INDEX_FRAME_SIZE {
+            self.start_frame()?;
+        }
+
         let writer = self.writer.as_mut().expect("not dropped yet");
-        let mut encoder =
-            frcode::Encoder::new(writer, b"p".to_vec(), serde_json::to_vec(&path).unwrap());
-        for entry in files.to_list(filter_prefix) {
+        let mut encoder = frcode::Encoder::new(
+            writer,
+            b"p".to_vec(),
+            serde_json::to_vec(path).expect("store path serializes to JSON"),
+        );
+        let mut count = 0;
+        for entry in entries {
+            if let Some(index) = self.exact_index.as_mut() {
+                index.add(&entry.path);
+            }
+            self.frame_size += entry.path.len();
             entry.encode(&mut encoder)?;
+            count += 1;
+        }
+        encoder.finish()?;
+        if let Some((packages, entries)) = self.footer.counts.as_mut() {
+            *packages += 1;
+            *entries += count;
+        }
+
+        // the footer resets the shared prefix, so a new block can start here
+        let writer = self.writer.as_mut().expect("not dropped yet");
+        if writer.pending_len() >= self.block_size {
+            writer.end_block()?;
+        }
+        Ok(count)
+    }
+
+    /// Finishes the current compressed frame and starts a new one.
+    ///
+    /// This is only valid between packages, since each frame must be decodable on its own.
+    fn start_frame(&mut self) -> io::Result<()> {
+        let writer = self.writer.take().expect("not dropped yet");
+        let file = self.finish_blocks(writer)?.finish()?;
+        self.start_compressor(file)
+    }
+
+    /// Starts a new compressed frame at the current position of `file`.
+    fn start_compressor(&mut self, mut file: HashingFile) -> io::Result<()> {
+        let offset = file.file.stream_position()?;
+        let encoder = Compressor::new(file, self.codec, self.level, self.threads)?;
+        self.writer = Some(frcode::BlockWriter::new(encoder));
+
+        if let Some(index) = self.exact_index.as_mut() {
+            index.frames.push(offset);
         }
+        self.frame_size = 0;
+        Ok(())
+    }
+
+    /// Writes all packages that were added so far to disk, so that the file is a complete
+    /// database even if the process is interrupted later.
+    ///
+    /// This ends the current block and compressed frame and writes the exact path index (if
+    /// enabled) and the footer, which are removed again when the next package is added. If
+    /// the process is interrupted after that, the checkpointed packages can still be found,
+    /// but queries may fail at the end of the file, since it can contain an unfinished frame.
+    /// Each checkpoint slightly reduces the compression ratio. When appending, the checkpoint
+    /// is written to the temporary file (see `append`), which only replaces the database once
+    /// the writer is finished or dropped.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        let Some(writer) = self.writer.take() else {
+            // nothing was added since the last checkpoint
+            return Ok(());
+        };
+        let mut file = self.finish_blocks(writer)?.finish()?;
+        let end = file.file.stream_position()?;
+        self.write_trailer(&mut file)?;
+        file.file.sync_all()?;
+        self.checkpointed = Some((file, end));
         Ok(())
     }
 
+    /// Continues writing after a checkpoint, removing the index and footer it wrote.
+    fn resume(&mut self) -> io::Result<()> {
+        let Some((mut data, end)) = self.checkpointed.take() else {
+            return Ok(());
+        };
+        let file = &mut data.file;
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(INDEX_OFFSET_POS))?;
+        file.write_u64::<LittleEndian>(0)?;
+        file.seek(SeekFrom::Start(FOOTER_OFFSET_POS))?;
+        file.write_u64::<LittleEndian>(0)?;
+        file.seek(SeekFrom::End(0))?;
+        self.start_compressor(data)
+    }
+
     /// Finishes encoding. After calling this function, `add` may no longer be called, since this function
     /// closes the stream.
     ///
-    /// The return value is the underlying File.
+    /// The return value is the underlying File. When appending, it has replaced the original
+    /// database, unless finishing failed, in which case the temporary file is removed.
     fn finish_encoder(&mut self) -> io::Result<File> {
+        let result = self.finish_file();
+        let Some((temp, path)) = self.replaces.take() else {
+            return result;
+        };
+        let result = result.and_then(|file| {
+            file.sync_all()?;
+            fs::rename(&temp, path)?;
+            Ok(file)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(temp);
+        }
+        result
+    }
+
+    /// Finishes the compressed stream and writes the trailer, see `finish_encoder`.
+    fn finish_file(&mut self) -> io::Result<File> {
+        self.resume()?;
         let writer = self.writer.take().expect("not dropped yet");
-        let encoder = writer.into_inner()?;
-        encoder.finish()
+        let encoder = self.finish_blocks(writer)?;
+        let mut file = encoder.finish()?;
+        self.write_trailer(&mut file)?;
+        Ok(file.file)
+    }
+
+    /// Writes the exact path index (if there is one) and the footer at the end of `file` and
+    /// records their offsets in the header.
+    ///
+    /// The footer includes the hash of all compressed entries that were written to `data`.
+    /// The trailer itself is not hashed, so writing can continue after a checkpoint.
+    fn write_trailer(&mut self, data: &mut HashingFile) -> io::Result<()> {
+        self.footer.content_hash = Some(data.hasher.clone().finalize().into());
+
+        let file = &mut data.file;
+        if let Some(index) = self.exact_index.as_mut() {
+            let offset = file.stream_position()?;
+            index.write(BufWriter::new(&mut *file))?;
+            file.seek(SeekFrom::Start(INDEX_OFFSET_POS))?;
+            file.write_u64::<LittleEndian>(offset)?;
+            file.seek(SeekFrom::End(0))?;
+        }
+
+        let offset = file.stream_position()?;
+        self.footer.write(BufWriter::new(&mut *file))?;
+        file.seek(SeekFrom::Start(FOOTER_OFFSET_POS))?;
+        file.write_u64::<LittleEndian>(offset)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Ends the last block of `writer` and records the offsets of its blocks in the footer.
+    fn finish_blocks(
+        &mut self,
+        mut writer: frcode::BlockWriter<Compressor>,
+    ) -> io::Result<Compressor> {
+        writer.end_block()?;
+        let start = self.footer.stream_len;
+        let offsets = writer.block_offsets().iter().map(|offset| start + offset);
+        self.footer.block_offsets.extend(offsets);
+        self.footer.stream_len += writer.position();
+        writer.finish()
     }
 
     /// Finish the encoding and return the size in bytes of the compressed file that was created.
@@ -97,407 +852,4475 @@ impl Writer {
     }
 }
 
-error_chain! {
-    errors {
-        UnsupportedFileType(found: Vec<u8>) {
-            description("unsupported file type")
-            display("expected file to start with nix-index file magic 'NIXI', but found '{}' (is this a valid nix-index database file?)", String::from_utf8_lossy(found))
-        }
-        UnsupportedVersion(found: u64) {
-            description("unsupported file version")
-            display("this executable only supports the nix-index database version {}, but found a database with version {}", FORMAT_VERSION, found)
+/// An index from the file names in a database to the compressed frames that contain them.
+///
+/// This allows looking up exact paths without decoding the whole database.
+#[derive(Debug, Clone)]
+struct ExactIndex {
+    /// The offsets at which the compressed frames start. Each frame ends where the next one
+    /// starts (or, for the last frame, where the index starts).
+    frames: Vec<u64>,
+    /// Pairs of a file name hash and the index of a frame containing a file with that name.
+    ///
+    /// When reading, this is sorted so it can be binary searched.
+    entries: Vec<(u64, u32)>,
+}
+
+impl ExactIndex {
+    /// Records that the current frame contains an entry with the given path.
+    fn add(&mut self, path: &[u8]) {
+        let frame = (self.frames.len() - 1) as u32;
+        self.entries.push((file_name_hash(path), frame));
+    }
+
+    fn write<W: Write>(&mut self, mut out: W) -> io::Result<()> {
+        self.entries.sort_unstable();
+        self.entries.dedup();
+
+        out.write_u64::<LittleEndian>(self.frames.len() as u64)?;
+        for &offset in &self.frames {
+            out.write_u64::<LittleEndian>(offset)?;
         }
-        MissingPackageEntry {
-            description("missing package entry for path")
-            display("database corrupt, found a file entry without a matching package entry")
+        out.write_u64::<LittleEndian>(self.entries.len() as u64)?;
+        for &(hash, frame) in &self.entries {
+            out.write_u64::<LittleEndian>(hash)?;
+            out.write_u32::<LittleEndian>(frame)?;
         }
-        Frcode(err: frcode::Error) {
-            description("frcode error")
-            display("database corrupt, frcode error: {}", err)
+        out.flush()
+    }
+
+    fn read<R: Read>(mut input: R) -> io::Result<ExactIndex> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid exact path index");
+
+        let frame_count = input.read_u64::<LittleEndian>()?;
+        let mut frames = Vec::new();
+        for _ in 0..frame_count {
+            frames.push(input.read_u64::<LittleEndian>()?);
         }
-        EntryParse(entry: Vec<u8>) {
-            description("entry parse failure")
-            display("database corrupt, could not parse entry: {:?}", String::from_utf8_lossy(entry))
+
+        let entry_count = input.read_u64::<LittleEndian>()?;
+        let mut entries = Vec::new();
+        for _ in 0..entry_count {
+            let hash = input.read_u64::<LittleEndian>()?;
+            let frame = input.read_u32::<LittleEndian>()?;
+            if frame as usize >= frames.len() {
+                return Err(invalid());
+            }
+            entries.push((hash, frame));
         }
-        StorePathParse(path: Vec<u8>) {
-            description("store path parse failure")
-            display("database corrupt, could not parse store path: {:?}", String::from_utf8_lossy(path))
+        if !entries.is_sorted() {
+            return Err(invalid());
         }
+
+        Ok(ExactIndex { frames, entries })
     }
 
-    foreign_links {
-        Io(io::Error);
-        Grep(grep::regex::Error);
+    /// Returns the ranges of the frames that may contain the given path. The last frame
+    /// ends at `end`.
+    fn frames_for(&self, path: &[u8], end: u64) -> Vec<(u64, u64)> {
+        let hash = file_name_hash(path);
+        let start = self.entries.partition_point(|&(h, _)| h < hash);
+        self.entries[start..]
+            .iter()
+            .take_while(|&&(h, _)| h == hash)
+            .map(|&(_, frame)| {
+                let frame = frame as usize;
+                let frame_end = self.frames.get(frame + 1).copied().unwrap_or(end);
+                (self.frames[frame], frame_end)
+            })
+            .collect()
     }
 }
 
-impl From<frcode::Error> for Error {
-    fn from(err: frcode::Error) -> Error {
-        ErrorKind::Frcode(err).into()
-    }
+/// Returns the offset at which the entries of a database end, given the offsets of the exact
+/// path index and the footer which are stored after the entries (zero if they are missing).
+///
+/// If there is neither an index nor a footer, the entries continue until the end of the
+/// database and `None` is returned.
+fn data_end(index_offset: u64, footer_offset: u64) -> Option<u64> {
+    [index_offset, footer_offset]
+        .into_iter()
+        .filter(|&offset| offset != 0)
+        .min()
 }
 
-/// A Reader allows fast querying of a nix-index database.
-pub struct Reader {
-    decoder: frcode::Decoder<BufReader<zstd::Decoder<'static, BufReader<File>>>>,
+/// Returns the range of the source that contains the entries of a database starting at
+/// `base`. If the end of the entries is not known, the range extends to the end of the source.
+fn source_range(base: u64, start: u64, end: Option<u64>) -> (u64, u64) {
+    (base + start, end.map_or(u64::MAX, |end| base + end))
 }
 
-impl Reader {
-    /// Opens a nix-index database located at the given path.
-    ///
-    /// If the path does not exist or is not a valid database, an error is returned.
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader> {
-        let mut file = File::open(path)?;
-        let mut magic = [0u8; 4];
-        file.read_exact(&mut magic)?;
+/// Information about the whole database that is written after all entries.
+///
+/// The footer is written when the writer is finished, so databases that were not finished
+/// properly have none.
+#[derive(Debug, Clone, Default)]
+struct Footer {
+    /// The length of the decompressed entries.
+    stream_len: u64,
+    /// The offsets at which the checksummed blocks start in the decompressed entries.
+    block_offsets: Vec<u64>,
+    /// The number of packages and file entries in the database, if they are known.
+    counts: Option<(u64, u64)>,
+    /// The SHA-256 hash of the compressed entries, if it is known.
+    content_hash: Option<[u8; 32]>,
+}
 
-        if magic != FILE_MAGIC {
-            return Err(ErrorKind::UnsupportedFileType(magic.to_vec()).into());
+impl Footer {
+    fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_u64::<LittleEndian>(self.stream_len)?;
+        out.write_u64::<LittleEndian>(self.block_offsets.len() as u64)?;
+        for &offset in &self.block_offsets {
+            out.write_u64::<LittleEndian>(offset)?;
         }
+        let (packages, entries) = self.counts.unwrap_or_default();
+        out.write_u64::<LittleEndian>(packages)?;
+        out.write_u64::<LittleEndian>(entries)?;
+        out.write_all(&self.content_hash.unwrap_or_default())?;
+        out.flush()
+    }
 
-        let version = file.read_u64::<LittleEndian>()?;
-        if version != FORMAT_VERSION {
-            return Err(ErrorKind::UnsupportedVersion(version).into());
+    /// Reads the footer of a database with the given format version.
+    fn read<R: Read>(mut input: R, version: u64) -> io::Result<Footer> {
+        let stream_len = input.read_u64::<LittleEndian>()?;
+        let count = input.read_u64::<LittleEndian>()?;
+        let mut block_offsets = Vec::new();
+        for _ in 0..count {
+            block_offsets.push(input.read_u64::<LittleEndian>()?);
         }
-
-        let decoder = zstd::Decoder::new(file)?;
-        Ok(Reader {
-            decoder: frcode::Decoder::new(BufReader::new(decoder)),
+        if !block_offsets.is_sorted() || block_offsets.last().is_some_and(|&o| o >= stream_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid database footer",
+            ));
+        }
+        let counts = if version >= COUNTS_VERSION {
+            let packages = input.read_u64::<LittleEndian>()?;
+            Some((packages, input.read_u64::<LittleEndian>()?))
+        } else {
+            None
+        };
+        let content_hash = if version >= CONTENT_HASH_VERSION {
+            let mut hash = [0; 32];
+            input.read_exact(&mut hash)?;
+            Some(hash)
+        } else {
+            None
+        };
+        Ok(Footer {
+            stream_len,
+            block_offsets,
+            counts,
+            content_hash,
         })
     }
+}
+
+/// Hashes the last component of a path with 64-bit FNV-1a.
+///
+/// The hash is stored in the database, so it must not depend on the platform or the Rust version.
+fn file_name_hash(path: &[u8]) -> u64 {
+    let name = memrchr(b'/', path).map_or(path, |i| &path[i + 1..]);
+    name.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Adds all `packages` with their file trees to `writer`, skipping store paths that the
+/// database already contains.
+///
+/// This is what `nix-index` does with the packages it fetched, for callers that get the
+/// packages from somewhere else, such as a different evaluator or a precomputed list. Like
+/// with `Writer::add`, only entries matching `filter_prefix` are added. Returns the number of
+/// packages that were added.
+pub fn build_index<I>(writer: &mut Writer, packages: I, filter_prefix: &[u8]) -> io::Result<usize>
+where
+    I: IntoIterator<Item = (StorePath, FileTree)>,
+{
+    build_index_with_progress(writer, packages, filter_prefix, |_| {})
+}
+
+/// Information about the progress of building an index, passed to the callback of
+/// `build_index_with_progress`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IndexProgress {
+    /// The number of packages that have been added so far.
+    pub packages: usize,
+    /// The number of entries of the package that was just added.
+    pub files: u64,
+}
+
+/// Builds an index like `build_index`, but calls `on_progress` after each package that was
+/// added, so that callers can show how far along they are.
+///
+/// Packages that are skipped because the database already contains them are not reported.
+pub fn build_index_with_progress<I, F>(
+    writer: &mut Writer,
+    packages: I,
+    filter_prefix: &[u8],
+    mut on_progress: F,
+) -> io::Result<usize>
+where
+    I: IntoIterator<Item = (StorePath, FileTree)>,
+    F: FnMut(IndexProgress),
+{
+    let mut added = 0;
+    for (path, files) in packages {
+        if writer.store_path_hashes().contains(path.hash().as_ref()) {
+            continue;
+        }
+        let files = writer.add(path, files, filter_prefix)?;
+        added += 1;
+        on_progress(IndexProgress {
+            packages: added,
+            files,
+        });
+    }
+    Ok(added)
+}
+
+/// Converts the database at `old_path`, which may use an older format version, to a database
+/// in the current format at `new_path`.
+///
+/// The new database uses the same codec and application tag as the old one, compressed at the
+/// given level. All packages are copied as they are, including store paths that occur more
+/// than once. Since the new database is created before the old one is read, the paths must
+/// refer to different files.
+pub fn migrate<P: AsRef<Path>, Q: AsRef<Path>>(old_path: P, new_path: Q, level: i32) -> Result<()> {
+    let (old_path, new_path) = (old_path.as_ref(), new_path.as_ref());
+    let same_file = match (fs::canonicalize(old_path), fs::canonicalize(new_path)) {
+        (Ok(old), Ok(new)) => old == new,
+        _ => old_path == new_path,
+    };
+    if same_file {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot migrate a database to the same file",
+        )
+        .into());
+    }
+
+    let mut old = Reader::open(old_path)?;
+    let mut writer = WriterBuilder::new()
+        .codec(old.codec)
+        .level(level)
+        .application_tag(old.application_tag)
+        .build(new_path)?;
+    old.for_each_package(|path, entries| {
+        writer.add_entries(&path, entries)?;
+        Ok(())
+    })?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Exports all remaining packages and files of `reader` to a new SQLite database at `db_path`.
+///
+/// The SQLite database has two tables:
+///
+/// * `packages(id, hash, name, attr, output)` with one row per store path and
+/// * `files(package_id, path, type, size, target)`, where `package_id` refers to `packages.id`,
+///   `type` is one of `regular`, `executable`, `directory` or `symlink`, `size` is only set for
+///   regular files and directories and `target` only for symlinks.
+///
+/// Paths and targets are stored as text if they are valid UTF-8 and as blobs otherwise, so no
+/// path is changed by the export. All rows are inserted in a single transaction. Fails if the
+/// tables already exist in the SQLite database.
+#[cfg(feature = "sqlite")]
+pub fn export_sqlite<P: AsRef<Path>>(mut reader: Reader, db_path: P) -> Result<()> {
+    use rusqlite::types::Value;
+
+    /// Stores valid UTF-8 as text and everything else as a blob.
+    fn bytes_value(bytes: &[u8]) -> Value {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Value::Text(s.to_string()),
+            Err(_) => Value::Blob(bytes.to_vec()),
+        }
+    }
+
+    let mut connection = rusqlite::Connection::open(db_path)?;
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(
+        r#"
+        create table packages (
+            id          integer primary key,
+            hash        text not null,
+            name        text not null,
+            attr        text not null,
+            output      text not null
+        );
+        create table files (
+            package_id  integer not null references packages(id),
+            path        text not null,
+            type        text not null,
+            size        integer,
+            target      text
+        );
+    "#,
+    )?;
+    {
+        let mut insert_package = transaction
+            .prepare("insert into packages(hash, name, attr, output) values (?, ?, ?, ?)")?;
+        let mut insert_file = transaction.prepare(
+            "insert into files(package_id, path, type, size, target) values (?, ?, ?, ?, ?)",
+        )?;
+        reader.for_each_package(|store_path, entries| {
+            let origin = store_path.origin_ref();
+            let package_id = insert_package.insert((
+                store_path.hash(),
+                store_path.name(),
+                &origin.attr,
+                &origin.output,
+            ))?;
+            for entry in entries {
+                let (typ, size, target) = match entry.node {
+                    FileNode::Regular { size, executable } => {
+                        let typ = if executable { "executable" } else { "regular" };
+                        (typ, Some(size), None)
+                    }
+                    FileNode::Directory { size, .. } => ("directory", Some(size), None),
+                    FileNode::Symlink { ref target } => {
+                        ("symlink", None, Some(bytes_value(target)))
+                    }
+                };
+                insert_file.execute((package_id, bytes_value(&entry.path), typ, size, target))?;
+            }
+            Ok(())
+        })?;
+    }
+    transaction.execute_batch("create index files_package_id on files(package_id);")?;
+    transaction.commit()?;
+    Ok(())
+}
+
+error_chain! {
+    errors {
+        UnsupportedFileType(found: Vec<u8>) {
+            description("unsupported file type")
+            display("expected file to start with nix-index file magic 'NIXI', but found '{}' (is this a valid nix-index database file?)", String::from_utf8_lossy(found))
+        }
+        UnsupportedVersion(found: u64) {
+            description("unsupported file version")
+            display("this executable only supports the nix-index database versions {} to {}, but found a database with version {}", MIN_FORMAT_VERSION, FORMAT_VERSION, found)
+        }
+        UnsupportedCodec(tag: u8) {
+            description("unsupported compression codec")
+            display("the database is compressed with an unknown codec (tag {}), it may have been created by a newer version of nix-index", tag)
+        }
+        MissingFooter {
+            description("missing database footer")
+            display("the database has no footer, it was created by an older version of nix-index or not finished properly")
+        }
+        BlockOutOfRange(index: usize, count: usize) {
+            description("block index out of range")
+            display("cannot seek to block {}, the database only has {} blocks", index, count)
+        }
+        MissingPackageEntry {
+            description("missing package entry for path")
+            display("database corrupt, found a file entry without a matching package entry")
+        }
+        Frcode(err: frcode::Error) {
+            description("frcode error")
+            display("database corrupt, frcode error: {}", err)
+        }
+        EntryParse(entry: Vec<u8>) {
+            description("entry parse failure")
+            display("database corrupt, could not parse entry: {:?}", String::from_utf8_lossy(entry))
+        }
+        StorePathParse(path: Vec<u8>) {
+            description("store path parse failure")
+            display("database corrupt, could not parse store path: {:?}", String::from_utf8_lossy(path))
+        }
+        PatternTooLarge(limit: usize) {
+            description("pattern too large")
+            display("the compiled pattern exceeds the size limit of {} bytes", limit)
+        }
+        Cancelled {
+            description("query cancelled")
+            display("the query was cancelled")
+        }
+    }
+
+    foreign_links {
+        Io(io::Error);
+        Grep(grep::regex::Error);
+        Regex(regex::Error);
+        Sqlite(rusqlite::Error) #[cfg(feature = "sqlite")];
+    }
+}
+
+impl From<frcode::Error> for Error {
+    fn from(err: frcode::Error) -> Error {
+        ErrorKind::Frcode(err).into()
+    }
+}
+
+/// A Reader allows fast querying of a nix-index database.
+pub struct Reader {
+    decoder: frcode::Decoder<Box<dyn Input>>,
+    /// The source of the database, shared with `decoder` so that other parts of the database
+    /// can be read as well.
+    source: Arc<Mutex<Box<dyn Source>>>,
+    /// The position of the start of the database in `source`.
+    base: u64,
+    /// The format version of the database.
+    version: u64,
+    application_tag: [u8; 4],
+    /// The offset of the compressed entries, right after the header.
+    data_start: u64,
+    codec: Codec,
+    /// The size of the blocks in which the entries are decoded.
+    block_size: usize,
+    /// The offset of the exact path index, or zero if the database does not have one.
+    index_offset: u64,
+    /// The exact path index, loaded on first use.
+    exact_index: Option<ExactIndex>,
+    /// The offset of the footer, or zero if the database does not have one.
+    footer_offset: u64,
+    /// The footer, loaded on first use.
+    footer: Option<Footer>,
+}
+
+/// A seekable source from which a database can be read.
+trait Source: Read + Seek + Send {}
+impl<T: Read + Seek + Send> Source for T {}
+
+/// The decompressed entries of a database, as read by the frcode decoder.
+trait Input: BufRead + Seek + Send {}
+impl<T: BufRead + Seek + Send> Input for T {}
+
+/// A reader for a range of a shared `Source`.
+///
+/// Each handle keeps track of its own position, so multiple handles can read from the same
+/// source independently.
+struct SourceRange {
+    source: Arc<Mutex<Box<dyn Source>>>,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for SourceRange {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.end.saturating_sub(self.pos) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let mut source = self
+            .source
+            .lock()
+            .map_err(|_| io::Error::other("database source lock poisoned"))?;
+        source.seek(SeekFrom::Start(self.pos))?;
+        let n = source.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// The decompressed data of a compressed range of a `Source`.
+///
+/// Seeking is supported by skipping over decompressed data, starting again from the beginning
+/// of the range when seeking backwards. This is slow, but it avoids decoding the skipped entries.
+struct DecompressedRange {
+    source: Arc<Mutex<Box<dyn Source>>>,
+    codec: Codec,
+    range: (u64, u64),
+    reader: BufReader<Box<dyn Read + Send>>,
+    /// The position in the decompressed data.
+    pos: u64,
+}
+
+impl DecompressedRange {
+    fn new(
+        source: &Arc<Mutex<Box<dyn Source>>>,
+        codec: Codec,
+        range: (u64, u64),
+    ) -> io::Result<DecompressedRange> {
+        Ok(DecompressedRange {
+            source: Arc::clone(source),
+            codec,
+            range,
+            reader: DecompressedRange::decompress(source, codec, range)?,
+            pos: 0,
+        })
+    }
+
+    fn decompress(
+        source: &Arc<Mutex<Box<dyn Source>>>,
+        codec: Codec,
+        (start, end): (u64, u64),
+    ) -> io::Result<BufReader<Box<dyn Read + Send>>> {
+        let range = SourceRange {
+            source: Arc::clone(source),
+            pos: start,
+            end,
+        };
+        let decoder: Box<dyn Read + Send> = match codec {
+            Codec::Zstd => Box::new(zstd::Decoder::new(range)?),
+            Codec::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(range)),
+            Codec::Gzip => Box::new(flate2::read::MultiGzDecoder::new(range)),
+            Codec::None => Box::new(range),
+        };
+        Ok(BufReader::new(decoder))
+    }
+}
+
+impl Read for DecompressedRange {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl BufRead for DecompressedRange {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.pos += amt as u64;
+    }
+}
+
+impl Seek for DecompressedRange {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => n,
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position")
+            })?,
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "cannot seek from the end of compressed data",
+                ))
+            }
+        };
+
+        if target < self.pos {
+            self.reader = DecompressedRange::decompress(&self.source, self.codec, self.range)?;
+            self.pos = 0;
+        }
+        let skip = target - self.pos;
+        self.pos += io::copy(&mut (&mut self.reader).take(skip), &mut io::sink())?;
+        Ok(self.pos)
+    }
+}
+
+impl Reader {
+    /// Opens a nix-index database located at the given path.
+    ///
+    /// The compression codec is detected from the database header, so this works for databases
+    /// created with any of the supported codecs.
+    ///
+    /// If the path does not exist or is not a valid database, an error is returned.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader> {
+        Reader::from_reader(File::open(path)?)
+    }
+
+    /// Opens a nix-index database located at the given path by memory-mapping it.
+    ///
+    /// This avoids copying the compressed data through an intermediate buffer, which reduces
+    /// resident memory when many short queries are run against the same database.
+    ///
+    /// The file must not be modified while the returned `Reader` is alive. Databases are
+    /// normally replaced atomically by `nix-index`, which is fine.
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> Result<Reader> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only and the documented contract of this function is that
+        // the underlying file is not modified while it is mapped.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Reader::from_reader(io::Cursor::new(map))
+    }
+
+    /// Reads a nix-index database from the given source, such as a `File` or an
+    /// in-memory `Cursor`.
+    ///
+    /// The source must be positioned at the start of the database. If it does not contain
+    /// a valid database, an error is returned. On WebAssembly, where there are no files, a
+    /// database that was fetched into a `Cursor<Vec<u8>>` can be queried this way.
+    pub fn from_reader<R: Read + Seek + Send + 'static>(mut reader: R) -> Result<Reader> {
+        let base = reader.stream_position()?;
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if magic != FILE_MAGIC {
+            return Err(ErrorKind::UnsupportedFileType(magic.to_vec()).into());
+        }
+
+        let version = reader.read_u64::<LittleEndian>()?;
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(ErrorKind::UnsupportedVersion(version).into());
+        }
+
+        let mut application_tag = DEFAULT_APPLICATION_TAG;
+        if version >= APPLICATION_TAG_VERSION {
+            reader.read_exact(&mut application_tag)?;
+        }
+        let codec = if version >= 2 {
+            let tag = reader.read_u8()?;
+            Codec::from_tag(tag).ok_or(ErrorKind::UnsupportedCodec(tag))?
+        } else {
+            Codec::Zstd
+        };
+        let index_offset = if version >= 3 {
+            reader.read_u64::<LittleEndian>()?
+        } else {
+            0
+        };
+        let block_size = if version >= BLOCK_SIZE_VERSION {
+            reader.read_u32::<LittleEndian>()? as usize
+        } else {
+            frcode::DEFAULT_BLOCK_SIZE
+        };
+        let footer_offset = if version >= FOOTER_VERSION {
+            reader.read_u64::<LittleEndian>()?
+        } else {
+            0
+        };
+        let data_start = reader.stream_position()? - base;
+
+        let source: Arc<Mutex<Box<dyn Source>>> = Arc::new(Mutex::new(Box::new(reader)));
+        let range = source_range(base, data_start, data_end(index_offset, footer_offset));
+        let decoder = Reader::range_decoder(&source, version, codec, block_size, range)?;
+        Ok(Reader {
+            decoder,
+            source,
+            base,
+            version,
+            application_tag,
+            data_start,
+            codec,
+            block_size,
+            index_offset,
+            exact_index: None,
+            footer_offset,
+            footer: None,
+        })
+    }
+
+    /// Returns a new reader for the same database that starts at the first entry again.
+    ///
+    /// Since `query` consumes the reader, this can be used to run several queries without
+    /// opening the database again. The header is not read and validated again, and the new
+    /// reader shares the underlying file (or other source) with this one, as well as the
+    /// exact path index and the footer if they were already loaded. Both readers can be used
+    /// independently.
+    pub fn reopen(&self) -> Result<Reader> {
+        let end = data_end(self.index_offset, self.footer_offset);
+        let range = source_range(self.base, self.data_start, end);
+        let mut decoder = Reader::range_decoder(
+            &self.source,
+            self.version,
+            self.codec,
+            self.block_size,
+            range,
+        )?;
+        if let Some(footer) = &self.footer {
+            decoder.set_block_offsets(footer.block_offsets.clone());
+        }
+        Ok(Reader {
+            decoder,
+            source: Arc::clone(&self.source),
+            exact_index: self.exact_index.clone(),
+            footer: self.footer.clone(),
+            ..*self
+        })
+    }
+
+    /// Creates a decoder for the entries stored in the range from `start` to `end` of `source`.
+    ///
+    /// The range must start at the beginning of a compressed frame.
+    fn range_decoder(
+        source: &Arc<Mutex<Box<dyn Source>>>,
+        version: u64,
+        codec: Codec,
+        block_size: usize,
+        range: (u64, u64),
+    ) -> io::Result<frcode::Decoder<Box<dyn Input>>> {
+        let decompressed = DecompressedRange::new(source, codec, range)?;
+        let input: Box<dyn Input> = if version >= CHECKSUMS_VERSION {
+            Box::new(frcode::BlockReader::new(decompressed))
+        } else {
+            Box::new(decompressed)
+        };
+        Ok(frcode::Decoder::with_block_size(input, block_size))
+    }
+
+    /// Returns the exact path index of this database, loading it if necessary.
+    fn exact_index(&mut self) -> Result<Option<&ExactIndex>> {
+        if self.index_offset != 0 && self.exact_index.is_none() {
+            let range = SourceRange {
+                source: Arc::clone(&self.source),
+                pos: self.base + self.index_offset,
+                end: u64::MAX,
+            };
+            self.exact_index = Some(ExactIndex::read(BufReader::new(range))?);
+        }
+        Ok(self.exact_index.as_ref())
+    }
+
+    /// Returns the footer of this database, loading it if necessary.
+    fn footer(&mut self) -> Result<&Footer> {
+        if self.footer.is_none() {
+            if self.footer_offset == 0 {
+                return Err(ErrorKind::MissingFooter.into());
+            }
+            let range = SourceRange {
+                source: Arc::clone(&self.source),
+                pos: self.base + self.footer_offset,
+                end: u64::MAX,
+            };
+            let footer = Footer::read(BufReader::new(range), self.version)?;
+            self.decoder.set_block_offsets(footer.block_offsets.clone());
+            self.footer = Some(footer);
+        }
+        Ok(self.footer.as_ref().expect("footer was just loaded"))
+    }
+
+    /// Returns the number of checksummed blocks in the database, which can be used with
+    /// `seek_block`.
+    ///
+    /// This needs the footer of the database, which was added in format version 7.
+    pub fn block_count(&mut self) -> Result<usize> {
+        Ok(self.footer()?.block_offsets.len())
+    }
+
+    /// Returns the length of the decompressed entries of the database, in bytes.
+    ///
+    /// This can be used as the total for the `bytes` reported by `Query::on_progress` to show
+    /// the progress of a query as a percentage. It also counts the headers of the checksummed
+    /// blocks, so it is slightly larger than the number of bytes that a query searches. It is
+    /// read from the footer of the database, which was added in format version 7.
+    pub fn uncompressed_len(&mut self) -> Result<u64> {
+        Ok(self.footer()?.stream_len)
+    }
+
+    /// Returns the number of packages in the database, without decoding the entries.
+    ///
+    /// This is read from the footer, so it fails with `ErrorKind::MissingFooter` for databases
+    /// created before format version 9.
+    pub fn package_count(&mut self) -> Result<u64> {
+        Ok(self.footer_counts()?.0)
+    }
+
+    /// Returns the number of file entries in the database, without decoding them.
+    ///
+    /// Like `package_count`, this needs a database with format version 9 or newer.
+    pub fn entry_count(&mut self) -> Result<u64> {
+        Ok(self.footer_counts()?.1)
+    }
+
+    /// Returns the SHA-256 hash of the compressed entries of the database.
+    ///
+    /// The hash changes whenever packages are added to the database, so it can be used to
+    /// detect whether a database changed, for example as the key of a cache of query results.
+    /// Unlike the modification time, it stays the same when the file is copied. It is read
+    /// from the footer, so it fails with `ErrorKind::MissingFooter` for databases created
+    /// before format version 10.
+    pub fn content_hash(&mut self) -> Result<[u8; 32]> {
+        self.footer()?
+            .content_hash
+            .ok_or_else(|| ErrorKind::MissingFooter.into())
+    }
+
+    /// Returns the number of packages and file entries recorded in the footer.
+    fn footer_counts(&mut self) -> Result<(u64, u64)> {
+        self.footer()?
+            .counts
+            .ok_or_else(|| ErrorKind::MissingFooter.into())
+    }
+
+    /// Continues reading the database at the start of the block with the given index, so that
+    /// the next query only searches this and the following blocks.
+    ///
+    /// Since the database is compressed, the data before the block still has to be
+    /// decompressed, but the entries in it are not decoded or searched.
+    pub fn seek_block(&mut self, block_index: usize) -> Result<()> {
+        let count = self.block_count()?;
+        if block_index >= count {
+            return Err(ErrorKind::BlockOutOfRange(block_index, count).into());
+        }
+        self.decoder.seek_block(block_index)?;
+        Ok(())
+    }
+
+    /// Finds all entries whose path is exactly `path`, such as `/bin/zsh`.
+    ///
+    /// If the database was built with an exact path index (see `Writer::enable_exact_index`),
+    /// only the parts of the database that can contain the path are decoded. Otherwise, the
+    /// whole database is scanned.
+    pub fn lookup_exact(&mut self, path: &[u8]) -> Result<Vec<Match>> {
+        let (base, index_offset) = (self.base, self.index_offset);
+        let ranges = match self.exact_index()? {
+            Some(index) => index
+                .frames_for(path, index_offset)
+                .into_iter()
+                .map(|(start, end)| source_range(base, start, Some(end)))
+                .collect(),
+            None => {
+                let end = data_end(index_offset, self.footer_offset);
+                vec![source_range(base, self.data_start, end)]
+            }
+        };
+
+        let mut result = Vec::new();
+        for (start, end) in ranges {
+            let decoder = Reader::range_decoder(
+                &self.source,
+                self.version,
+                self.codec,
+                self.block_size,
+                (start, end),
+            )?;
+            let mut reader = Reader {
+                decoder,
+                source: Arc::clone(&self.source),
+                base,
+                version: self.version,
+                application_tag: self.application_tag,
+                data_start: self.data_start,
+                codec: self.codec,
+                block_size: self.block_size,
+                index_offset: self.index_offset,
+                exact_index: None,
+                footer_offset: self.footer_offset,
+                footer: None,
+            };
+            reader.for_each_package(|store_path, entries| {
+                for entry in entries.into_iter().filter(|e| e.path == path) {
+                    result.push(Match::new(store_path.clone(), entry));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(result)
+    }
+
+    /// Returns the application tag that was recorded when the database was created.
+    ///
+    /// This is `DEFAULT_APPLICATION_TAG` for databases created by nix-index, including those
+    /// that use a format version from before the tag was introduced. Other tools may set a
+    /// different tag with `Writer::create_with_tag`.
+    pub fn application_tag(&self) -> [u8; 4] {
+        self.application_tag
+    }
+
+    /// Collects statistics about the database, such as the number of packages and files.
+    ///
+    /// The counts are read from the footer if the database has one with counts (format
+    /// version 9 or newer). Otherwise, this reads all remaining entries of the database, but
+    /// does not parse them.
+    pub fn stats(&mut self) -> Result<DbStats> {
+        let compressed_size = {
+            let mut source = self
+                .source
+                .lock()
+                .map_err(|_| io::Error::other("database source lock poisoned"))?;
+            source.seek(SeekFrom::End(0))? - self.base
+        };
+
+        let mut stats = DbStats {
+            version: self.version,
+            application_tag: self.application_tag,
+            codec: self.codec,
+            compressed_size,
+            packages: 0,
+            files: 0,
+        };
+        let counts = match self.footer_offset {
+            0 => None,
+            _ => self.footer()?.counts,
+        };
+        if let Some((packages, files)) = counts {
+            stats.packages = packages as usize;
+            stats.files = files as usize;
+            return Ok(stats);
+        }
+
+        loop {
+            let block = self.decoder.decode()?;
+            let Some((_, block)) = block.split_last() else {
+                break;
+            };
+
+            for line in block.split(|c| *c == b'\n') {
+                if line.starts_with(b"p\0") {
+                    stats.packages += 1;
+                } else {
+                    stats.files += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns the hashes of all store paths in the database.
+    ///
+    /// Only the package entries are parsed, so this is faster than iterating over all files.
+    pub fn store_path_hashes(&mut self) -> Result<HashSet<String>> {
+        let mut hashes = HashSet::new();
+        let mut packages = VecDeque::new();
+        while self.decode_packages(&mut packages)? {
+            for store_path in packages.drain(..) {
+                hashes.insert(store_path?.hash().into_owned());
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Returns an iterator over the store paths of all remaining packages in the database.
+    ///
+    /// Like `store_path_hashes`, this only parses the package entries and skips the file entries.
+    pub fn packages(self) -> PackagesIter {
+        PackagesIter {
+            reader: Some(self),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns an iterator over the store paths of the remaining packages whose name matches
+    /// `name_pattern`, for example `^python3`.
+    ///
+    /// Like `packages`, this skips the file entries, so it is much faster than a query with a
+    /// pattern that matches every file. Errors are always returned.
+    pub fn find_packages(
+        self,
+        name_pattern: &Regex,
+    ) -> impl Iterator<Item = Result<StorePath>> + '_ {
+        self.packages().filter(move |store_path| match store_path {
+            Ok(store_path) => name_pattern.is_match(store_path.name().as_bytes()),
+            Err(_) => true,
+        })
+    }
+
+    /// Returns an iterator over all file entries of the package whose store path has the given
+    /// hash, for example to show the contents of a package.
+    ///
+    /// Unlike a query, this does not match the entries against a pattern: only the package
+    /// entries are checked, and only the file entries of the package are decoded. The search
+    /// stops after the package was found, so if the database contains the same store path
+    /// more than once, only the files of the first one are returned. If no package has the
+    /// hash, the iterator is empty.
+    pub fn package_files(self, hash: &str) -> PackageFilesIter {
+        PackageFilesIter {
+            reader: Some(self),
+            hash: hash.to_string(),
+            unassigned: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Decodes the next block and appends the store paths of all packages in it to `packages`.
+    ///
+    /// Returns false once the end of the database has been reached.
+    fn decode_packages(&mut self, packages: &mut VecDeque<Result<StorePath>>) -> Result<bool> {
+        let block = self.decoder.decode()?;
+        let Some((_, block)) = block.split_last() else {
+            return Ok(false);
+        };
+
+        for json in block
+            .split(|c| *c == b'\n')
+            .filter_map(|l| l.strip_prefix(b"p\0"))
+        {
+            packages.push_back(
+                serde_json::from_slice(json).chain_err(|| ErrorKind::StorePathParse(json.to_vec())),
+            );
+        }
+        Ok(true)
+    }
 
     /// Builds a query to find all entries in the database that have a filename matching the given pattern.
     ///
     /// Afterwards, use `Query::into_iter` to iterate over the items.
-    pub fn query(self, exact_regex: &Regex) -> Query {
+    pub fn query(self, exact_regex: &Regex) -> Query<'_, '_> {
+        self.query_multi(std::slice::from_ref(exact_regex))
+    }
+
+    /// Builds a query to find all entries that have a filename matching any of the given patterns.
+    ///
+    /// The database is only decoded once for all patterns. Use `Query::run_tagged` to learn which
+    /// pattern an entry matched.
+    pub fn query_multi(self, exact_regexes: &[Regex]) -> Query<'_, '_> {
         Query {
             reader: self,
-            exact_regex,
-            hash: None,
+            exact_regexes: Cow::Borrowed(exact_regexes),
+            compiled: None,
+            hashes: None,
             package_pattern: None,
+            exclude: None,
+            output: None,
+            case_insensitive: false,
+            whole_basename: false,
+            anchor_end: false,
+            dedup_packages: false,
+            max_buffered: None,
+            sorted: false,
+            size_limit: None,
+            limit: None,
+            path_prefix: None,
+            file_type: None,
+            min_size: None,
+            max_size: None,
+            executable_only: false,
+            cancel_token: None,
+            on_progress: None,
+            threads: 1,
+        }
+    }
+
+    /// Builds a query like `query_multi`, but for patterns that were compiled ahead of time.
+    ///
+    /// This avoids parsing the patterns again for each query, which is useful when the same
+    /// patterns are used for many queries. For a single pattern, the grep matcher of the
+    /// `CompiledPattern` is used as is, unless the query changes it with `case_insensitive`,
+    /// `whole_basename`, `anchor_end` or `size_limit`.
+    pub fn query_compiled(self, patterns: &[CompiledPattern]) -> Query<'_, '_> {
+        let exact_regexes = patterns.iter().map(|p| p.regex.clone()).collect();
+        Query {
+            exact_regexes: Cow::Owned(exact_regexes),
+            compiled: Some(patterns),
+            ..self.query_multi(&[])
+        }
+    }
+
+    /// Dumps the contents of the database to stdout, for debugging.
+    pub fn dump(&mut self) -> Result<()> {
+        self.dump_to(&mut io::stdout().lock())
+    }
+
+    /// Dumps the contents of the database to `out`, for debugging.
+    ///
+    /// Each raw entry is written on its own line, and the boundaries of the decoded blocks
+    /// are marked with `-- block boundary`.
+    pub fn dump_to<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        loop {
+            let block = self.decoder.decode()?;
+            if block.is_empty() {
+                break;
+            }
+            for line in block.split(|c| *c == b'\n') {
+                writeln!(out, "{:?}", String::from_utf8_lossy(line))?;
+            }
+            writeln!(out, "-- block boundary")?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Dumps the contents of the database as newline-delimited JSON.
+    ///
+    /// Each line of the output is a JSON object describing a single file entry, with the
+    /// fields `store_path`, `attr`, `output`, `toplevel`, `path` and `type` (one of `regular`,
+    /// `directory` or `symlink`). Regular files additionally have `size` and `executable` fields,
+    /// directories have a `size` field and symlinks have a `target` field. Paths and targets
+    /// that are not valid UTF-8 are encoded as base64, as described for `FileTreeEntry`.
+    pub fn dump_json<W: Write>(&mut self, mut out: W) -> Result<()> {
+        self.for_each_package(|store_path, entries| {
+            for entry in &entries {
+                serde_json::to_writer(&mut out, &JsonEntry::new(&store_path, entry))
+                    .map_err(io::Error::from)?;
+                out.write_all(b"\n")?;
+            }
+            Ok(())
+        })?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Checks the integrity of the database by decoding all remaining entries.
+    ///
+    /// This verifies that every entry can be parsed and that each group of file entries is
+    /// terminated by a package entry. Problems with individual entries are recorded as recoverable
+    /// errors and checking continues with the next entry. If the frcode stream itself is damaged
+    /// (for example, because the file was truncated), checking stops and the error is recorded as
+    /// fatal.
+    ///
+    /// Inconsistencies are reported through the returned `VerifyReport`, not as an `Err`.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut pending_files = 0;
+        loop {
+            let block = match self.decoder.decode() {
+                Ok(block) => block,
+                Err(e) => {
+                    report.fatal = Some(e.into());
+                    break;
+                }
+            };
+            let Some((_, block)) = block.split_last() else {
+                break;
+            };
+
+            for line in block.split(|c| *c == b'\n') {
+                if let Some(json) = line.strip_prefix(b"p\0") {
+                    if let Err(e) = serde_json::from_slice::<StorePath>(json) {
+                        report.recoverable.push(Error::with_chain(
+                            e,
+                            ErrorKind::StorePathParse(json.to_vec()),
+                        ));
+                    }
+                    report.packages += 1;
+                    report.files += pending_files;
+                    pending_files = 0;
+                } else if decode_entry(self.version, line).is_ok() {
+                    pending_files += 1;
+                } else {
+                    report
+                        .recoverable
+                        .push(ErrorKind::EntryParse(line.to_vec()).into());
+                }
+            }
+        }
+
+        if pending_files > 0 {
+            report
+                .recoverable
+                .push(ErrorKind::MissingPackageEntry.into());
+        }
+
+        if self.version >= FOOTER_VERSION {
+            if let Err(e) = self.footer() {
+                report.recoverable.push(e);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Decodes all remaining entries of the database and calls `f` once for every package,
+    /// with all the file entries of that package.
+    ///
+    /// The entries of a package are stored before the package entry itself, so entries are
+    /// buffered until the package entry that terminates them is found, even if that entry is
+    /// only in one of the next blocks.
+    fn for_each_package<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(StorePath, Vec<FileTreeEntry>) -> Result<()>,
+    {
+        let mut entries = Vec::new();
+        loop {
+            let block = self.decoder.decode()?;
+            let Some((_, block)) = block.split_last() else {
+                break;
+            };
+
+            for line in block.split(|c| *c == b'\n') {
+                if let Some(json) = line.strip_prefix(b"p\0") {
+                    let store_path: StorePath = serde_json::from_slice(json)
+                        .chain_err(|| ErrorKind::StorePathParse(json.to_vec()))?;
+                    f(store_path, std::mem::take(&mut entries))?;
+                } else {
+                    entries.push(decode_entry(self.version, line)?);
+                }
+            }
+        }
+
+        if !entries.is_empty() {
+            return Err(ErrorKind::MissingPackageEntry.into());
+        }
+        Ok(())
+    }
+}
+
+/// An iterator over the store paths of the packages in a database, see `Reader::packages`.
+pub struct PackagesIter {
+    /// The reader, until the end of the database or an error that prevents decoding the
+    /// rest of it has been reached.
+    reader: Option<Reader>,
+    /// The store paths that were decoded but not returned yet.
+    pending: VecDeque<Result<StorePath>>,
+}
+
+impl Iterator for PackagesIter {
+    type Item = Result<StorePath>;
+
+    fn next(&mut self) -> Option<Result<StorePath>> {
+        while self.pending.is_empty() {
+            let reader = self.reader.as_mut()?;
+            match reader.decode_packages(&mut self.pending) {
+                Ok(true) => {}
+                Ok(false) => self.reader = None,
+                Err(e) => {
+                    self.reader = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+/// An iterator over the file entries of a single package, see `Reader::package_files`.
+pub struct PackageFilesIter {
+    /// The reader, until the package or the end of the database was found, or an error that
+    /// prevents decoding the rest of it has been reached.
+    reader: Option<Reader>,
+    /// The hash of the store path of the package.
+    hash: String,
+    /// The raw file entries after the last package entry that was decoded. They belong to a
+    /// package whose entry is in one of the next blocks.
+    unassigned: Vec<u8>,
+    /// The file entries of the package that were decoded but not returned yet.
+    pending: VecDeque<Result<FileTreeEntry>>,
+}
+
+impl PackageFilesIter {
+    /// Decodes the next block. If it contains the entry of the package, the file entries of
+    /// the package are appended to `pending`.
+    ///
+    /// Returns false once the package or the end of the database has been found.
+    fn decode_block(&mut self) -> Result<bool> {
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(false);
+        };
+        let block = reader.decoder.decode()?;
+        if block.is_empty() {
+            return Ok(false);
+        }
+
+        // the start of the file entries that belong to the next package entry of the block
+        let mut start = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let line_start = pos;
+            pos = memchr(b'\n', &block[pos..]).map_or(block.len(), |i| pos + i + 1);
+            let Some(json) = block[line_start..pos].strip_prefix(b"p\0") else {
+                continue;
+            };
+            let json = json.strip_suffix(b"\n").unwrap_or(json);
+
+            // the hash is part of the JSON, so most package entries don't have to be parsed
+            if memmem::find(json, self.hash.as_bytes()).is_some() {
+                let store_path: StorePath = serde_json::from_slice(json)
+                    .chain_err(|| ErrorKind::StorePathParse(json.to_vec()))?;
+                if store_path.hash() == self.hash {
+                    self.unassigned.extend_from_slice(&block[start..line_start]);
+                    let entries = self.unassigned.split(|c| *c == b'\n');
+                    for line in entries.filter(|line| !line.is_empty()) {
+                        self.pending.push_back(decode_entry(reader.version, line));
+                    }
+                    return Ok(false);
+                }
+            }
+            self.unassigned.clear();
+            start = pos;
+        }
+        self.unassigned.extend_from_slice(&block[start..]);
+        Ok(true)
+    }
+}
+
+impl Iterator for PackageFilesIter {
+    type Item = Result<FileTreeEntry>;
+
+    fn next(&mut self) -> Option<Result<FileTreeEntry>> {
+        while self.pending.is_empty() {
+            self.reader.as_ref()?;
+            match self.decode_block() {
+                Ok(true) => {}
+                Ok(false) => self.reader = None,
+                Err(e) => {
+                    self.reader = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+/// Statistics about a database, as returned by `Reader::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbStats {
+    /// The version of the database format.
+    pub version: u64,
+    /// The application tag of the database, see `Reader::application_tag`.
+    pub application_tag: [u8; 4],
+    /// The codec used to compress the database.
+    pub codec: Codec,
+    /// The size of the database file, in bytes.
+    pub compressed_size: u64,
+    /// The number of packages in the database.
+    pub packages: usize,
+    /// The number of file entries in the database.
+    pub files: usize,
+}
+
+/// A reader for querying a database from async code.
+///
+/// Opening the database and searching it happens on tokio's blocking thread pool. Queries are
+/// built with the same `Query` as for `Reader` and run with `Query::run_stream`.
+#[cfg(feature = "tokio")]
+pub struct AsyncReader {
+    reader: Reader,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncReader {
+    /// Opens a nix-index database located at the given path, like `Reader::open`.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<AsyncReader> {
+        let path = path.as_ref().to_path_buf();
+        let reader = tokio::task::spawn_blocking(move || Reader::open(path))
+            .await
+            .expect("open task panicked")?;
+        Ok(AsyncReader { reader })
+    }
+
+    /// Builds a query to find all entries in the database that have a filename matching the given pattern.
+    ///
+    /// Use `Query::run_stream` to get the matches.
+    pub fn query(self, exact_regex: &Regex) -> Query<'_, '_> {
+        self.reader.query(exact_regex)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl From<Reader> for AsyncReader {
+    fn from(reader: Reader) -> AsyncReader {
+        AsyncReader { reader }
+    }
+}
+
+/// The result of checking the integrity of a database with `Reader::verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// The number of package entries that were found.
+    pub packages: usize,
+    /// The number of file entries that belong to a package.
+    pub files: usize,
+    /// Problems with individual entries. The rest of the database could still be read.
+    pub recoverable: Vec<Error>,
+    /// A problem that prevented reading the rest of the database, if any.
+    pub fatal: Option<Error>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.recoverable.is_empty() && self.fatal.is_none()
+    }
+}
+
+/// The JSON representation of a single file entry, as written by `Reader::dump_json`.
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    store_path: Cow<'a, str>,
+    attr: &'a str,
+    output: &'a str,
+    toplevel: bool,
+    #[serde(flatten)]
+    entry: &'a FileTreeEntry,
+}
+
+impl<'a> JsonEntry<'a> {
+    fn new(store_path: &'a StorePath, entry: &'a FileTreeEntry) -> JsonEntry<'a> {
+        let origin = store_path.origin_ref();
+        JsonEntry {
+            store_path: store_path.as_str(),
+            attr: &origin.attr,
+            output: &origin.output,
+            toplevel: origin.toplevel,
+            entry,
+        }
+    }
+}
+
+/// Translates a shell glob such as `*.so` or `bin/py*` to a regex for `Reader::query`.
+///
+/// `*` matches any number of characters except `/`, `**` also matches `/`, `?` matches a single
+/// character except `/` and `[...]` matches one of the listed characters (`[!...]` negates the
+/// set). All other characters match literally.
+///
+/// If the glob starts with `/`, it has to match from the root of the store path. Otherwise, it
+/// may start at any path component, so `bin/py*` matches `/bin/python` and `/usr/bin/python`.
+/// If `anchor_end` is true, the glob has to match the whole file name, otherwise it is enough
+/// if it matches a prefix of the file name (so `py` matches `/bin/python`).
+pub fn glob_to_regex(glob: &str, anchor_end: bool) -> Regex {
+    let mut pattern = String::from(if glob.starts_with('/') {
+        "^"
+    } else {
+        "(?:^|/)"
+    });
+
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            '[' => {
+                let rest: String = chars.clone().collect();
+                let negated = rest.starts_with('!') || rest.starts_with('^');
+                let body = &rest[usize::from(negated)..];
+                // a `]` directly after the opening bracket is part of the set
+                let close = body.char_indices().skip(1).find(|&(_, c)| c == ']');
+                let Some((close, _)) = close else {
+                    // no closing bracket, so the `[` is literal
+                    pattern.push_str(r"\[");
+                    continue;
+                };
+
+                pattern.push_str(if negated { "[^/" } else { "[" });
+                for c in body[..close].chars() {
+                    // keep `-` unescaped so ranges like `[a-z]` work
+                    if c == '-' {
+                        pattern.push(c);
+                    } else {
+                        pattern.push_str(&regex_syntax::escape(c.encode_utf8(&mut [0; 4])));
+                    }
+                }
+                pattern.push(']');
+
+                let consumed = usize::from(negated) + body[..=close].chars().count();
+                for _ in 0..consumed {
+                    chars.next();
+                }
+            }
+            c => pattern.push_str(&regex_syntax::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    if !anchor_end {
+        pattern.push_str("[^/]*");
+    }
+    pattern.push('$');
+    Regex::new(&pattern).expect("glob is translated to a valid regex")
+}
+
+/// Options for `locate`. The default options find all entries matching the pattern.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct LocateOptions {
+    /// Only include the package with the given hash (see `Query::hash`).
+    pub hash: Option<String>,
+    /// Only include packages whose name matches this regex (see `Query::package_pattern`).
+    pub package_pattern: Option<String>,
+    /// Match file paths case-insensitively (see `Query::case_insensitive`).
+    pub case_insensitive: bool,
+    /// The maximum number of entries to return (see `Query::limit`).
+    pub limit: Option<usize>,
+    /// Only include entries of the given file type (see `Query::file_type`).
+    pub file_type: Option<FileType>,
+}
+
+/// Searches the database at `db_path` for entries whose path matches the regex `pattern`.
+///
+/// This is a shortcut for opening the database with `Reader::open` and running a `Query`
+/// with the given options. As with `Reader::query`, the pattern may match anywhere in the
+/// path and a leading `^` anchors it at the root of the store path, so `^/bin/` only finds
+/// entries directly below `bin`.
+pub fn locate<P: AsRef<Path>>(
+    db_path: P,
+    pattern: &str,
+    opts: LocateOptions,
+) -> Result<impl Iterator<Item = Result<Match>>> {
+    let pattern = Regex::new(pattern)?;
+    let package_pattern = opts
+        .package_pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()?;
+
+    let iter = Reader::open(db_path)?
+        .query(&pattern)
+        .hash(opts.hash)
+        .package_pattern(package_pattern.as_ref())
+        .case_insensitive(opts.case_insensitive)
+        .limit(opts.limit)
+        .file_type(opts.file_type)
+        .run()?;
+    Ok(iter.into_owned())
+}
+
+/// Finds the packages in the database at `db_path` that provide the command `command`.
+///
+/// These are the packages that contain an executable or symlink called `command` directly in
+/// their `bin` directory. Each store path is only returned once. Store paths of the `out` and
+/// `bin` outputs come first, followed by the other outputs, and within each of these groups,
+/// top-level packages come before packages that were only found as a dependency.
+pub fn which<P: AsRef<Path>>(db_path: P, command: &str) -> Result<Vec<StorePath>> {
+    let pattern = Regex::new(&format!("^/bin/{}$", regex::escape(command)))?;
+    let mut store_paths = Reader::open(db_path)?
+        .query(&pattern)
+        .dedup_packages(true)
+        .run()?
+        .filter(|m| {
+            m.as_ref().map_or(true, |m| {
+                matches!(
+                    m.entry.node_type(),
+                    FileType::Regular { executable: true } | FileType::Symlink
+                )
+            })
+        })
+        .map(|m| m.map(|m| m.store_path))
+        .collect::<Result<Vec<_>>>()?;
+
+    store_paths.sort_by_key(|store_path| {
+        let origin = store_path.origin_ref();
+        let main_output = origin.output == "out" || origin.output == "bin";
+        (!main_output, !origin.toplevel)
+    });
+    Ok(store_paths)
+}
+
+/// A cache for the results of repeated queries against the same database.
+///
+/// Since a `Reader` is consumed by a query, the cache does not keep the database open. It
+/// only stores the path and opens the database again (with `locate`) whenever a query is not
+/// cached. The results of the most recently used queries are kept until the number of cached
+/// queries or the approximate size of their results exceeds the configured budget.
+///
+/// All cached results are dropped once the modification time or the size of the database file
+/// changes, so rebuilding the database does not return stale results even if the file system
+/// only records modification times with a coarse resolution.
+pub struct QueryCache {
+    path: PathBuf,
+    max_entries: usize,
+    max_bytes: usize,
+    /// The cached results, from the least to the most recently used.
+    entries: IndexMap<(String, LocateOptions), Arc<[Match]>>,
+    /// The approximate size of all cached results, in bytes.
+    bytes: usize,
+    /// The modification time and the size of the database when the cached results were
+    /// computed.
+    modified: Option<(SystemTime, u64)>,
+}
+
+impl QueryCache {
+    /// Creates a cache for the database at `path`.
+    ///
+    /// By default, the results of up to 64 queries with a total size of about 64 MiB are
+    /// cached.
+    pub fn new<P: AsRef<Path>>(path: P) -> QueryCache {
+        QueryCache {
+            path: path.as_ref().to_path_buf(),
+            max_entries: 64,
+            max_bytes: 64 * 1024 * 1024,
+            entries: IndexMap::new(),
+            bytes: 0,
+            modified: None,
+        }
+    }
+
+    /// Sets the maximum number of queries whose results are cached.
+    pub fn max_entries(mut self, max_entries: usize) -> QueryCache {
+        self.max_entries = max_entries;
+        self.evict();
+        self
+    }
+
+    /// Sets the maximum total size of the cached results, in bytes.
+    ///
+    /// The size of a result is estimated from the size of its matches. Results that are
+    /// larger than this on their own are never cached.
+    pub fn max_bytes(mut self, max_bytes: usize) -> QueryCache {
+        self.max_bytes = max_bytes;
+        self.evict();
+        self
+    }
+
+    /// Searches the database like `locate`, but returns cached results if the same query
+    /// was run before and the database has not changed since.
+    pub fn query(&mut self, pattern: &str, opts: &LocateOptions) -> Result<Arc<[Match]>> {
+        let metadata = fs::metadata(&self.path)?;
+        let modified = metadata.modified().ok().map(|time| (time, metadata.len()));
+        if modified.is_none() || modified != self.modified {
+            self.clear();
+            self.modified = modified;
+        }
+
+        let key = (pattern.to_string(), opts.clone());
+        if let Some(index) = self.entries.get_index_of(&key) {
+            let last = self.entries.len() - 1;
+            self.entries.move_index(index, last);
+            return Ok(self.entries[last].clone());
+        }
+
+        let matches =
+            locate(&self.path, pattern, opts.clone())?.collect::<Result<Arc<[Match]>>>()?;
+        let bytes = QueryCache::estimated_size(&matches);
+        if bytes <= self.max_bytes && self.max_entries > 0 {
+            self.bytes += bytes;
+            self.entries.insert(key, matches.clone());
+            self.evict();
+        }
+        Ok(matches)
+    }
+
+    /// Removes all cached results.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    /// Removes the least recently used results until the cache is within its budget.
+    fn evict(&mut self) {
+        while self.entries.len() > self.max_entries || self.bytes > self.max_bytes {
+            let Some((_, matches)) = self.entries.shift_remove_index(0) else {
+                break;
+            };
+            self.bytes -= QueryCache::estimated_size(&matches);
+        }
+    }
+
+    /// Estimates the memory used by the given matches.
+    fn estimated_size(matches: &[Match]) -> usize {
+        matches
+            .iter()
+            .map(|m| {
+                let origin = m.origin();
+                mem::size_of::<Match>()
+                    + m.entry.path.len()
+                    + m.store_path.as_str().len()
+                    + origin.attr.len()
+                    + origin.output.len()
+            })
+            .sum()
+    }
+}
+
+/// A builder for a `ReaderIter` to iterate over entries in the database matching a given pattern.
+pub struct Query<'a, 'b> {
+    /// The underlying reader from which we read input.
+    reader: Reader,
+
+    /// The patterns that file paths are matched against. A path has to match at least one of them.
+    exact_regexes: Cow<'a, [Regex]>,
+
+    /// The patterns of `exact_regexes`, if they were compiled ahead of time.
+    compiled: Option<&'a [CompiledPattern]>,
+
+    /// Only include the packages with the given hashes.
+    hashes: Option<HashSet<String>>,
+
+    /// Only include packages whose name matches the given pattern.
+    package_pattern: Option<&'b Regex>,
+
+    /// Skip entries whose path matches the given pattern.
+    exclude: Option<&'b Regex>,
+
+    /// Only include store paths of the given output of their derivation.
+    output: Option<String>,
+
+    /// Whether file paths should be matched without regard to case.
+    case_insensitive: bool,
+
+    /// Whether the patterns have to match the whole file name of an entry.
+    whole_basename: bool,
+
+    /// Whether the patterns have to match at the end of the path of an entry.
+    anchor_end: bool,
+
+    /// Whether packages with the same store path as an earlier package should be skipped.
+    dedup_packages: bool,
+
+    /// The number of matches after which searching a block is paused.
+    max_buffered: Option<usize>,
+
+    /// Whether the matches of each block are returned sorted by package and path.
+    sorted: bool,
+
+    /// The size limit for the compiled patterns, in bytes.
+    size_limit: Option<usize>,
+
+    /// The maximum number of entries to return.
+    limit: Option<usize>,
+
+    /// Only include entries below the given directory of the package.
+    path_prefix: Option<Vec<u8>>,
+
+    /// Only include entries of the given file type.
+    file_type: Option<FileType>,
+
+    /// Only include regular files that are at least this large.
+    min_size: Option<u64>,
+
+    /// Only include regular files that are at most this large.
+    max_size: Option<u64>,
+
+    /// Only include regular files with the executable bit set.
+    executable_only: bool,
+
+    /// Stop the query with `ErrorKind::Cancelled` once this is set.
+    cancel_token: Option<Arc<AtomicBool>>,
+
+    /// Called after each block of the database has been decoded.
+    on_progress: Option<ProgressCallback>,
+
+    /// The number of blocks that are searched at once on a thread pool.
+    threads: usize,
+}
+
+/// A callback that receives progress updates while a query runs.
+pub type ProgressCallback = Box<dyn FnMut(ProgressInfo) + Send>;
+
+/// Information about the progress of a running query, passed to the callback set with
+/// `Query::on_progress`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ProgressInfo {
+    /// The number of decompressed bytes that have been searched so far.
+    pub bytes: u64,
+    /// The number of blocks that have been searched so far.
+    pub blocks: usize,
+}
+
+/// Tracks the progress of a query and reports it to the callback, if there is one.
+struct Progress {
+    callback: Option<ProgressCallback>,
+    info: ProgressInfo,
+}
+
+impl Progress {
+    /// Records that a block of `len` bytes has been decoded.
+    fn block_decoded(&mut self, len: usize) {
+        self.info.bytes += len as u64;
+        self.info.blocks += 1;
+        if let Some(callback) = self.callback.as_mut() {
+            callback(self.info);
+        }
+    }
+}
+
+impl<'a, 'b> Query<'a, 'b> {
+    /// Limit results to entries from the package with the specified hash if `Some`.
+    ///
+    /// This is a shortcut for `hashes` with a single hash.
+    pub fn hash(self, hash: Option<String>) -> Query<'a, 'b> {
+        match hash {
+            Some(hash) => self.hashes(HashSet::from([hash])),
+            None => Query {
+                hashes: None,
+                ..self
+            },
+        }
+    }
+
+    /// Limit results to entries from the packages whose store path hash is in `hashes`.
+    ///
+    /// This can be used to search only a known set of store paths, such as the closure of a
+    /// system, in a single pass over the database.
+    pub fn hashes(self, hashes: HashSet<String>) -> Query<'a, 'b> {
+        Query {
+            hashes: Some(hashes),
+            ..self
+        }
+    }
+
+    /// Limit results to entries from packages whose name matches the given regex if `Some`.
+    pub fn package_pattern(self, package_pattern: Option<&'b Regex>) -> Query<'a, 'b> {
+        Query {
+            package_pattern,
+            ..self
+        }
+    }
+
+    /// Skip entries whose path matches the given regex if `Some`, even if they match the
+    /// patterns of the query.
+    ///
+    /// Like the patterns of the query, the regex is matched against the path of the entry
+    /// relative to its store path, for example `/lib/debug/` to skip debug symbols. It is
+    /// used as is, so it is not affected by `case_insensitive`, `whole_basename` or `anchor_end`.
+    pub fn exclude(self, exclude: Option<&'b Regex>) -> Query<'a, 'b> {
+        Query { exclude, ..self }
+    }
+
+    /// Limit results to store paths of the given output (such as `bin`, `man` or `dev`) if `Some`.
+    ///
+    /// This compares the output recorded in the origin of each store path, see
+    /// `Match::output`.
+    pub fn output(self, output: Option<&str>) -> Query<'a, 'b> {
+        Query {
+            output: output.map(str::to_string),
+            ..self
+        }
+    }
+
+    /// Match file paths case-insensitively if `true`.
+    ///
+    /// This applies to both the pattern used for searching and the exact pattern
+    /// used to verify matches, so `LIBSSL` will find `libssl.so`.
+    pub fn case_insensitive(self, case_insensitive: bool) -> Query<'a, 'b> {
+        Query {
+            case_insensitive,
+            ..self
+        }
+    }
+
+    /// Only match the whole file name (the last component of the path) if `true`.
+    ///
+    /// For example, `ls` then matches `/bin/ls` but not `/bin/false` or `/share/tools`.
+    /// The pattern may still contain `/` to match the directories above the file, so
+    /// `bin/ls` matches `/bin/ls` and `/usr/bin/ls`.
+    pub fn whole_basename(self, whole_basename: bool) -> Query<'a, 'b> {
+        Query {
+            whole_basename,
+            ..self
+        }
+    }
+
+    /// Only match at the end of the path if `true`, as if the patterns ended with `$`.
+    ///
+    /// For example, `libssl.so` then matches `/lib/libssl.so` but not `/lib/libssl.so.3`.
+    /// Since the end of the path is also the end of the raw entry in the database, this lets
+    /// the search skip most entries that only contain the pattern somewhere else, which makes
+    /// lookups of exact file names faster. `whole_basename` implies this.
+    pub fn anchor_end(self, anchor_end: bool) -> Query<'a, 'b> {
+        Query { anchor_end, ..self }
+    }
+
+    /// Skip packages with the same store path as a package that was already returned if `true`.
+    ///
+    /// nix-env may report the same store path under several attribute paths (for example
+    /// because of aliases). If a database contains such a package more than once, each of its
+    /// files is only returned once, for the first package in the database.
+    pub fn dedup_packages(self, dedup_packages: bool) -> Query<'a, 'b> {
+        Query {
+            dedup_packages,
+            ..self
+        }
+    }
+
+    /// Pause searching once `max_buffered` matches are waiting to be returned if `Some`.
+    ///
+    /// By default, each block of the database is searched completely before the matches in it
+    /// are returned. For patterns that match a large part of the database, this can take a
+    /// lot of memory. With this option, searching continues where it stopped once the matches
+    /// found so far have been consumed. Matches that belong to a package whose entry is in the
+    /// next block are not counted, so this is a soft limit.
+    pub fn max_buffered(self, max_buffered: Option<usize>) -> Query<'a, 'b> {
+        Query {
+            max_buffered,
+            ..self
+        }
+    }
+
+    /// Return the matches sorted by store path and then by file path if `true`.
+    ///
+    /// All entries of a package are stored next to each other, so the matches are sorted as
+    /// they are found in each block of the database, without collecting all of them first.
+    /// The files of each package are returned in order, but packages in different blocks are
+    /// not sorted relative to each other. With `max_buffered`, only the matches that were
+    /// buffered together are sorted.
+    pub fn sorted(self, sorted: bool) -> Query<'a, 'b> {
+        Query { sorted, ..self }
+    }
+
+    /// Fail with `ErrorKind::PatternTooLarge` if a compiled pattern would use more than
+    /// `size_limit` bytes, if `Some`. The same limit applies to the cache of the lazy DFA
+    /// that is used while searching.
+    ///
+    /// The patterns are rewritten and compiled again when the query is run, so this is
+    /// important for services that accept untrusted patterns. Such patterns should also be
+    /// compiled with a size limit (see `RegexBuilder::size_limit`) before they are passed to
+    /// `Reader::query`.
+    pub fn size_limit(self, size_limit: Option<usize>) -> Query<'a, 'b> {
+        Query { size_limit, ..self }
+    }
+
+    /// Stop after yielding `limit` entries if `Some`.
+    ///
+    /// Once the limit is reached, no more blocks are read from the database.
+    pub fn limit(self, limit: Option<usize>) -> Query<'a, 'b> {
+        Query { limit, ..self }
+    }
+
+    /// Limit results to entries in the given subtree of each package if `Some`, for
+    /// example `bin` or `share/man`.
+    ///
+    /// The prefix is compared as literal bytes (it is not a regex) and only matches whole
+    /// path components, so `bin` matches `/bin/foo` but not `/binfoo`. Leading and trailing
+    /// slashes are ignored.
+    ///
+    /// This only filters the matches of the patterns once they were decoded: the prefix is not
+    /// part of the pattern that the blocks of the database are searched with, so it does not
+    /// let the search skip any blocks. If that matters, anchor the pattern itself instead, for
+    /// example `^/bin/.*foo` rather than `foo` with the prefix `bin`.
+    pub fn path_prefix(self, path_prefix: Option<&[u8]>) -> Query<'a, 'b> {
+        let path_prefix = path_prefix.map(|prefix| {
+            let start = prefix
+                .iter()
+                .position(|c| *c != b'/')
+                .unwrap_or(prefix.len());
+            let end = prefix
+                .iter()
+                .rposition(|c| *c != b'/')
+                .map_or(start, |i| i + 1);
+            let mut normalized = Vec::with_capacity(end - start + 1);
+            normalized.push(b'/');
+            normalized.extend_from_slice(&prefix[start..end]);
+            normalized
+        });
+        Query {
+            path_prefix,
+            ..self
+        }
+    }
+
+    /// Limit results to entries of the given file type if `Some`.
+    ///
+    /// Regular files are distinguished by their executable bit, so
+    /// `FileType::Regular { executable: true }` only matches executables. Directories are
+    /// indexed by default, so `FileType::Directory` finds directories such as
+    /// `/include/openssl` whose path matches the patterns, even if they are empty.
+    pub fn file_type(self, file_type: Option<FileType>) -> Query<'a, 'b> {
+        Query { file_type, ..self }
+    }
+
+    /// Limit results to regular files of at least `min_size` bytes if `Some`.
+    ///
+    /// Directories and symlinks do not have a size in bytes, so they are never returned
+    /// if a size limit is set.
+    pub fn min_size(self, min_size: Option<u64>) -> Query<'a, 'b> {
+        Query { min_size, ..self }
+    }
+
+    /// Limit results to regular files of at most `max_size` bytes if `Some`.
+    ///
+    /// Like with `min_size`, directories and symlinks are never returned if this is set.
+    pub fn max_size(self, max_size: Option<u64>) -> Query<'a, 'b> {
+        Query { max_size, ..self }
+    }
+
+    /// Limit results to regular files with the executable bit set if `true`.
+    ///
+    /// This is the same as `file_type(Some(FileType::Regular { executable: true }))`, but can
+    /// be combined with another file type filter.
+    pub fn executable_only(self, executable_only: bool) -> Query<'a, 'b> {
+        Query {
+            executable_only,
+            ..self
+        }
+    }
+
+    /// Allows cancelling the query from another thread by setting `token` to `true`.
+    ///
+    /// The token is checked before each block of the database is decoded. Once it is set,
+    /// the query returns an `ErrorKind::Cancelled` error.
+    pub fn cancel_token(self, token: Arc<AtomicBool>) -> Query<'a, 'b> {
+        Query {
+            cancel_token: Some(token),
+            ..self
+        }
+    }
+
+    /// Calls `callback` each time a block of the database has been searched.
+    ///
+    /// This can be used to give feedback during queries over large databases, for example
+    /// by showing how much data has been searched so far.
+    pub fn on_progress(self, callback: ProgressCallback) -> Query<'a, 'b> {
+        Query {
+            on_progress: Some(callback),
+            ..self
+        }
+    }
+
+    /// Search up to `threads` blocks of the database at once on a pool of worker threads if
+    /// `threads` is larger than one.
+    ///
+    /// Matching the patterns against the entries of a block is CPU-bound, so this makes broad
+    /// queries faster on machines with many cores. The blocks are still decoded one after
+    /// another on the calling thread, and the matches are returned in the same order as
+    /// without this option. `max_buffered` is ignored, since the matches of all blocks that
+    /// are searched together are buffered.
+    pub fn parallel(self, threads: usize) -> Query<'a, 'b> {
+        Query { threads, ..self }
+    }
+
+    /// Runs the query, returning only the number of entries matching the conditions.
+    ///
+    /// This is faster than counting the items of the iterator returned by `run`, since
+    /// matching entries are not decoded. The package of each entry is only looked up if
+    /// the query filters by package name or hash.
+    pub fn count(self) -> Result<usize> {
+        let mut iter = self.run()?;
+        if iter.needs_package() {
+            iter.try_fold(0, |count, item| item.map(|_| count + 1))
+        } else {
+            iter.count_without_package()
+        }
+    }
+
+    /// Runs the query, returning an Iterator that will yield all entries matching the conditions.
+    ///
+    /// There is no guarantee about the order of the returned matches.
+    pub fn run(self) -> Result<ReaderIter<'a, 'b>> {
+        let too_large = |limit| Error::from(ErrorKind::PatternTooLarge(limit));
+        let anchor = if self.whole_basename {
+            PathAnchor::Basename
+        } else if self.anchor_end {
+            PathAnchor::End
+        } else {
+            PathAnchor::None
+        };
+        let anchored = anchor != PathAnchor::None;
+
+        let mut regex_builder = grep_matcher_builder();
+        let package_entry_pattern = regex_builder.build("^p\0").expect("valid regex");
+
+        let literal = match (&*self.exact_regexes, self.compiled) {
+            _ if self.case_insensitive => None,
+            (_, Some([compiled])) => compiled.literal.clone(),
+            ([regex], None) => literal_bytes(regex),
+            _ => None,
+        };
+        let pattern = match (literal, self.compiled) {
+            // all candidates are checked against the exact patterns, so searching for the
+            // literal is enough even if the whole basename has to match. Each entry ends with
+            // a newline, so an anchored literal is followed by one.
+            (Some(mut literal), _) => {
+                if anchored {
+                    literal.push(b'\n');
+                }
+                LineFinder::Literal(memmem::Finder::new(&literal).into_owned())
+            }
+            (None, Some([compiled]))
+                if !self.case_insensitive && !anchored && self.size_limit.is_none() =>
+            {
+                LineFinder::Regex(compiled.matcher.clone())
+            }
+            (None, _) => {
+                // the grep matcher searches for all patterns at once, so each of them is a
+                // branch of a single alternation
+                let branches = self
+                    .exact_regexes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, regex)| -> Result<_> {
+                        let grep_pattern = match self.compiled {
+                            Some(compiled) if !anchored => {
+                                Cow::Borrowed(compiled[i].grep_pattern.as_str())
+                            }
+                            _ => {
+                                Cow::Owned(Query::grep_pattern(parse_ast(regex.as_str())?, anchor))
+                            }
+                        };
+                        Ok(format!("(?:{})", grep_pattern))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                // the flags are applied through the builders, since the pattern string itself
+                // is produced from the rewritten AST.
+                let grep_pattern = branches.join("|");
+                regex_builder.case_insensitive(self.case_insensitive);
+                if let Some(limit) = self.size_limit {
+                    // grep does not tell apart the errors of patterns that are too large, so
+                    // the size is checked by compiling the pattern with the regex crate first
+                    let checked = RegexBuilder::new(&grep_pattern)
+                        .multi_line(true)
+                        .case_insensitive(self.case_insensitive)
+                        .size_limit(limit)
+                        .dfa_size_limit(limit)
+                        .build();
+                    match checked {
+                        Err(regex::Error::CompiledTooBig(limit)) => return Err(too_large(limit)),
+                        Err(e) => return Err(e.into()),
+                        Ok(_) => {}
+                    }
+                    regex_builder.size_limit(limit).dfa_size_limit(limit);
+                }
+                LineFinder::Regex(regex_builder.build(&grep_pattern)?)
+            }
+        };
+
+        // the exact patterns only have to be compiled again if the query changes them
+        let exact_patterns = if self.case_insensitive || anchored || self.size_limit.is_some() {
+            let patterns = self
+                .exact_regexes
+                .iter()
+                .map(|regex| -> Result<_> {
+                    let pattern = match anchor {
+                        PathAnchor::None => regex.as_str().to_string(),
+                        _ => anchor.apply(parse_ast(regex.as_str())?).to_string(),
+                    };
+                    let mut builder = RegexBuilder::new(&pattern);
+                    builder.case_insensitive(self.case_insensitive);
+                    if let Some(limit) = self.size_limit {
+                        builder.size_limit(limit).dfa_size_limit(limit);
+                    }
+                    match builder.build() {
+                        Ok(regex) => Ok(regex),
+                        Err(regex::Error::CompiledTooBig(limit)) => Err(too_large(limit)),
+                        Err(e) => Err(e.into()),
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Cow::Owned(patterns)
+        } else {
+            self.exact_regexes
+        };
+
+        let pool = if self.threads > 1 {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(self.threads)
+                .build()
+                .map_err(io::Error::other)?;
+            Some(pool)
+        } else {
+            None
+        };
+
+        Ok(ReaderIter {
+            reader: self.reader,
+            found: Vec::new(),
+            found_without_package: Vec::new(),
+            pattern,
+            exact_patterns,
+            package_entry_pattern,
+            package_name_pattern: self.package_pattern.map(Cow::Borrowed),
+            exclude_pattern: self.exclude.map(Cow::Borrowed),
+            package_hashes: self.hashes,
+            package_output: self.output,
+            seen_packages: self.dedup_packages.then(HashSet::new),
+            accepted_package_end: None,
+            max_buffered: self.max_buffered,
+            resume_pos: None,
+            sorted: self.sorted,
+            remaining: self.limit,
+            path_prefix: self.path_prefix,
+            file_type: self.file_type,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            executable_only: self.executable_only,
+            cancel_token: self.cancel_token,
+            progress: Progress {
+                callback: self.on_progress,
+                info: ProgressInfo::default(),
+            },
+            pool,
+        })
+    }
+
+    /// Runs the query like `run`, but returns the matches as a `Stream`.
+    ///
+    /// The blocks of the database are decoded and searched on tokio's blocking thread pool,
+    /// so this does not block the executor. It must be called from within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn run_stream(self) -> Result<impl futures::Stream<Item = Result<Match>> + Send + 'static> {
+        use futures::StreamExt;
+
+        let iter = self.run()?.into_owned();
+        let batches = futures::stream::unfold(Some(iter), |iter| async move {
+            let mut iter = iter?;
+            let (iter, batch) = tokio::task::spawn_blocking(move || {
+                let batch = iter.next_batch();
+                (iter, batch)
+            })
+            .await
+            .expect("query task panicked");
+
+            match batch {
+                Ok(batch) if batch.is_empty() => None,
+                Ok(batch) => Some((batch.into_iter().map(Ok).collect::<Vec<_>>(), Some(iter))),
+                Err(e) => Some((vec![Err(e)], None)),
+            }
+        });
+        Ok(batches.flat_map(futures::stream::iter))
+    }
+
+    /// Runs the query like `run`, but also returns the index of the pattern that each entry
+    /// matched. This is useful for queries created with `Reader::query_multi`.
+    ///
+    /// If an entry matches multiple patterns, the index of the first one is returned.
+    pub fn run_tagged(self) -> Result<TaggedReaderIter<'a, 'b>> {
+        self.run().map(TaggedReaderIter)
+    }
+
+    /// Translates the syntax tree of a regex for file paths into a pattern for the raw entries
+    /// of the database, after anchoring it to the path with `anchor`.
+    fn grep_pattern(expr: Ast, anchor: PathAnchor) -> String {
+        // the start of the file name cannot be checked here: it would become a NUL byte like
+        // `^`, and a `^` at the start of the pattern would then need a second NUL byte. The grep
+        // pattern only has to find candidates, and the exact patterns check the `/` instead.
+        let anchor = match anchor {
+            PathAnchor::Basename => PathAnchor::End,
+            anchor => anchor,
+        };
+        let mut expr = anchor.apply(expr);
+        // each entry is of the form `METADATA\0PATH\n` and the grep matcher works on whole
+        // lines, so the anchors for the start and end of the path have to be rewritten. This
+        // includes the anchors that were just added.
+        {
+            let mut stack = vec![&mut expr];
+            while let Some(e) = stack.pop() {
+                match *e {
+                    // the NUL byte marks the start of the path, so `^` and `\A` become a NUL byte
+                    Ast::Assertion(Assertion {
+                        kind: AssertionKind::StartLine | AssertionKind::StartText,
+                        span,
+                    }) => {
+                        *e = Ast::Literal(Literal {
+                            span,
+                            c: '\0',
+                            kind: regex_syntax::ast::LiteralKind::Verbatim,
+                        })
+                    }
+                    // the path ends at the end of the line, which `$` already matches since the
+                    // matcher is in multi-line mode. `\z` would only match at the end of the block.
+                    Ast::Assertion(Assertion {
+                        kind: AssertionKind::EndText,
+                        span,
+                    }) => {
+                        *e = Ast::Assertion(Assertion {
+                            span,
+                            kind: AssertionKind::EndLine,
+                        })
+                    }
+                    // the path is surrounded by a NUL byte and a newline, which are no word
+                    // characters, so word boundaries match the same positions as in the path.
+                    Ast::Assertion(Assertion {
+                        kind:
+                            AssertionKind::EndLine
+                            | AssertionKind::WordBoundary
+                            | AssertionKind::NotWordBoundary,
+                        ..
+                    }) => {}
+                    Ast::Group(Group { ref mut ast, .. }) => stack.push(ast),
+                    Ast::Repetition(Repetition { ref mut ast, .. }) => stack.push(ast),
+                    Ast::Concat(Concat { ref mut asts, .. })
+                    | Ast::Alternation(Alternation { ref mut asts, .. }) => stack.extend(asts),
+                    _ => {}
+                }
+            }
+        }
+        expr.to_string()
+    }
+}
+
+/// Where the patterns of a query have to match in the path, see `Query::whole_basename` and
+/// `Query::anchor_end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PathAnchor {
+    /// Anywhere in the path.
+    None,
+    /// At the end of the path.
+    End,
+    /// At the end of the path and after a `/` or at the start of the path.
+    Basename,
+}
+
+impl PathAnchor {
+    /// Wraps the syntax tree of a regex for file paths so that it only matches at this anchor.
+    ///
+    /// The regex is put into a group, so that alternations in it are anchored as a whole. For
+    /// `Basename`, `(?:^|/)(?:expr)$` is returned.
+    fn apply(self, expr: Ast) -> Ast {
+        let span = *expr.span();
+        let group = |ast| {
+            Ast::Group(Group {
+                span,
+                kind: GroupKind::NonCapturing(Flags {
+                    span,
+                    items: Vec::new(),
+                }),
+                ast: Box::new(ast),
+            })
+        };
+        let mut asts = Vec::new();
+        match self {
+            PathAnchor::None => return expr,
+            PathAnchor::End => {}
+            PathAnchor::Basename => {
+                let start = Ast::Assertion(Assertion {
+                    span,
+                    kind: AssertionKind::StartLine,
+                });
+                let slash = Ast::Literal(Literal {
+                    span,
+                    c: '/',
+                    kind: regex_syntax::ast::LiteralKind::Verbatim,
+                });
+                asts.push(group(Ast::Alternation(Alternation {
+                    span,
+                    asts: vec![start, slash],
+                })));
+            }
+        }
+        asts.push(group(expr));
+        asts.push(Ast::Assertion(Assertion {
+            span,
+            kind: AssertionKind::EndLine,
+        }));
+        Ast::Concat(Concat { span, asts })
+    }
+}
+
+/// Returns a builder for grep matchers that search the raw entries of a database, with one
+/// entry per line.
+fn grep_matcher_builder() -> grep::regex::RegexMatcherBuilder {
+    let mut builder = grep::regex::RegexMatcherBuilder::new();
+    builder.line_terminator(Some(b'\n')).multi_line(true);
+    builder
+}
+
+/// Parses a regex into its syntax tree.
+fn parse_ast(pattern: &str) -> Result<Ast> {
+    regex_syntax::ast::parse::Parser::new()
+        .parse(pattern)
+        .map_err(|e| regex::Error::Syntax(e.to_string()).into())
+}
+
+/// A pattern for file paths that was parsed and compiled once, so that it can be used for
+/// many queries with `Reader::query_compiled`.
+///
+/// Besides the regex itself, this contains the grep matcher for the raw entries of the database
+/// that a query searches with, which would otherwise be derived from the regex by every query.
+#[derive(Debug, Clone)]
+pub struct CompiledPattern {
+    regex: Regex,
+    /// The bytes that the regex matches, if it only matches a single literal string.
+    literal: Option<Vec<u8>>,
+    /// The pattern for the raw entries, see `Query::grep_pattern`.
+    grep_pattern: String,
+    /// The grep matcher for `grep_pattern`.
+    matcher: grep::regex::RegexMatcher,
+}
+
+impl CompiledPattern {
+    /// Parses and compiles the given regex.
+    pub fn new(pattern: &str) -> Result<CompiledPattern> {
+        CompiledPattern::from_ast(&parse_ast(pattern)?)
+    }
+
+    /// Compiles a regex that was already parsed into a syntax tree, for example by a caller
+    /// that inspects or rewrites the pattern before searching for it.
+    pub fn from_ast(ast: &Ast) -> Result<CompiledPattern> {
+        let regex = Regex::new(&ast.to_string())?;
+        let grep_pattern = Query::grep_pattern(ast.clone(), PathAnchor::None);
+        let matcher = grep_matcher_builder().build(&grep_pattern)?;
+        Ok(CompiledPattern {
+            literal: literal_bytes(&regex),
+            regex,
+            grep_pattern,
+            matcher,
+        })
+    }
+
+    /// Returns the regex that file paths are matched against.
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+}
+
+/// A single entry found by a query, together with the package it belongs to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Match {
+    /// The store path (package) that contains the entry.
+    pub store_path: StorePath,
+    /// The matching file entry. Its path is relative to the root of the store path.
+    pub entry: FileTreeEntry,
+    /// The part of the package name that matched the package pattern of the query.
+    #[serde(skip)]
+    package_span: Option<Range<usize>>,
+    /// The part of the path that matched the pattern of the query.
+    #[serde(skip)]
+    path_span: Option<Range<usize>>,
+}
+
+impl Match {
+    /// Creates a new match for an entry of the given store path.
+    pub fn new(store_path: StorePath, entry: FileTreeEntry) -> Match {
+        Match {
+            store_path,
+            entry,
+            package_span: None,
+            path_span: None,
+        }
+    }
+
+    /// Returns the byte range of the package name (as returned by `StorePath::name`) that
+    /// matched the pattern set with `Query::package_pattern`.
+    ///
+    /// This can be used to highlight the matching part of the name. If the query had no
+    /// package pattern, this returns `None`.
+    pub fn package_span(&self) -> Option<Range<usize>> {
+        self.package_span.clone()
+    }
+
+    /// Returns the byte range of the path (as returned by `path`) that matched the pattern of
+    /// the query.
+    ///
+    /// This can be used to highlight the matching part of the path. The range is the leftmost
+    /// match of the pattern that the entry matched. With `Query::whole_basename`, it also
+    /// contains the `/` before the file name. Matches that were not created by a query return
+    /// `None`.
+    pub fn path_span(&self) -> Option<Range<usize>> {
+        self.path_span.clone()
+    }
+
+    /// Returns the origin of the store path that contains the entry.
+    pub fn origin(&self) -> &PathOrigin {
+        self.store_path.origin_ref()
+    }
+
+    /// Returns the name of the output of the derivation that produced the store path
+    /// containing the entry, such as `out`, `lib` or `man`.
+    pub fn output(&self) -> &str {
+        &self.origin().output
+    }
+
+    /// Returns the path of the entry, relative to the root of the store path.
+    ///
+    /// Paths are raw bytes, since they are not necessarily valid UTF-8. Use `path_lossy`
+    /// to display them.
+    pub fn path(&self) -> &[u8] {
+        &self.entry.path
+    }
+
+    /// Returns the path of the entry as a string, see `FileTreeEntry::path_lossy`.
+    pub fn path_lossy(&self) -> Cow<'_, str> {
+        self.entry.path_lossy()
+    }
+
+    /// Returns the absolute path of the entry in the nix store, see
+    /// `FileTreeEntry::absolute_path`.
+    pub fn absolute_path(&self) -> PathBuf {
+        self.entry.absolute_path(&self.store_path)
+    }
+
+    /// Splits the match into the store path and the file entry.
+    pub fn into_parts(self) -> (StorePath, FileTreeEntry) {
+        (self.store_path, self.entry)
+    }
+}
+
+impl From<Match> for (StorePath, FileTreeEntry) {
+    fn from(m: Match) -> (StorePath, FileTreeEntry) {
+        m.into_parts()
+    }
+}
+
+/// An iterator for entries in a database matching a given pattern.
+///
+/// The items are `Match`es. Use `Match::into_parts` (or `Into`) to get a `(StorePath, FileTreeEntry)`
+/// tuple instead.
+pub struct ReaderIter<'a, 'b> {
+    /// The underlying reader from which we read input.
+    reader: Reader,
+    /// Entries that matched the pattern but have not been returned by `next` yet, together
+    /// with the index of the pattern they matched.
+    found: Vec<(usize, Match)>,
+    /// Entries that matched the pattern but for which we don't know yet what package they belong to.
+    /// This may happen if the entry we matched was at the end of the search buffer, so that the entry
+    /// for the package did not fit into the buffer anymore (since the package is stored after the entries
+    /// of the package). In this case, we need to look for the package entry in the next iteration when
+    /// we read the next block of input.
+    ///
+    /// The second element is the part of the path that matched the pattern.
+    found_without_package: Vec<(usize, Range<usize>, FileTreeEntry)>,
+    /// The pattern for which to search package paths.
+    ///
+    /// This pattern should work on the raw bytes of file entries. In particular, the file path is not the
+    /// first data in a file entry, so the regex `^` anchor will not work correctly.
+    ///
+    /// The pattern here may produce false positives (for example, if it matches inside the metadata of a file
+    /// entry). This is not a problem, as matches are later checked against `exact_patterns`.
+    pattern: LineFinder,
+    /// The raw patterns, as supplied to the query. These are used to verify matches, since `pattern`
+    /// itself may produce false positives.
+    exact_patterns: Cow<'a, [Regex]>,
+    /// Pattern that matches only package entries.
+    package_entry_pattern: grep::regex::RegexMatcher,
+    /// Pattern that the package name should match.
+    package_name_pattern: Option<Cow<'b, Regex>>,
+    /// Entries whose path matches this pattern are skipped.
+    exclude_pattern: Option<Cow<'b, Regex>>,
+    /// Only search the packages with the given hashes.
+    package_hashes: Option<HashSet<String>>,
+    /// Only search packages of the given output.
+    package_output: Option<String>,
+    /// The hashes of the packages that matches were returned for, if duplicate packages
+    /// should be skipped.
+    seen_packages: Option<HashSet<String>>,
+    /// The end of the package (in the current block) whose matches are returned even though
+    /// its hash is in `seen_packages`, since that package is the one that added the hash.
+    accepted_package_end: Option<usize>,
+    /// Searching a block is paused once this many matches were found in it.
+    max_buffered: Option<usize>,
+    /// The position in the last decoded block at which searching was paused, if it was.
+    resume_pos: Option<usize>,
+    /// Whether the matches in `found` are sorted by store path and file path.
+    sorted: bool,
+    /// The number of entries that may still be returned, if the query has a limit.
+    remaining: Option<usize>,
+    /// Only return entries whose path is inside this directory (normalized to start with a `/`).
+    path_prefix: Option<Vec<u8>>,
+    /// Only return entries of this file type.
+    file_type: Option<FileType>,
+    /// Only return regular files that are at least this large.
+    min_size: Option<u64>,
+    /// Only return regular files that are at most this large.
+    max_size: Option<u64>,
+    /// Only return regular files with the executable bit set.
+    executable_only: bool,
+    /// If set, the query is stopped before decoding the next block.
+    cancel_token: Option<Arc<AtomicBool>>,
+    /// The progress of the query.
+    progress: Progress,
+    /// The thread pool on which blocks are searched, for parallel queries.
+    pool: Option<rayon::ThreadPool>,
+}
+
+/// Finds the lines of a block that may match the patterns of a query.
+enum LineFinder {
+    /// A regex for the raw entries, see `Query::grep_pattern`.
+    Regex(grep::regex::RegexMatcher),
+    /// A literal that has to occur in the line. This is used instead of a regex if the query
+    /// has a single pattern without any metacharacters, since `memmem` finds it faster.
+    Literal(memmem::Finder<'static>),
+}
+
+impl LineFinder {
+    /// Returns the next line of `buf` at or after `start` that may match.
+    fn next_line(&self, buf: &[u8], start: usize) -> Option<LineMatch> {
+        match *self {
+            LineFinder::Regex(ref matcher) => next_matching_line(matcher, buf, start),
+            LineFinder::Literal(ref finder) => {
+                let pos = start + finder.find(&buf[start..])?;
+                let line_start = memrchr(b'\n', &buf[..pos]).map_or(0, |x| x + 1);
+                let line_end = memchr(b'\n', &buf[pos..]).map_or(buf.len(), |x| x + pos + 1);
+                Some(LineMatch::new(line_start, line_end))
+            }
+        }
+    }
+}
+
+/// Returns the bytes matched by `regex` if it only matches a single non-empty literal string.
+fn literal_bytes(regex: &Regex) -> Option<Vec<u8>> {
+    let hir = regex_syntax::ParserBuilder::new()
+        .utf8(false)
+        .build()
+        .parse(regex.as_str())
+        .ok()?;
+    match hir.kind() {
+        HirKind::Literal(hir::Literal(bytes)) => Some(bytes.to_vec()),
+        _ => None,
+    }
+}
+
+/// Tests if `path` is the directory `prefix` or inside of it. A prefix of `/` contains all paths.
+fn is_in_subtree(path: &[u8], prefix: &[u8]) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix == b"/" || rest.is_empty() || rest[0] == b'/',
+        None => false,
+    }
+}
+
+/// The file type of regular files with the executable bit set.
+const EXECUTABLE: FileType = FileType::Regular { executable: true };
+
+/// Tests if `node` is a regular file whose size is within the given limits. If there are no
+/// limits, all nodes pass.
+fn size_in_range(node: &FileNode<()>, min_size: Option<u64>, max_size: Option<u64>) -> bool {
+    if min_size.is_none() && max_size.is_none() {
+        return true;
+    }
+    match *node {
+        FileNode::Regular { size, .. } => {
+            min_size.is_none_or(|min| size >= min) && max_size.is_none_or(|max| size <= max)
+        }
+        _ => false,
+    }
+}
+
+/// Decodes a file entry of a database with the given format version.
+fn decode_entry(version: u64, line: &[u8]) -> Result<FileTreeEntry> {
+    let entry = if version >= ESCAPED_PATHS_VERSION {
+        FileTreeEntry::decode(line)
+    } else {
+        FileTreeEntry::decode_legacy(line)
+    };
+    entry.ok_or_else(|| ErrorKind::EntryParse(line.to_vec()).into())
+}
+
+/// Returns the index of the first of the `patterns` that matches the given path, together with
+/// the part of the path that it matched.
+fn matching_pattern(patterns: &[Regex], path: &[u8]) -> Option<(usize, Range<usize>)> {
+    patterns
+        .iter()
+        .enumerate()
+        .find_map(|(index, r)| Some((index, r.find(path)?.range())))
+}
+
+fn consume_no_error<T>(e: NoError) -> T {
+    panic!("impossible: {}", e)
+}
+
+fn next_matching_line<M: Matcher<Error = NoError>>(
+    matcher: M,
+    buf: &[u8],
+    mut start: usize,
+) -> Option<LineMatch> {
+    while let Some(candidate) = matcher
+        .find_candidate_line(&buf[start..])
+        .unwrap_or_else(consume_no_error)
+    {
+        // the buffer may end with a newline character, so we may get a match
+        // for an empty "line" at the end of the buffer
+        // since this is not a line match, return None
+        if start == buf.len() {
+            return None;
+        };
+
+        let (pos, confirmed) = match candidate {
+            LineMatchKind::Confirmed(pos) => (start + pos, true),
+            LineMatchKind::Candidate(pos) => (start + pos, false),
+        };
+
+        let line_start = memrchr(b'\n', &buf[..pos]).map_or(0, |x| x + 1);
+        let line_end = memchr(b'\n', &buf[pos..]).map_or(buf.len(), |x| x + pos + 1);
+
+        if !confirmed
+            && !matcher
+                .is_match(&buf[line_start..line_end])
+                .unwrap_or_else(consume_no_error)
+        {
+            start = line_end;
+            continue;
+        }
+
+        return Some(LineMatch::new(line_start, line_end));
+    }
+    None
+}
+
+/// The conditions of a query, borrowed from a `ReaderIter`. Blocks are searched with these
+/// both by serial and by parallel queries, where they are shared by the worker threads.
+struct BlockSearch<'s> {
+    version: u64,
+    pattern: &'s LineFinder,
+    exact_patterns: &'s [Regex],
+    package_entry_pattern: &'s grep::regex::RegexMatcher,
+    package_name_pattern: Option<&'s Regex>,
+    exclude_pattern: Option<&'s Regex>,
+    package_hashes: Option<&'s HashSet<String>>,
+    package_output: Option<&'s str>,
+    path_prefix: Option<&'s [u8]>,
+    file_type: Option<FileType>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    executable_only: bool,
+}
+
+/// The matches that were found in a single block by `BlockSearch::search`.
+struct BlockMatches {
+    /// The first package entry of the block and its end, if the block has one. The matches
+    /// that previous blocks left without a package belong to it.
+    first_package: Option<(StorePath, usize)>,
+    /// The part of the name of the first package that matched the package pattern, or `None`
+    /// if the first package is not searched.
+    first_package_span: Option<Option<Range<usize>>>,
+    /// The matches whose package is in the block, together with the end of the package entry.
+    matches: Vec<(usize, Match, usize)>,
+    /// The matches after the last package entry of the block, whose package is in a later block.
+    trailing: Vec<(usize, Range<usize>, FileTreeEntry)>,
+    /// The position at which the search was paused because enough matches were found.
+    resume_pos: Option<usize>,
+}
+
+impl BlockSearch<'_> {
+    /// Finds the matches in `block` from position `start` on, applying all conditions of the
+    /// query except for removing duplicate packages, which depends on the previous blocks.
+    ///
+    /// If `max_matches` is set, the search is paused once that many matches with a package
+    /// were found.
+    fn search(
+        &self,
+        block: &[u8],
+        start: usize,
+        max_matches: Option<usize>,
+    ) -> Result<BlockMatches> {
+        let mut packages = PackageFinder::default();
+        let first_package = packages.find(self.package_entry_pattern, block, start)?;
+        let first_package_span = first_package
+            .as_ref()
+            .and_then(|(pkg, _)| self.search_package(pkg));
+
+        let mut matches = Vec::new();
+        let mut trailing = Vec::new();
+        let mut resume_pos = None;
+        let mut pos = start;
+        while let Some(mat) = self.pattern.next_line(block, pos) {
+            pos = mat.end();
+            let line = &block[mat.start()..mat.end() - 1];
+            if self
+                .package_entry_pattern
+                .is_match(line)
+                .unwrap_or_else(consume_no_error)
+            {
+                continue;
+            }
+
+            let package = packages.find(self.package_entry_pattern, block, mat.end())?;
+            let mut package_span = None;
+            if let Some((ref pkg, end)) = package {
+                match self.search_package(pkg) {
+                    Some(span) => package_span = span,
+                    None => {
+                        // all entries before end will have the same package
+                        pos = end;
+                        continue;
+                    }
+                }
+            }
+
+            let entry = decode_entry(self.version, line)?;
+            let Some((index, path_span)) = self.check_entry(&entry) else {
+                continue;
+            };
+            match package {
+                None => trailing.push((index, path_span, entry)),
+                Some((store_path, end)) => matches.push((
+                    index,
+                    Match {
+                        store_path,
+                        entry,
+                        package_span,
+                        path_span: Some(path_span),
+                    },
+                    end,
+                )),
+            }
+
+            if max_matches.is_some_and(|max| matches.len() >= max) {
+                resume_pos = Some(pos);
+                break;
+            }
+        }
+
+        Ok(BlockMatches {
+            first_package,
+            first_package_span,
+            matches,
+            trailing,
+            resume_pos,
+        })
+    }
+
+    /// Tests if a store path matches the package conditions. For packages that do, this
+    /// returns the part of the name that matched the package pattern.
+    fn search_package(&self, pkg: &StorePath) -> Option<Option<Range<usize>>> {
+        if self
+            .package_hashes
+            .is_some_and(|hashes| !hashes.contains(pkg.hash().as_ref()))
+            || self
+                .package_output
+                .is_some_and(|o| o != pkg.origin_ref().output)
+        {
+            return None;
+        }
+        match self.package_name_pattern {
+            Some(r) => r.find(pkg.name().as_bytes()).map(|m| Some(m.range())),
+            None => Some(None),
+        }
+    }
+
+    /// Returns the index of the first pattern that matches `entry` and the part of the path
+    /// that it matched, if the entry also passes the other conditions of the query.
+    fn check_entry(&self, entry: &FileTreeEntry) -> Option<(usize, Range<usize>)> {
+        let file_type = entry.node.get_type();
+        if self.file_type.is_some_and(|t| file_type != t)
+            || (self.executable_only && file_type != EXECUTABLE)
+            || !size_in_range(&entry.node, self.min_size, self.max_size)
+            || !self
+                .path_prefix
+                .is_none_or(|prefix| is_in_subtree(&entry.path, prefix))
+        {
+            return None;
+        }
+        let found = matching_pattern(self.exact_patterns, &entry.path)?;
+        if self
+            .exclude_pattern
+            .is_some_and(|p| p.is_match(&entry.path))
+        {
+            return None;
+        }
+        Some(found)
+    }
+}
+
+/// Finds the package entries that the entries of a block belong to.
+///
+/// The package entry comes after all file entries of a package, so this skips forward until
+/// a package entry is found. To be more efficient if there are many matches, the current
+/// package is cached.
+#[derive(Default)]
+struct PackageFinder {
+    /// The last package that was found. It is valid for all positions up to the second element
+    /// of the tuple (after that, a new package begins).
+    cached: Option<(StorePath, usize)>,
+    /// Whether there are no more package entries after the cached one.
+    exhausted: bool,
+}
+
+impl PackageFinder {
+    /// Returns the package of the entry that ends at `item_end` together with the end of the
+    /// package entry, or `None` if the package entry is not in `block`.
+    fn find(
+        &mut self,
+        package_entry_pattern: &grep::regex::RegexMatcher,
+        block: &[u8],
+        item_end: usize,
+    ) -> Result<Option<(StorePath, usize)>> {
+        if let Some((ref pkg, end)) = self.cached {
+            if item_end < end {
+                return Ok(Some((pkg.clone(), end)));
+            }
+        }
+        if self.exhausted {
+            return Ok(None);
+        }
+
+        let Some(mat) = next_matching_line(package_entry_pattern, block, item_end) else {
+            self.exhausted = true;
+            return Ok(None);
+        };
+        let json = &block[mat.start() + 2..mat.end() - 1];
+        let pkg: StorePath =
+            serde_json::from_slice(json).chain_err(|| ErrorKind::StorePathParse(json.to_vec()))?;
+        self.cached = Some((pkg.clone(), mat.end()));
+        Ok(Some((pkg, mat.end())))
+    }
+}
+
+impl<'a, 'b> ReaderIter<'a, 'b> {
+    /// Reads input until `self.found` contains at least one entry or the end of the input has been reached.
+    fn fill_buf(&mut self) -> Result<()> {
+        if !self.found.is_empty() {
+            return Ok(());
+        }
+        if self.pool.is_some() {
+            self.search_parallel()?;
+        } else {
+            self.search_blocks()?;
+        }
+        if self.sorted {
+            // matches are popped from the end, so sort them in reverse
+            self.found.sort_by(|(_, a), (_, b)| {
+                (&b.store_path, &b.entry.path).cmp(&(&a.store_path, &a.entry.path))
+            });
+        }
+        Ok(())
+    }
+
+    /// Searches the next blocks one after another until `self.found` contains at least one
+    /// entry or the end of the input has been reached.
+    fn search_blocks(&mut self) -> Result<()> {
+        // the input is processed in blocks until we've found at least a single entry
+        while self.found.is_empty() {
+            self.check_cancelled()?;
+            // continue searching the last block if that was paused, otherwise decode the next one
+            let pos = match self.resume_pos.take() {
+                Some(pos) => pos,
+                None => {
+                    let len = self.reader.decoder.decode()?.len();
+
+                    // if the block is empty, the end of input has been reached. all entries
+                    // are followed by the entry of their package, so if there are entries left
+                    // whose package we have not found, the database is corrupt.
+                    if len == 0 {
+                        if !self.found_without_package.is_empty() {
+                            self.found_without_package.clear();
+                            return Err(ErrorKind::MissingPackageEntry.into());
+                        }
+                        return Ok(());
+                    }
+                    self.progress.block_decoded(len);
+                    0
+                }
+            };
+
+            let block = self.reader.decoder.last_block();
+            let matches = self.block_search().search(block, pos, self.max_buffered)?;
+            self.resume_pos = matches.resume_pos;
+            let found = self.add_block_matches(matches, pos > 0);
+            self.found.extend(found);
+        }
+        Ok(())
+    }
+
+    /// Like `search_blocks`, but decodes as many blocks as the thread pool has threads and
+    /// searches them at once.
+    ///
+    /// Each worker finds the matches of a single block together with their packages (see
+    /// `BlockSearch::search`). Only the matches whose package is in a later block and the
+    /// removal of duplicate packages depend on the previous blocks, so they are handled when
+    /// the results are merged in the order of the blocks.
+    fn search_parallel(&mut self) -> Result<()> {
+        while self.found.is_empty() {
+            self.check_cancelled()?;
+            let mut blocks = Vec::new();
+            let threads = self.pool.as_ref().map_or(1, |p| p.current_num_threads());
+            while blocks.len() < threads {
+                let block = self.reader.decoder.decode()?;
+                if block.is_empty() {
+                    break;
+                }
+                self.progress.block_decoded(block.len());
+                blocks.push(block.to_vec());
+            }
+
+            // the end of input has been reached, see `search_blocks`
+            if blocks.is_empty() {
+                if !self.found_without_package.is_empty() {
+                    self.found_without_package.clear();
+                    return Err(ErrorKind::MissingPackageEntry.into());
+                }
+                return Ok(());
+            }
+
+            let search = self.block_search();
+            let pool = self
+                .pool
+                .as_ref()
+                .expect("only called for parallel queries");
+            let results = pool.install(|| {
+                blocks
+                    .par_iter()
+                    .map(|block| search.search(block, 0, None))
+                    .collect::<Vec<_>>()
+            });
+
+            let mut batch = Vec::with_capacity(results.len());
+            for result in results {
+                batch.push(self.add_block_matches(result?, false));
+            }
+
+            // matches are popped from the end, so the matches of the first block go last
+            for found in batch.into_iter().rev() {
+                self.found.extend(found);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the conditions of the query for searching a block with `BlockSearch::search`.
+    fn block_search(&self) -> BlockSearch<'_> {
+        BlockSearch {
+            version: self.reader.version,
+            pattern: &self.pattern,
+            exact_patterns: &self.exact_patterns,
+            package_entry_pattern: &self.package_entry_pattern,
+            package_name_pattern: self.package_name_pattern.as_deref(),
+            exclude_pattern: self.exclude_pattern.as_deref(),
+            package_hashes: self.package_hashes.as_ref(),
+            package_output: self.package_output.as_deref(),
+            path_prefix: self.path_prefix.as_deref(),
+            file_type: self.file_type,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            executable_only: self.executable_only,
+        }
+    }
+
+    /// Returns the matches of a block searched by `BlockSearch::search` that should be
+    /// returned, after assigning the matches left without a package by the previous block and
+    /// removing duplicate packages.
+    ///
+    /// If the search of the block was `resumed`, its first package was already handled.
+    fn add_block_matches(&mut self, block: BlockMatches, resumed: bool) -> Vec<(usize, Match)> {
+        let mut found = Vec::new();
+        if !resumed {
+            self.accepted_package_end = None;
+            if !self.found_without_package.is_empty() {
+                if let Some((pkg, end)) = block.first_package {
+                    match block.first_package_span {
+                        Some(package_span) if !self.is_duplicate(&pkg, end) => {
+                            for (index, path_span, entry) in self.found_without_package.drain(..) {
+                                let store_path = pkg.clone();
+                                let package_span = package_span.clone();
+                                found.push((
+                                    index,
+                                    Match {
+                                        store_path,
+                                        entry,
+                                        package_span,
+                                        path_span: Some(path_span),
+                                    },
+                                ));
+                            }
+                        }
+                        _ => self.found_without_package.clear(),
+                    }
+                }
+            }
+        }
+
+        for (index, m, end) in block.matches {
+            if !self.is_duplicate(&m.store_path, end) {
+                found.push((index, m));
+            }
+        }
+        self.found_without_package.extend(block.trailing);
+        found
+    }
+
+    /// Tests if a package with the same store path as `pkg` was already returned. `end`
+    /// identifies the package in the current block, since the same package is checked for each
+    /// of its matches.
+    fn is_duplicate(&mut self, pkg: &StorePath, end: usize) -> bool {
+        let Some(seen) = self.seen_packages.as_mut() else {
+            return false;
+        };
+        if self.accepted_package_end == Some(end) {
+            return false;
+        }
+        if !seen.insert(pkg.hash().into_owned()) {
+            return true;
+        }
+        self.accepted_package_end = Some(end);
+        false
+    }
+
+    /// Returns an error if the query was cancelled through its cancel token.
+    fn check_cancelled(&self) -> Result<()> {
+        match self.cancel_token {
+            Some(ref token) if token.load(Ordering::Relaxed) => Err(ErrorKind::Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns whether the package of a match needs to be known to decide if the match
+    /// should be returned.
+    fn needs_package(&self) -> bool {
+        self.package_name_pattern.is_some()
+            || self.package_hashes.is_some()
+            || self.package_output.is_some()
+            || self.seen_packages.is_some()
+    }
+
+    /// Counts the remaining matches without looking up the package that each match belongs to.
+    ///
+    /// This must only be used if `needs_package` returns false.
+    fn count_without_package(&mut self) -> Result<usize> {
+        let mut count = 0;
+        while self.remaining.is_none_or(|remaining| count < remaining) {
+            self.check_cancelled()?;
+            let block = self.reader.decoder.decode()?;
+            if block.is_empty() {
+                break;
+            }
+            self.progress.block_decoded(block.len());
+
+            let mut pos = 0;
+            while let Some(mat) = self.pattern.next_line(block, pos) {
+                pos = mat.end();
+                let entry = &block[mat.start()..mat.end() - 1];
+                if self
+                    .package_entry_pattern
+                    .is_match(entry)
+                    .unwrap_or_else(consume_no_error)
+                {
+                    continue;
+                }
+
+                // only the path and the file type are needed to check the conditions, so we
+                // can avoid decoding the full entry
+                let sep = memchr(b'\0', entry)
+                    .ok_or_else(|| Error::from(ErrorKind::EntryParse(entry.to_vec())))?;
+                let (meta, path) = (&entry[..sep], &entry[sep + 1..]);
+                let path = if self.reader.version >= ESCAPED_PATHS_VERSION {
+                    unescape_path(path)
+                        .ok_or_else(|| Error::from(ErrorKind::EntryParse(entry.to_vec())))?
+                } else {
+                    Cow::Borrowed(path)
+                };
+                if self.file_type.is_some() || self.executable_only {
+                    let found = FileNode::decode_type(meta)
+                        .ok_or_else(|| Error::from(ErrorKind::EntryParse(entry.to_vec())))?;
+                    if self.file_type.is_some_and(|t| found != t)
+                        || (self.executable_only && found != EXECUTABLE)
+                    {
+                        continue;
+                    }
+                }
+
+                if self.min_size.is_some() || self.max_size.is_some() {
+                    let node = if self.reader.version >= ESCAPED_PATHS_VERSION {
+                        FileNode::decode(meta)
+                    } else {
+                        FileNode::decode_legacy(meta)
+                    };
+                    let node =
+                        node.ok_or_else(|| Error::from(ErrorKind::EntryParse(entry.to_vec())))?;
+                    if !size_in_range(&node, self.min_size, self.max_size) {
+                        continue;
+                    }
+                }
+
+                let in_subtree = self
+                    .path_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| is_in_subtree(&path, prefix));
+                if in_subtree
+                    && matching_pattern(&self.exact_patterns, &path).is_some()
+                    && !self
+                        .exclude_pattern
+                        .as_ref()
+                        .is_some_and(|p| p.is_match(&path))
+                {
+                    count += 1;
+                }
+            }
+        }
+        Ok(self
+            .remaining
+            .map_or(count, |remaining| count.min(remaining)))
+    }
+
+    /// Writes the remaining matches to `out` as newline-delimited JSON, in the same format as
+    /// `Reader::dump_json`.
+    ///
+    /// Matches are written as soon as they are found, and `out` is flushed after the matches
+    /// of each block of the database, so a consumer can process them while the query runs.
+    pub fn write_ndjson<W: Write>(mut self, mut out: W) -> Result<()> {
+        while let Some((_, m)) = self.next_match()? {
+            serde_json::to_writer(&mut out, &JsonEntry::new(&m.store_path, &m.entry))
+                .map_err(io::Error::from)?;
+            out.write_all(b"\n")?;
+            if self.found.is_empty() {
+                out.flush()?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Groups the matches by package, yielding each package together with all of its matching
+    /// entries.
+    ///
+    /// The entries of a package are stored next to each other, so this does not need to
+    /// collect all matches first.
+    pub fn grouped(self) -> GroupedReaderIter<'a, 'b> {
+        GroupedReaderIter {
+            iter: self,
+            pending: None,
+        }
+    }
+
+    /// Returns all matches that were found in the next block that contains any, or an empty
+    /// vector if there are no more matches.
+    #[cfg(feature = "tokio")]
+    fn next_batch(&mut self) -> Result<Vec<Match>> {
+        let mut batch = Vec::new();
+        while let Some((_, m)) = self.next_match()? {
+            batch.push(m);
+            if self.found.is_empty() {
+                break;
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Converts the iterator into one that no longer borrows the patterns of the query.
+    pub(crate) fn into_owned(self) -> ReaderIter<'static, 'static> {
+        ReaderIter {
+            reader: self.reader,
+            found: self.found,
+            found_without_package: self.found_without_package,
+            pattern: self.pattern,
+            exact_patterns: Cow::Owned(self.exact_patterns.into_owned()),
+            package_entry_pattern: self.package_entry_pattern,
+            package_name_pattern: self
+                .package_name_pattern
+                .map(|p| Cow::Owned(p.into_owned())),
+            exclude_pattern: self.exclude_pattern.map(|p| Cow::Owned(p.into_owned())),
+            package_hashes: self.package_hashes,
+            package_output: self.package_output,
+            seen_packages: self.seen_packages,
+            accepted_package_end: self.accepted_package_end,
+            max_buffered: self.max_buffered,
+            resume_pos: self.resume_pos,
+            sorted: self.sorted,
+            remaining: self.remaining,
+            path_prefix: self.path_prefix,
+            file_type: self.file_type,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            executable_only: self.executable_only,
+            cancel_token: self.cancel_token,
+            progress: self.progress,
+            pool: self.pool,
+        }
+    }
+
+    /// Returns the next match in the database, with the index of the pattern it matched.
+    fn next_match(&mut self) -> Result<Option<(usize, Match)>> {
+        if self.remaining == Some(0) {
+            return Ok(None);
+        }
+        self.fill_buf()?;
+        let found = self.found.pop();
+        if let (Some(remaining), Some(_)) = (self.remaining.as_mut(), found.as_ref()) {
+            *remaining -= 1;
+        }
+        Ok(found)
+    }
+}
+
+impl<'a, 'b> Iterator for ReaderIter<'a, 'b> {
+    type Item = Result<Match>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_match() {
+            Err(e) => Some(Err(e)),
+            Ok(v) => v.map(|(_, m)| Ok(m)),
+        }
+    }
+}
+
+/// An iterator over the matches of a query grouped by package. Created by `ReaderIter::grouped`.
+pub struct GroupedReaderIter<'a, 'b> {
+    iter: ReaderIter<'a, 'b>,
+    /// The first match of the next package, which was already read while collecting the
+    /// matches of the previous package.
+    pending: Option<Match>,
+}
+
+impl<'a, 'b> GroupedReaderIter<'a, 'b> {
+    fn next_group(&mut self) -> Result<Option<(StorePath, Vec<FileTreeEntry>)>> {
+        let first = match self.pending.take() {
+            Some(m) => m,
+            None => match self.iter.next_match()? {
+                Some((_, m)) => m,
+                None => return Ok(None),
+            },
+        };
+
+        let (store_path, entry) = first.into_parts();
+        let mut entries = vec![entry];
+        while let Some((_, m)) = self.iter.next_match()? {
+            if m.store_path != store_path {
+                self.pending = Some(m);
+                break;
+            }
+            entries.push(m.entry);
+        }
+        Ok(Some((store_path, entries)))
+    }
+}
+
+impl<'a, 'b> Iterator for GroupedReaderIter<'a, 'b> {
+    type Item = Result<(StorePath, Vec<FileTreeEntry>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_group().transpose()
+    }
+}
+
+/// An iterator over the matches of a query, together with the index of the pattern that each
+/// match satisfied. Created by `Query::run_tagged`.
+pub struct TaggedReaderIter<'a, 'b>(ReaderIter<'a, 'b>);
+
+impl<'a, 'b> Iterator for TaggedReaderIter<'a, 'b> {
+    type Item = Result<(usize, Match)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_match().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the store path of the package `name`, whose hash consists of the character `hash`.
+    fn store_path(hash: char, name: &str) -> StorePath {
+        let origin = PathOrigin {
+            attr: name.to_string(),
+            output: "out".to_string(),
+            toplevel: true,
+            system: None,
+        };
+        let path = format!("/nix/store/{}-{}", hash.to_string().repeat(32), name);
+        StorePath::parse(origin, &path).expect("valid store path")
+    }
+
+    /// Returns a directory with the given entries.
+    fn directory<const N: usize>(entries: [(&str, FileTree); N]) -> FileTree {
+        let entries = entries
+            .into_iter()
+            .map(|(name, tree)| (serde_bytes::ByteBuf::from(name.as_bytes().to_vec()), tree));
+        FileTree::directory(entries.collect())
+    }
+
+    /// Encodes a package like `Writer::add`: its entries that match `filter_prefix`, followed
+    /// by the package entry.
+    fn encode_package<W: Write>(
+        out: W,
+        store_path: &StorePath,
+        tree: &FileTree,
+        filter_prefix: &[u8],
+    ) -> Result<()> {
+        let json = serde_json::to_vec(store_path).map_err(io::Error::from)?;
+        let mut encoder = frcode::Encoder::new(out, b"p".to_vec(), json);
+        for entry in tree.to_list(filter_prefix) {
+            entry.encode(&mut encoder)?;
+        }
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Encodes a package that contains regular files with the given paths, without the
+    /// directories that contain them.
+    fn encode_files<W: Write>(out: W, store_path: &StorePath, paths: &[String]) -> Result<()> {
+        let json = serde_json::to_vec(store_path).map_err(io::Error::from)?;
+        let mut encoder = frcode::Encoder::new(out, b"p".to_vec(), json);
+        for path in paths {
+            encoder.write_meta(b"1r")?;
+            encoder.write_path(path.as_bytes().to_vec())?;
+        }
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Returns a database in format version 1, whose header only consists of the version,
+    /// with the given encoded entries.
+    fn legacy_database(encoded: &[u8]) -> Result<Vec<u8>> {
+        let mut data = FILE_MAGIC.to_vec();
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&zstd::encode_all(encoded, 1)?);
+        Ok(data)
+    }
+
+    /// Returns a database whose encoded entries were written with a `frcode::BlockWriter`.
+    fn block_database(encoded: &[u8]) -> Result<Vec<u8>> {
+        let mut data = FILE_MAGIC.to_vec();
+        data.extend_from_slice(&CHECKSUMS_VERSION.to_le_bytes());
+        data.push(Codec::Zstd.tag());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&32u32.to_le_bytes());
+        data.extend_from_slice(&zstd::encode_all(encoded, 1)?);
+        Ok(data)
+    }
+
+    /// A path for a database in the temp directory, which is removed when this is dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> TempPath {
+            let name = format!("nix-index-{}-test-{}", name, std::process::id());
+            TempPath(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl AsRef<Path> for TempPath {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_next_matching_line_package() {
+        let matcher = grep::regex::RegexMatcherBuilder::new()
+            .line_terminator(Some(b'\n'))
+            .multi_line(true)
+            .build("^p")
+            .expect("valid regex");
+        let buffer = br#"
+SOME LINE
+pDATA
+ANOTHER LINE
+        "#;
+
+        let mat = next_matching_line(matcher, buffer, 0);
+        assert_eq!(mat, Some(LineMatch::new(11, 17)));
+    }
+
+    #[test]
+    fn test_is_in_subtree() {
+        assert!(is_in_subtree(b"/bin", b"/bin"));
+        assert!(is_in_subtree(b"/bin/foo", b"/bin"));
+        assert!(!is_in_subtree(b"/binfoo", b"/bin"));
+        assert!(!is_in_subtree(b"/share/man", b"/bin"));
+        assert!(is_in_subtree(b"/share/man", b"/"));
+    }
+
+    #[test]
+    fn test_literal_bytes() -> Result<()> {
+        assert_eq!(
+            literal_bytes(&Regex::new("bin/ls")?),
+            Some(b"bin/ls".to_vec())
+        );
+        assert_eq!(
+            literal_bytes(&Regex::new(r"libc\+\+\.so")?),
+            Some(b"libc++.so".to_vec())
+        );
+        assert_eq!(literal_bytes(&Regex::new("^/bin")?), None);
+        assert_eq!(literal_bytes(&Regex::new("lib.*")?), None);
+        assert_eq!(literal_bytes(&Regex::new("(?i)ls")?), None);
+        assert_eq!(literal_bytes(&Regex::new("")?), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grep_pattern_assertions() -> Result<()> {
+        let paths = [
+            &b"/foo"[..],
+            b"/bin/foo",
+            b"/foobar",
+            b"/barfoo",
+            b"/foo/bar",
+        ];
+        for pattern in ["foo$", r"\bfoo", "^/foo$", r"\A/foo\z", r"foo\B"] {
+            let compiled = CompiledPattern::new(pattern)?;
+            let exact = compiled.regex();
+            for path in paths {
+                // the grep pattern must find every entry matching the exact pattern
+                let mut line = b"1r\0".to_vec();
+                line.extend_from_slice(path);
+                line.push(b'\n');
+                let found = next_matching_line(&compiled.matcher, &line, 0).is_some();
+                assert_eq!(found, exact.is_match(path), "{} on {:?}", pattern, path);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_anchors_wrap_the_whole_pattern() -> Result<()> {
+        let anchored = |pattern: &str, anchor: PathAnchor| -> Result<String> {
+            Ok(anchor.apply(parse_ast(pattern)?).to_string())
+        };
+        assert_eq!(anchored("a|b", PathAnchor::None)?, "a|b");
+        assert_eq!(anchored("a|b", PathAnchor::End)?, "(?:a|b)$");
+        assert_eq!(anchored("a|b", PathAnchor::Basename)?, "(?:^|/)(?:a|b)$");
+        // the start of the path is a NUL byte in the raw entries, and the start of the file
+        // name is only checked by the exact pattern
+        assert_eq!(
+            Query::grep_pattern(parse_ast("^/bin/ls|sh")?, PathAnchor::Basename),
+            "(?:\0/bin/ls|sh)$"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_whole_basename_only_matches_file_names() -> Result<()> {
+        let bin = directory([
+            ("ls", FileTree::regular(10, true)),
+            ("false", FileTree::regular(10, true)),
+            ("lsblk", FileTree::regular(10, true)),
+        ]);
+        let share = directory([("tools", FileTree::regular(10, false))]);
+        let tree = directory([("bin", bin), ("share", share)]);
+        let mut encoded = Vec::new();
+        encode_package(&mut encoded, &store_path('0', "coreutils"), &tree, b"")?;
+        let data = legacy_database(&encoded)?;
+
+        let found = |pattern: &str, case_insensitive| -> Result<Vec<Vec<u8>>> {
+            let mut paths = Reader::from_reader(io::Cursor::new(data.clone()))?
+                .query(&Regex::new(pattern)?)
+                .whole_basename(true)
+                .case_insensitive(case_insensitive)
+                .run()?
+                .map(|m| m.map(|m| m.entry.path))
+                .collect::<Result<Vec<_>>>()?;
+            paths.sort();
+            Ok(paths)
+        };
+        assert_eq!(found("ls", false)?, [b"/bin/ls".to_vec()]);
+        assert_eq!(found("LS", true)?, [b"/bin/ls".to_vec()]);
+        assert_eq!(found("bin/ls", false)?, [b"/bin/ls".to_vec()]);
+        assert_eq!(found("^/bin/ls", false)?, [b"/bin/ls".to_vec()]);
+        // both branches of an alternation have to match the whole file name, so `s` does not
+        // match `/share`
+        assert_eq!(
+            found("s|ls|tool[s]", false)?,
+            [b"/bin/ls".to_vec(), b"/share/tools".to_vec()]
+        );
+        assert!(found("l.", false)?.iter().all(|path| path == b"/bin/ls"));
+        assert!(found("tool", false)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_reader_rejects_invalid_magic() {
+        let data = io::Cursor::new(b"NOPE\x01\x00\x00\x00\x00\x00\x00\x00".to_vec());
+        match Reader::from_reader(data) {
+            Err(Error(ErrorKind::UnsupportedFileType(found), _)) => assert_eq!(found, b"NOPE"),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("invalid database was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let matches = |glob, anchor_end, path: &str| {
+            glob_to_regex(glob, anchor_end).is_match(path.as_bytes())
+        };
+
+        assert!(matches("*.so", true, "/lib/libssl.so"));
+        assert!(!matches("*.so", true, "/lib/libssl.so.3"));
+        assert!(!matches("*.so", true, "/lib/libsslxso"));
+        assert!(matches("bin/py*", true, "/usr/bin/python3"));
+        assert!(!matches("/bin/py*", true, "/usr/bin/python3"));
+        assert!(matches("/bin/py?hon", true, "/bin/python"));
+        assert!(matches("py", false, "/bin/python"));
+        assert!(!matches("py", false, "/lib/python3/site.py"));
+        assert!(matches("lib/**.py", true, "/lib/python3/site.py"));
+        assert!(matches("libc[+-]*", true, "/lib/libc++.so"));
+        assert!(matches("[!a]b", true, "/xb"));
+        assert!(!matches("[!a]b", true, "/ab"));
+        assert!(matches("[]]", true, "/]"));
+        assert!(matches("a[b", true, "/a[b"));
+    }
+
+    #[test]
+    fn test_database_without_footer_after_other_data() -> Result<()> {
+        let store_path = store_path('0', "zsh");
+        let mut encoded = Vec::new();
+        encode_files(&mut encoded, &store_path, &["/bin/zsh".to_string()])?;
+        // the database is embedded after other data and has neither an index nor a footer
+        let mut data = b"some other data".to_vec();
+        let base = data.len() as u64;
+        data.extend_from_slice(&legacy_database(&encoded)?);
+        let mut source = io::Cursor::new(data);
+        source.set_position(base);
+
+        let mut reader = Reader::from_reader(source)?;
+        let found = reader.lookup_exact(b"/bin/zsh")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].store_path, store_path);
+        assert_eq!(reader.reopen()?.query(&Regex::new("zsh")?).count()?, 1);
+        assert_eq!(reader.query(&Regex::new("zsh")?).count()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_reader_rejects_unknown_codec() {
+        let mut data = FILE_MAGIC.to_vec();
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&DEFAULT_APPLICATION_TAG);
+        data.push(0xff);
+        match Reader::from_reader(io::Cursor::new(data)) {
+            Err(Error(ErrorKind::UnsupportedCodec(tag), _)) => assert_eq!(tag, 0xff),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("database with unknown codec was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_entry_with_nul_and_newline_round_trips() -> Result<()> {
+        let path = b"/share/a\0b\nc\x01d".to_vec();
+        let entry = FileTreeEntry {
+            path: path.clone(),
+            node: FileNode::Symlink {
+                target: serde_bytes::ByteBuf::from(b"x\0y".to_vec()),
+            },
+        };
+
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = frcode::Encoder::new(&mut encoded, b"p".to_vec(), b"{}".to_vec());
+            entry.encode(&mut encoder)?;
+            encoder.finish()?;
+        }
+
+        let mut decoder = frcode::Decoder::new(io::Cursor::new(encoded));
+        let block = decoder.decode()?;
+        let line = block.split(|c| *c == b'\n').next().unwrap_or_default();
+        let decoded = decode_entry(FORMAT_VERSION, line)?;
+        assert_eq!(decoded.path, path);
+        match decoded.node {
+            FileNode::Symlink { target } => assert_eq!(target.as_slice(), b"x\0y"),
+            node => panic!("unexpected node: {:?}", node),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_without_package_at_end_is_an_error() -> Result<()> {
+        let mut encoded = Vec::new();
+        encode_files(
+            &mut encoded,
+            &store_path('0', "foo"),
+            &["/bin/foo".to_string()],
+        )?;
+        // the last entry of the stream is a file entry, without a package entry after it
+        frcode::Encoder::new(&mut encoded, b"1r".to_vec(), b"/bin/foo-orphan".to_vec()).finish()?;
+        let data = legacy_database(&encoded)?;
+
+        let pattern = Regex::new("foo")?;
+        let results = Reader::from_reader(io::Cursor::new(data))?
+            .query(&pattern)
+            .run()?
+            .collect::<Vec<_>>();
+        match results.as_slice() {
+            [Ok(m), Err(Error(ErrorKind::MissingPackageEntry, _))] => {
+                assert_eq!(m.entry.path, b"/bin/foo")
+            }
+            results => panic!("unexpected results: {:?}", results),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_directories_are_found_by_file_type() -> Result<()> {
+        let openssl = directory([("ssl.h", FileTree::regular(10, false))]);
+        let tree = directory([("include", directory([("openssl", openssl)]))]);
+        let mut encoded = Vec::new();
+        encode_package(
+            &mut encoded,
+            &store_path('0', "openssl"),
+            &tree,
+            b"/include/openssl/",
+        )?;
+        let data = legacy_database(&encoded)?;
+
+        let pattern = Regex::new("include/openssl")?;
+        let found = |file_type| -> Result<usize> {
+            Reader::from_reader(io::Cursor::new(data.clone()))?
+                .query(&pattern)
+                .file_type(Some(file_type))
+                .count()
+        };
+        assert_eq!(found(FileType::Directory)?, 1);
+        assert_eq!(found(FileType::Regular { executable: false })?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopened_reader_starts_at_first_entry() -> Result<()> {
+        let tree = directory([("ssl.h", FileTree::regular(10, false))]);
+        let mut encoded = Vec::new();
+        encode_package(&mut encoded, &store_path('0', "openssl"), &tree, b"")?;
+        let data = legacy_database(&encoded)?;
+
+        let reader = Reader::from_reader(io::Cursor::new(data))?;
+        let pattern = Regex::new("ssl")?;
+        let again = reader.reopen()?;
+        assert_eq!(reader.query(&pattern).count()?, 1);
+        assert_eq!(again.reopen()?.query(&pattern).count()?, 1);
+        assert_eq!(again.query(&pattern).count()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_hash_covers_all_compressed_entries() -> Result<()> {
+        let package = |hash, name| {
+            let tree = directory([(name, FileTree::regular(10, true))]);
+            (store_path(hash, name), tree)
+        };
+        let path = TempPath::new("content-hash");
+        let expected_hash = || -> Result<[u8; 32]> {
+            let data = fs::read(&path)?;
+            let reader = Reader::from_reader(io::Cursor::new(data.clone()))?;
+            let end = data_end(reader.index_offset, reader.footer_offset).unwrap_or_default();
+            Ok(Sha256::digest(&data[HEADER_LEN as usize..end as usize]).into())
+        };
+
+        let mut writer = WriterBuilder::new().exact_index(true).build(&path)?;
+        let (foo, tree) = package('0', "foo");
+        writer.add(foo, tree, b"")?;
+        writer.checkpoint()?;
+        let checkpointed = Reader::open(&path)?.content_hash()?;
+        assert_eq!(checkpointed, expected_hash()?);
+        let (bar, tree) = package('1', "bar");
+        writer.add(bar, tree, b"")?;
+        writer.finish()?;
+        let finished = Reader::open(&path)?.content_hash()?;
+        assert_eq!(finished, expected_hash()?);
+        assert_ne!(finished, checkpointed);
+
+        let mut writer = Writer::append(&path, 1)?;
+        let (baz, tree) = package('2', "baz");
+        writer.add(baz, tree, b"")?;
+        writer.finish()?;
+        let appended = Reader::open(&path)?.content_hash()?;
+        assert_eq!(appended, expected_hash()?);
+        assert_ne!(appended, finished);
+        Ok(())
+    }
+
+    #[test]
+    fn test_footer_round_trips_content_hash() -> Result<()> {
+        let footer = Footer {
+            stream_len: 10,
+            block_offsets: vec![0, 4],
+            counts: Some((1, 2)),
+            content_hash: Some(Sha256::digest(b"abc").into()),
+        };
+        let mut data = Vec::new();
+        footer.write(&mut data)?;
+
+        let read = Footer::read(&data[..], FORMAT_VERSION)?;
+        assert_eq!(read.content_hash, footer.content_hash);
+        assert_eq!(read.counts, Some((1, 2)));
+        let hash = read.content_hash.unwrap_or_default();
+        assert_eq!(&hash[..4], &[0xba, 0x78, 0x16, 0xbf]);
+        // older footers end after the counts
+        let old = Footer::read(&data[..data.len() - 32], COUNTS_VERSION)?;
+        assert_eq!(old.content_hash, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_anchor_end_only_matches_at_end_of_path() -> Result<()> {
+        let lib = directory(
+            ["libssl.so", "libssl.so.3", "libssl.so.conf"]
+                .map(|name| (name, FileTree::regular(10, false))),
+        );
+        let tree = directory([("lib", lib)]);
+        let mut encoded = Vec::new();
+        encode_package(&mut encoded, &store_path('0', "openssl"), &tree, b"")?;
+        let data = legacy_database(&encoded)?;
+
+        let found = |pattern: &str, anchor_end| -> Result<usize> {
+            Reader::from_reader(io::Cursor::new(data.clone()))?
+                .query(&Regex::new(pattern)?)
+                .anchor_end(anchor_end)
+                .count()
+        };
+        // a literal pattern and one that needs a regex
+        for pattern in [r"libssl\.so", "ssl.s[o]"] {
+            assert_eq!(found(pattern, false)?, 3, "{}", pattern);
+            assert_eq!(found(pattern, true)?, 1, "{}", pattern);
+        }
+        assert_eq!(found("[.]so|3", true)?, 2);
+
+        let m = Reader::from_reader(io::Cursor::new(data.clone()))?
+            .query(&Regex::new("ssl.s[o]")?)
+            .anchor_end(true)
+            .run()?
+            .next()
+            .expect("one entry matches")?;
+        assert_eq!(m.path(), b"/lib/libssl.so");
+        assert_eq!(m.path_span(), Some(8..14));
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_insensitive_query_finds_mixed_case_paths_in_all_blocks() -> Result<()> {
+        let path = TempPath::new("case-insensitive");
+        // small blocks, so that each package ends up in a block of its own
+        let mut writer = WriterBuilder::new().block_size(64).build(&path)?;
+        for (hash, name) in [('0', "foo"), ('1', "bar"), ('2', "baz")] {
+            let tree = directory([
+                ("ReadMe.TXT", FileTree::regular(10, false)),
+                ("Tool\nName", FileTree::regular(10, true)),
+                ("ÄRGER", FileTree::regular(10, false)),
+            ]);
+            writer.add(store_path(hash, name), tree, b"")?;
+        }
+        writer.finish()?;
+        assert!(Reader::open(&path)?.block_count()? > 1);
+
+        let found = |pattern: &str, case_insensitive| -> Result<Vec<Vec<u8>>> {
+            let mut paths = Reader::open(&path)?
+                .query(&Regex::new(pattern)?)
+                .case_insensitive(case_insensitive)
+                .run()?
+                .map(|m| m.map(|m| m.entry.path))
+                .collect::<Result<Vec<_>>>()?;
+            paths.sort();
+            Ok(paths)
+        };
+        // a literal pattern, one that needs a regex and one for a path that is escaped in the
+        // database
+        for pattern in [r"readme\.txt", "READ[M]E", "nAME$", "ärger"] {
+            assert!(found(pattern, false)?.is_empty(), "{}", pattern);
+            assert_eq!(found(pattern, true)?.len(), 3, "{}", pattern);
+        }
+        assert_eq!(found("^/tool", true)?, vec![b"/Tool\nName".to_vec(); 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_multi_tags_each_entry_once_with_its_pattern() -> Result<()> {
+        let mut encoded = Vec::new();
+        {
+            let mut blocks = frcode::BlockWriter::new(&mut encoded);
+            for (hash, name) in [('0', "one"), ('1', "two")] {
+                let paths = ["/bin/foo", "/bin/bar", "/bin/foobar", "/lib/baz"]
+                    .map(|file| format!("{}-{}", file, name));
+                encode_files(&mut blocks, &store_path(hash, name), &paths)?;
+                blocks.end_block()?;
+            }
+            blocks.finish()?;
+        }
+        let data = block_database(&encoded)?;
+
+        let patterns = [Regex::new("/foo")?, Regex::new("bar")?];
+        let tagged = |threads| -> Result<Vec<(String, usize)>> {
+            let mut matches = Reader::from_reader(io::Cursor::new(data.clone()))?
+                .query_multi(&patterns)
+                .parallel(threads)
+                .run_tagged()?
+                .map(|m| m.map(|(i, m)| (m.path_lossy().into_owned(), i)))
+                .collect::<Result<Vec<_>>>()?;
+            matches.sort();
+            Ok(matches)
+        };
+        let mut expected = ["one", "two"]
+            .into_iter()
+            .flat_map(|name| {
+                [
+                    (format!("/bin/foo-{}", name), 0),
+                    (format!("/bin/bar-{}", name), 1),
+                    // matches both patterns, but is only returned for the first one
+                    (format!("/bin/foobar-{}", name), 0),
+                ]
+            })
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(tagged(1)?, expected);
+        assert_eq!(tagged(2)?, expected);
+
+        let count = Reader::from_reader(io::Cursor::new(data.clone()))?
+            .query_multi(&patterns)
+            .count()?;
+        assert_eq!(count, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_limit_stops_reading_blocks_early() -> Result<()> {
+        let mut encoded = Vec::new();
+        {
+            let mut blocks = frcode::BlockWriter::new(&mut encoded);
+            for (hash, name) in [('0', "foo"), ('1', "bar"), ('2', "baz")] {
+                let paths = ["/bin/a", "/bin/b"].map(|file| format!("{}-{}", file, name));
+                encode_files(&mut blocks, &store_path(hash, name), &paths)?;
+                blocks.end_block()?;
+            }
+            blocks.finish()?;
         }
+        let data = block_database(&encoded)?;
+        let pattern = Regex::new("^/bin/")?;
+
+        // returns the number of matches and of the blocks that were searched
+        let run = |limit| -> Result<(usize, usize)> {
+            let blocks = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let progress = Arc::clone(&blocks);
+            let matches = Reader::from_reader(io::Cursor::new(data.clone()))?
+                .query(&pattern)
+                .limit(limit)
+                .on_progress(Box::new(move |info| {
+                    progress.store(info.blocks, Ordering::Relaxed)
+                }))
+                .run()?
+                .collect::<Result<Vec<_>>>()?;
+            Ok((matches.len(), blocks.load(Ordering::Relaxed)))
+        };
+        let (all, all_blocks) = run(None)?;
+        assert_eq!(all, 6);
+        let (limited, limited_blocks) = run(Some(3))?;
+        assert_eq!(limited, 3);
+        assert!(limited_blocks < all_blocks, "{} blocks", limited_blocks);
+        let (first, first_blocks) = run(Some(1))?;
+        assert_eq!(first, 1);
+        assert!(first_blocks <= limited_blocks, "{} blocks", first_blocks);
+        // nothing has to be searched without any matches to return
+        assert_eq!(run(Some(0))?, (0, 0));
+        assert_eq!(run(Some(10))?, (6, all_blocks));
+
+        let count = Reader::from_reader(io::Cursor::new(data.clone()))?
+            .query(&pattern)
+            .limit(Some(4))
+            .count()?;
+        assert_eq!(count, 4);
+        Ok(())
     }
 
-    /// Dumps the contents of the database to stdout, for debugging.
-    #[allow(clippy::print_stdout)]
-    pub fn dump(&mut self) -> Result<()> {
-        loop {
-            let block = self.decoder.decode()?;
-            if block.is_empty() {
-                break;
-            }
-            for line in block.split(|c| *c == b'\n') {
-                println!("{:?}", String::from_utf8_lossy(line));
-            }
-            println!("-- block boundary");
+    #[test]
+    fn test_grouped_matches_contain_all_entries_of_each_package() -> Result<()> {
+        let path = TempPath::new("grouped");
+        // small blocks, so that the entries of the first package span several blocks
+        let mut writer = WriterBuilder::new().block_size(64).build(&path)?;
+        let many = (0..20).map(|i| (format!("tool-{:02}", i), FileTree::regular(1, true)));
+        let many = many.map(|(name, tree)| (serde_bytes::ByteBuf::from(name.into_bytes()), tree));
+        let packages = [
+            (
+                '0',
+                "foo",
+                directory([("bin", FileTree::directory(many.collect()))]),
+            ),
+            (
+                '1',
+                "bar",
+                directory([("lib", directory([("tool-00", FileTree::regular(1, false))]))]),
+            ),
+            (
+                '2',
+                "baz",
+                directory([("bin", directory([("tool-00", FileTree::regular(1, true))]))]),
+            ),
+        ];
+        for (hash, name, tree) in packages {
+            writer.add(store_path(hash, name), tree, b"")?;
         }
+        writer.finish()?;
+        assert!(Reader::open(&path)?.block_count()? > 2);
+
+        let groups = Reader::open(&path)?
+            .query(&Regex::new("^/bin/tool")?)
+            .run()?
+            .grouped()
+            .map(|group| {
+                group.map(|(store_path, entries)| {
+                    let mut paths = entries.into_iter().map(|e| e.path).collect::<Vec<_>>();
+                    paths.sort();
+                    (store_path.name().into_owned(), paths)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let foo = (0..20)
+            .map(|i| format!("/bin/tool-{:02}", i).into_bytes())
+            .collect();
+        // packages without matches are left out
+        assert_eq!(
+            groups,
+            [
+                ("foo".to_string(), foo),
+                ("baz".to_string(), vec![b"/bin/tool-00".to_vec()])
+            ]
+        );
         Ok(())
     }
-}
 
-/// A builder for a `ReaderIter` to iterate over entries in the database matching a given pattern.
-pub struct Query<'a, 'b> {
-    /// The underlying reader from which we read input.
-    reader: Reader,
+    #[test]
+    fn test_query_cache_returns_cached_results_until_the_database_changes() -> Result<()> {
+        let path = TempPath::new("query-cache");
+        let write = |names: &[&str]| -> Result<SystemTime> {
+            let mut writer = Writer::create(&path, 1)?;
+            for (i, name) in names.iter().enumerate() {
+                let tree = directory([("bin", directory([(*name, FileTree::regular(1, true))]))]);
+                writer.add(store_path(char::from(b'0' + i as u8), name), tree, b"")?;
+            }
+            writer.finish()?;
+            Ok(fs::metadata(&path)?.modified()?)
+        };
+        let modified = write(&["foo"])?;
 
-    /// The pattern that file paths have to match.
-    exact_regex: &'a Regex,
+        let mut cache = QueryCache::new(&path).max_entries(2);
+        let opts = LocateOptions::default();
+        let first = cache.query("^/bin/", &opts)?;
+        assert_eq!(first.len(), 1);
+        assert!(Arc::ptr_eq(&first, &cache.query("^/bin/", &opts)?));
+        // different options are cached separately
+        let limited = LocateOptions {
+            limit: Some(0),
+            ..LocateOptions::default()
+        };
+        assert!(cache.query("^/bin/", &limited)?.is_empty());
+        assert!(Arc::ptr_eq(&first, &cache.query("^/bin/", &opts)?));
 
-    /// Only include the package with the given hash.
-    hash: Option<String>,
+        // the least recently used query is evicted
+        let foo = cache.query("foo", &opts)?;
+        assert!(Arc::ptr_eq(&first, &cache.query("^/bin/", &opts)?));
+        cache.query("bin", &opts)?;
+        assert!(Arc::ptr_eq(&first, &cache.query("^/bin/", &opts)?));
+        assert!(!Arc::ptr_eq(&foo, &cache.query("foo", &opts)?));
 
-    /// Only include packages whose name matches the given pattern.
-    package_pattern: Option<&'b Regex>,
-}
+        // a rebuilt database with the same modification time is still detected by its size
+        write(&["foo", "bar"])?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)?;
+        assert_eq!(cache.query("^/bin/", &opts)?.len(), 2);
+        Ok(())
+    }
 
-impl<'a, 'b> Query<'a, 'b> {
-    /// Limit results to entries from the package with the specified hash if `Some`.
-    pub fn hash(self, hash: Option<String>) -> Query<'a, 'b> {
-        Query { hash, ..self }
+    #[test]
+    fn test_cancelled_query_stops_with_an_error() -> Result<()> {
+        let mut encoded = Vec::new();
+        {
+            let mut blocks = frcode::BlockWriter::new(&mut encoded);
+            for (hash, name) in [('0', "foo"), ('1', "bar"), ('2', "baz")] {
+                encode_files(
+                    &mut blocks,
+                    &store_path(hash, name),
+                    &[format!("/bin/{}", name)],
+                )?;
+                blocks.end_block()?;
+            }
+            blocks.finish()?;
+        }
+        let data = block_database(&encoded)?;
+        let pattern = Regex::new("^/bin/")?;
+        let is_cancelled = |result: Option<Result<Match>>| {
+            matches!(result, Some(Err(Error(ErrorKind::Cancelled, _))))
+        };
+
+        let token = Arc::new(AtomicBool::new(false));
+        let reader = Reader::from_reader(io::Cursor::new(data.clone()))?;
+        let mut matches = reader.query(&pattern).cancel_token(token.clone()).run()?;
+        let first = matches.next().expect("the first package matches")?;
+        assert_eq!(first.path(), b"/bin/foo");
+        token.store(true, Ordering::Relaxed);
+        assert!(is_cancelled(matches.next()));
+        // the matches of the remaining packages are not returned afterwards
+        assert!(matches.take(3).all(|m| m.is_err()));
+
+        // a token that is already set stops the query before the first block
+        let reader = Reader::from_reader(io::Cursor::new(data))?;
+        let mut matches = reader.query(&pattern).cancel_token(token).run()?;
+        assert!(is_cancelled(matches.next()));
+        Ok(())
     }
 
-    /// Limit results to entries from packages whose name matches the given regex if `Some`.
-    pub fn package_pattern(self, package_pattern: Option<&'b Regex>) -> Query<'a, 'b> {
-        Query {
-            package_pattern,
-            ..self
+    #[test]
+    fn test_parallel_query_finds_the_same_matches() -> Result<()> {
+        let mut encoded = Vec::new();
+        {
+            let mut blocks = frcode::BlockWriter::new(&mut encoded);
+            for name in ["foo", "bar", "baz"] {
+                let paths = ["/bin/a", "/bin/b", "/lib/c"].map(|file| format!("{}-{}", file, name));
+                encode_files(&mut blocks, &store_path('0', name), &paths)?;
+                blocks.end_block()?;
+            }
+            blocks.finish()?;
         }
+        let data = block_database(&encoded)?;
+
+        let pattern = Regex::new("^/bin/")?;
+        let paths = |threads, dedup, max_buffered| -> Result<Vec<Vec<u8>>> {
+            Reader::from_reader(io::Cursor::new(data.clone()))?
+                .query(&pattern)
+                .parallel(threads)
+                .dedup_packages(dedup)
+                .max_buffered(max_buffered)
+                .run()?
+                .map(|m| m.map(|m| m.entry.path))
+                .collect()
+        };
+        let serial = paths(1, false, None)?;
+        assert_eq!(serial.len(), 6);
+        assert_eq!(paths(2, false, None)?, serial);
+        assert_eq!(paths(1, false, Some(1))?, serial);
+        // all packages have the same hash, so only the files of the first one are returned
+        let mut dedup = paths(1, true, Some(1))?;
+        dedup.sort();
+        assert_eq!(dedup, [b"/bin/a-foo".to_vec(), b"/bin/b-foo".to_vec()]);
+        let mut parallel = paths(2, true, None)?;
+        parallel.sort();
+        assert_eq!(parallel, dedup);
+        Ok(())
     }
 
-    /// Runs the query, returning an Iterator that will yield all entries matching the conditions.
-    ///
-    /// There is no guarantee about the order of the returned matches.
-    pub fn run(self) -> Result<ReaderIter<'a, 'b>> {
-        let mut expr = regex_syntax::ast::parse::Parser::new()
-            .parse(self.exact_regex.as_str())
-            .expect("regex cannot be invalid");
-        // replace the ^ anchor by a NUL byte, since each entry is of the form `METADATA\0PATH`
-        // (so the NUL byte marks the start of the path).
+    #[test]
+    fn test_package_files_returns_all_files_of_one_package() -> Result<()> {
+        let mut encoded = Vec::new();
         {
-            let mut stack = vec![&mut expr];
-            while let Some(e) = stack.pop() {
-                match *e {
-                    Ast::Assertion(Assertion {
-                        kind: AssertionKind::StartLine,
-                        span,
-                    }) => {
-                        *e = Ast::Literal(Literal {
-                            span,
-                            c: '\0',
-                            kind: regex_syntax::ast::LiteralKind::Verbatim,
-                        })
-                    }
-                    Ast::Group(Group { ref mut ast, .. }) => stack.push(ast),
-                    Ast::Repetition(Repetition { ref mut ast, .. }) => stack.push(ast),
-                    Ast::Concat(Concat { ref mut asts, .. })
-                    | Ast::Alternation(Alternation { ref mut asts, .. }) => stack.extend(asts),
-                    _ => {}
+            let mut blocks = frcode::BlockWriter::new(&mut encoded);
+            for (hash, name) in [('0', "foo"), ('1', "bar"), ('2', "baz")] {
+                let paths = ["/bin/a", "/bin/b", "/lib/c"].map(|file| format!("{}-{}", file, name));
+                encode_files(&mut blocks, &store_path(hash, name), &paths)?;
+                if name == "foo" {
+                    blocks.end_block()?;
                 }
             }
+            blocks.finish()?;
         }
-        let mut regex_builder = grep::regex::RegexMatcherBuilder::new();
-        regex_builder.line_terminator(Some(b'\n')).multi_line(true);
+        let data = block_database(&encoded)?;
 
-        let grep = regex_builder.build(&format!("{}", expr))?;
-        Ok(ReaderIter {
-            reader: self.reader,
-            found: Vec::new(),
-            found_without_package: Vec::new(),
-            pattern: grep,
-            exact_pattern: self.exact_regex,
-            package_entry_pattern: regex_builder.build("^p\0").expect("valid regex"),
-            package_name_pattern: self.package_pattern,
-            package_hash: self.hash,
-        })
+        let files = |hash: char| -> Result<Vec<Vec<u8>>> {
+            Reader::from_reader(io::Cursor::new(data.clone()))?
+                .package_files(&hash.to_string().repeat(32))
+                .map(|entry| entry.map(|entry| entry.path))
+                .collect()
+        };
+        for (hash, name) in [('0', "foo"), ('1', "bar"), ('2', "baz")] {
+            let expected = ["/bin/a", "/bin/b", "/lib/c"]
+                .iter()
+                .map(|file| format!("{}-{}", file, name).into_bytes())
+                .collect::<Vec<_>>();
+            assert_eq!(files(hash)?, expected);
+        }
+        assert!(files('3')?.is_empty());
+        Ok(())
     }
-}
 
-/// An iterator for entries in a database matching a given pattern.
-pub struct ReaderIter<'a, 'b> {
-    /// The underlying reader from which we read input.
-    reader: Reader,
-    /// Entries that matched the pattern but have not been returned by `next` yet.
-    found: Vec<(StorePath, FileTreeEntry)>,
-    /// Entries that matched the pattern but for which we don't know yet what package they belong to.
-    /// This may happen if the entry we matched was at the end of the search buffer, so that the entry
-    /// for the package did not fit into the buffer anymore (since the package is stored after the entries
-    /// of the package). In this case, we need to look for the package entry in the next iteration when
-    /// we read the next block of input.
-    found_without_package: Vec<FileTreeEntry>,
-    /// The pattern for which to search package paths.
-    ///
-    /// This pattern should work on the raw bytes of file entries. In particular, the file path is not the
-    /// first data in a file entry, so the regex `^` anchor will not work correctly.
-    ///
-    /// The pattern here may produce false positives (for example, if it matches inside the metadata of a file
-    /// entry). This is not a problem, as matches are later checked against `exact_pattern`.
-    pattern: grep::regex::RegexMatcher,
-    /// The raw pattern, as supplied to `find_iter`. This is used to verify matches, since `pattern` itself
-    /// may produce false positives.
-    exact_pattern: &'a Regex,
-    /// Pattern that matches only package entries.
-    package_entry_pattern: grep::regex::RegexMatcher,
-    /// Pattern that the package name should match.
-    package_name_pattern: Option<&'b Regex>,
-    /// Only search the package with the given hash.
-    package_hash: Option<String>,
-}
+    #[test]
+    fn test_build_index_skips_duplicate_store_paths() -> Result<()> {
+        let packages = [('0', "foo"), ('1', "bar"), ('0', "foo")].map(|(hash, name)| {
+            let bin = directory([(name, FileTree::regular(10, true))]);
+            (store_path(hash, name), directory([("bin", bin)]))
+        });
 
-fn consume_no_error<T>(e: NoError) -> T {
-    panic!("impossible: {}", e)
-}
+        let path = TempPath::new("build-index");
+        let mut writer = Writer::create(&path, 1)?;
+        let mut progress = Vec::new();
+        let added =
+            build_index_with_progress(&mut writer, packages, b"/bin/", |p| progress.push(p))?;
+        writer.finish()?;
 
-fn next_matching_line<M: Matcher<Error = NoError>>(
-    matcher: M,
-    buf: &[u8],
-    mut start: usize,
-) -> Option<Match> {
-    while let Some(candidate) = matcher
-        .find_candidate_line(&buf[start..])
-        .unwrap_or_else(consume_no_error)
-    {
-        // the buffer may end with a newline character, so we may get a match
-        // for an empty "line" at the end of the buffer
-        // since this is not a line match, return None
-        if start == buf.len() {
-            return None;
-        };
+        let mut reader = Reader::open(&path)?;
+        let counts = (reader.package_count()?, reader.entry_count()?);
+        assert_eq!(added, 2);
+        // each package has the `/bin` directory and one file in it
+        assert_eq!(counts, (2, 4));
+        let expected = [1, 2].map(|packages| IndexProgress { packages, files: 2 });
+        assert_eq!(progress, expected);
+        Ok(())
+    }
 
-        let (pos, confirmed) = match candidate {
-            LineMatchKind::Confirmed(pos) => (start + pos, true),
-            LineMatchKind::Candidate(pos) => (start + pos, false),
+    #[test]
+    fn test_append_keeps_old_entries_and_replaces_database_on_finish() -> Result<()> {
+        let package = |hash, name| {
+            let tree = directory([(name, FileTree::regular(10, true))]);
+            (store_path(hash, name), tree)
+        };
+        let path = TempPath::new("append");
+        let count = |pattern: &str| -> Result<usize> {
+            Reader::open(&path)?.query(&Regex::new(pattern)?).count()
         };
 
-        let line_start = memrchr(b'\n', &buf[..pos]).map_or(0, |x| x + 1);
-        let line_end = memchr(b'\n', &buf[pos..]).map_or(buf.len(), |x| x + pos + 1);
+        let mut writer = Writer::create(&path, 1)?;
+        let (foo, tree) = package('0', "foo");
+        writer.add(foo, tree, b"")?;
+        writer.finish()?;
 
-        if !confirmed
-            && !matcher
-                .is_match(&buf[line_start..line_end])
-                .unwrap_or_else(consume_no_error)
-        {
-            start = line_end;
-            continue;
-        }
+        let mut writer = Writer::append(&path, 1)?;
+        assert!(writer.store_path_hashes().contains(&"0".repeat(32)));
+        assert_eq!(
+            (writer.codec(), writer.has_exact_index()),
+            (Codec::Zstd, false)
+        );
+        let (bar, tree) = package('1', "bar");
+        writer.add(bar, tree, b"")?;
+        // the database is only replaced once the writer is finished
+        assert_eq!((count("foo")?, count("bar")?), (1, 0));
+        writer.finish()?;
+        assert_eq!((count("foo")?, count("bar")?), (1, 1));
+        let stats = Reader::open(&path)?.stats()?;
+        // each package has the root directory and one file in it
+        assert_eq!((stats.packages, stats.files), (2, 4));
+        Ok(())
+    }
 
-        return Some(Match::new(line_start, line_end));
+    #[test]
+    fn test_append_rejects_older_databases() -> Result<()> {
+        let mut encoded = Vec::new();
+        encode_files(
+            &mut encoded,
+            &store_path('0', "foo"),
+            &["/bin/foo".to_string()],
+        )?;
+        let path = TempPath::new("append-legacy");
+        fs::write(&path, legacy_database(&encoded)?)?;
+        match Writer::append(&path, 1) {
+            Err(Error(ErrorKind::UnsupportedVersion(1), _)) => {}
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+        Ok(())
     }
-    None
-}
 
-impl<'a, 'b> ReaderIter<'a, 'b> {
-    /// Reads input until `self.found` contains at least one entry or the end of the input has been reached.
-    fn fill_buf(&mut self) -> Result<()> {
-        // the input is processed in blocks until we've found at least a single entry
-        while self.found.is_empty() {
-            let &mut ReaderIter {
-                ref mut reader,
-                ref package_entry_pattern,
-                ref package_name_pattern,
-                ref package_hash,
-                ..
-            } = self;
-            let block = reader.decoder.decode()?;
-
-            // if the block is empty, the end of input has been reached
-            if block.is_empty() {
-                return Ok(());
-            }
+    #[test]
+    fn test_dropping_an_appending_writer_after_a_checkpoint_replaces_database() -> Result<()> {
+        let path = TempPath::new("append-checkpoint");
+        let mut writer = Writer::create(&path, 1)?;
+        let tree = directory([("foo", FileTree::regular(10, true))]);
+        writer.add(store_path('0', "foo"), tree, b"")?;
+        writer.finish()?;
 
-            // when we find a match, we need to know the package that this match belongs to.
-            // the `find_package` function will skip forward until a package entry is found
-            // (the package entry comes after all file entries for a package).
-            //
-            // to be more efficient if there are many matches, we cache the current package here.
-            // this package is valid for all positions up to the second element of the tuple
-            // (after that, a new package begins).
-            let mut cached_package: Option<(StorePath, usize)> = None;
-            let mut no_more_package = false;
-            let mut find_package = |item_end| -> Result<_> {
-                if let Some((ref pkg, end)) = cached_package {
-                    if item_end < end {
-                        return Ok(Some((pkg.clone(), end)));
-                    }
-                }
+        let mut writer = Writer::append(&path, 1)?;
+        let tree = directory([("bar", FileTree::regular(10, true))]);
+        writer.add(store_path('1', "bar"), tree, b"")?;
+        writer.checkpoint()?;
+        drop(writer);
 
-                if no_more_package {
-                    return Ok(None);
-                }
+        let names = Reader::open(&path)?
+            .packages()
+            .map(|p| p.map(|p| p.name().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(names, ["foo", "bar"]);
+        let mut temp = path.0.clone().into_os_string();
+        temp.push(format!(".append-{}", std::process::id()));
+        assert!(!Path::new(&temp).exists());
+        Ok(())
+    }
 
-                let mat = match next_matching_line(package_entry_pattern, block, item_end) {
-                    Some(v) => v,
-                    None => {
-                        no_more_package = true;
-                        return Ok(None);
-                    }
-                };
+    #[test]
+    fn test_stats_are_read_from_the_footer_if_it_has_counts() -> Result<()> {
+        let tree = directory([("bin", directory([("foo", FileTree::regular(10, true))]))]);
+        let path = TempPath::new("stats");
+        let mut writer = Writer::create(&path, 1)?;
+        writer.add(store_path('0', "foo"), tree.clone(), b"")?;
+        writer.finish()?;
 
-                let json = &block[mat.start() + 2..mat.end() - 1];
-                let pkg: StorePath = serde_json::from_slice(json)
-                    .chain_err(|| ErrorKind::StorePathParse(json.to_vec()))?;
-                cached_package = Some((pkg.clone(), mat.end()));
-                Ok(Some((pkg, mat.end())))
-            };
+        let mut reader = Reader::open(&path)?;
+        let stats = reader.stats()?;
+        assert_eq!((stats.packages, stats.files), (1, 3));
+        // the entries were not decoded, so they can still be searched
+        assert_eq!(reader.query(&Regex::new("/bin/foo")?).count()?, 1);
 
-            // Tests if a store path matches the `package_name_pattern` and `package_hash` constraints.
-            let should_search_package = |pkg: &StorePath| -> bool {
-                package_name_pattern.map_or(true, |r| r.is_match(pkg.name().as_bytes()))
-                    && package_hash.as_ref().map_or(true, |h| h == &pkg.hash())
-            };
+        // databases from before the footer had counts are scanned
+        let mut encoded = Vec::new();
+        encode_package(&mut encoded, &store_path('0', "foo"), &tree, b"")?;
+        let data = legacy_database(&encoded)?;
+        let stats = Reader::from_reader(io::Cursor::new(data))?.stats()?;
+        assert_eq!((stats.version, stats.packages, stats.files), (1, 1, 3));
+        Ok(())
+    }
 
-            let mut pos = 0;
-            // if there are any entries without a package left over from the previous iteration, see
-            // if this block contains the package entry.
-            if !self.found_without_package.is_empty() {
-                if let Some((pkg, end)) = find_package(0)? {
-                    if !should_search_package(&pkg) {
-                        // all entries before end will have the same package
-                        pos = end;
-                        self.found_without_package.truncate(0);
-                    } else {
-                        for entry in self.found_without_package.split_off(0) {
-                            self.found.push((pkg.clone(), entry));
-                        }
-                    }
-                }
-            }
+    #[test]
+    fn test_writing_resumes_after_a_checkpoint() -> Result<()> {
+        let path = TempPath::new("checkpoint");
+        let packages = |path: &TempPath| -> Result<(Vec<String>, u64)> {
+            let mut reader = Reader::open(path)?;
+            let count = reader.package_count()?;
+            let names = reader.packages().map(|p| p.map(|p| p.name().into_owned()));
+            Ok((names.collect::<Result<_>>()?, count))
+        };
+        let lookup = |path: &TempPath, file: &[u8]| -> Result<usize> {
+            Ok(Reader::open(path)?.lookup_exact(file)?.len())
+        };
 
-            // process all matches in this block
-            while let Some(mat) = next_matching_line(&self.pattern, block, pos) {
-                pos = mat.end();
-                let entry = &block[mat.start()..mat.end() - 1];
-                // skip entries that aren't describing file paths
-                if self
-                    .package_entry_pattern
-                    .is_match(entry)
-                    .unwrap_or_else(consume_no_error)
-                {
-                    continue;
-                }
+        let mut writer = WriterBuilder::new().exact_index(true).build(&path)?;
+        for (hash, name) in [('0', "foo"), ('1', "bar")] {
+            let tree = directory([(name, FileTree::regular(10, true))]);
+            writer.add(store_path(hash, name), tree, b"")?;
+        }
+        writer.checkpoint()?;
+        // a second checkpoint without new packages changes nothing
+        writer.checkpoint()?;
+        assert_eq!(packages(&path)?, (vec!["foo".into(), "bar".into()], 2));
+        assert_eq!(lookup(&path, b"/bar")?, 1);
 
-                // skip if package name or hash doesn't match
-                // we can only skip if we know the package
-                if let Some((pkg, end)) = find_package(mat.end())? {
-                    if !should_search_package(&pkg) {
-                        // all entries before end will have the same package
-                        pos = end;
-                        continue;
-                    }
-                }
+        let tree = directory([("baz", FileTree::regular(10, true))]);
+        writer.add(store_path('2', "baz"), tree, b"")?;
+        writer.checkpoint()?;
+        assert_eq!(lookup(&path, b"/baz")?, 1);
+        let tree = directory([("qux", FileTree::regular(10, true))]);
+        writer.add(store_path('3', "qux"), tree, b"")?;
+        writer.finish()?;
 
-                let entry = FileTreeEntry::decode(entry)
-                    .ok_or_else(|| Error::from(ErrorKind::EntryParse(entry.to_vec())))?;
+        let names = ["foo", "bar", "baz", "qux"].map(String::from).to_vec();
+        assert_eq!(packages(&path)?, (names, 4));
+        for file in [&b"/foo"[..], b"/baz", b"/qux"] {
+            assert_eq!(lookup(&path, file)?, 1);
+        }
+        assert_eq!(Reader::open(&path)?.stats()?.files, 8);
+        Ok(())
+    }
 
-                // check for false positives
-                if !self.exact_pattern.is_match(&entry.path) {
-                    continue;
-                }
+    #[test]
+    fn test_exact_index_finds_paths_in_all_frames() -> Result<()> {
+        // enough file names to fill the first frame, so that the second package is in a
+        // frame of its own
+        let docs = (0..EXACT_INDEX_FRAME_SIZE / 64 + 1).map(|i| {
+            let name = format!("{:0>64}", i);
+            (
+                serde_bytes::ByteBuf::from(name.into_bytes()),
+                FileTree::regular(1, false),
+            )
+        });
+        let large = directory([
+            ("bin", directory([("zsh", FileTree::regular(10, true))])),
+            ("doc", FileTree::directory(docs.collect())),
+        ]);
+        let small = directory([
+            ("bin", directory([("zsh", FileTree::regular(10, true))])),
+            ("share", directory([("zsh", FileTree::regular(10, false))])),
+        ]);
 
-                match find_package(mat.end())? {
-                    None => self.found_without_package.push(entry),
-                    Some((pkg, _)) => self.found.push((pkg, entry)),
-                }
+        let path = TempPath::new("exact-index");
+        let mut writer = WriterBuilder::new().exact_index(true).build(&path)?;
+        writer.add(store_path('0', "zsh-large"), large, b"")?;
+        writer.add(store_path('1', "zsh"), small, b"")?;
+        writer.finish()?;
+
+        let mut reader = Reader::open(&path)?;
+        let frames = reader.exact_index()?.map(|index| index.frames.len());
+        assert_eq!(frames, Some(2));
+        let lookup = |reader: &mut Reader, path: &[u8]| -> Result<Vec<String>> {
+            let matches = reader.lookup_exact(path)?;
+            Ok(matches
+                .into_iter()
+                .map(|m| m.store_path.name().into_owned())
+                .collect())
+        };
+        assert_eq!(lookup(&mut reader, b"/bin/zsh")?, ["zsh-large", "zsh"]);
+        assert_eq!(lookup(&mut reader, b"/share/zsh")?, ["zsh"]);
+        assert_eq!(
+            lookup(&mut reader, &format!("/doc/{:0>64}", 7).into_bytes())?,
+            ["zsh-large"]
+        );
+        // only whole paths match, not paths with the same file name
+        assert!(lookup(&mut reader, b"/zsh")?.is_empty());
+        assert!(lookup(&mut reader, b"/bin/bash")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_copies_every_package_of_older_databases() -> Result<()> {
+        let tree = directory([("bin", directory([("foo", FileTree::regular(10, true))]))]);
+        let (mut encoded, mut blocks) = (Vec::new(), Vec::new());
+        {
+            let mut writer = frcode::BlockWriter::new(&mut blocks);
+            // the same store path under two attribute paths
+            for name in ["foo", "bar"] {
+                encode_package(&mut encoded, &store_path('0', name), &tree, b"")?;
+                encode_package(&mut writer, &store_path('0', name), &tree, b"")?;
             }
+            writer.finish()?;
         }
+        let new_path = TempPath::new("migrated");
+        let pattern = Regex::new("^/bin/foo$")?;
+        for (name, data) in [
+            ("legacy", legacy_database(&encoded)?),
+            ("blocks", block_database(&blocks)?),
+        ] {
+            let old_path = TempPath::new(name);
+            fs::write(&old_path, data)?;
+            migrate(&old_path, &new_path, 1)?;
+
+            let old = Reader::open(&old_path)?.stats()?;
+            let new = Reader::open(&new_path)?.stats()?;
+            assert_eq!((new.packages, new.files), (old.packages, old.files));
+            assert_eq!((new.packages, new.files), (2, 6));
+            assert_eq!(new.version, FORMAT_VERSION);
+            let paths = Reader::open(&new_path)?.query(&pattern).count()?;
+            assert_eq!(paths, 2);
+        }
+
+        match migrate(&new_path, &new_path, 1) {
+            Err(Error(ErrorKind::Io(e), _)) if e.kind() == io::ErrorKind::InvalidInput => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(Reader::open(&new_path)?.stats()?.packages, 2);
         Ok(())
     }
 
-    /// Returns the next match in the database.
-    fn next_match(&mut self) -> Result<Option<(StorePath, FileTreeEntry)>> {
-        self.fill_buf()?;
-        Ok(self.found.pop())
+    #[test]
+    fn test_which_only_returns_executables_and_symlinks() -> Result<()> {
+        let path = TempPath::new("which");
+        let mut writer = Writer::create(&path, 1)?;
+        let commands = [
+            ('0', "exe", FileTree::regular(10, true)),
+            ('1', "conf", FileTree::regular(10, false)),
+            (
+                '2',
+                "link",
+                FileTree::symlink(serde_bytes::ByteBuf::from(b"../libexec/foo".to_vec())),
+            ),
+            ('3', "dir", directory([])),
+        ];
+        for (hash, name, foo) in commands {
+            let tree = directory([("bin", directory([("foo", foo)]))]);
+            writer.add(store_path(hash, name), tree, b"")?;
+        }
+        writer.finish()?;
+
+        let mut names = which(&path, "foo")?
+            .into_iter()
+            .map(|store_path| store_path.name().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["exe", "link"]);
+        Ok(())
     }
-}
 
-impl<'a, 'b> Iterator for ReaderIter<'a, 'b> {
-    type Item = Result<(StorePath, FileTreeEntry)>;
+    #[test]
+    fn test_unicode_store_paths_and_files_round_trip_through_database() -> Result<()> {
+        let store_paths = [('0', "café au lait-1.0"), ('1', "héllo & wörld")]
+            .map(|(hash, name)| store_path(hash, name));
+        let file_name = "naïve & brave";
+        let tree = directory([(file_name, FileTree::regular(10, false))]);
+        let path = TempPath::new("unicode");
+        let mut writer = Writer::create(&path, 1)?;
+        let packages = store_paths.iter().map(|p| (p.clone(), tree.clone()));
+        build_index(&mut writer, packages, b"")?;
+        writer.finish()?;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.next_match() {
-            Err(e) => Some(Err(e)),
-            Ok(v) => v.map(Ok),
+        let mut matches = Reader::open(&path)?
+            .query(&Regex::new("ï")?)
+            .sorted(true)
+            .run()?
+            .collect::<Result<Vec<_>>>()?;
+        matches.sort_by(|a, b| a.store_path.hash().cmp(&b.store_path.hash()));
+        assert_eq!(matches.len(), 2);
+        for (m, store_path) in matches.iter().zip(&store_paths) {
+            assert_eq!(&m.store_path, store_path);
+            assert_eq!(m.path(), [&b"/"[..], file_name.as_bytes()].concat());
         }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_uncompressed_database_stores_raw_entries() -> Result<()> {
+        let tree = directory([("hello-world", FileTree::regular(10, true))]);
+        let path = TempPath::new("raw");
+        let mut writer = WriterBuilder::new().codec(Codec::None).build(&path)?;
+        writer.add(store_path('0', "hello"), tree, b"")?;
+        writer.finish()?;
+        let data = fs::read(&path)?;
+
+        assert!(memmem::find(&data, b"hello-world").is_some());
+        let count = Reader::from_reader(io::Cursor::new(data))?
+            .query(&Regex::new("world")?)
+            .count()?;
+        assert_eq!(count, 1);
+        Ok(())
+    }
 
     #[test]
-    fn test_next_matching_line_package() {
-        let matcher = grep::regex::RegexMatcherBuilder::new()
-            .line_terminator(Some(b'\n'))
-            .multi_line(true)
-            .build("^p")
-            .expect("valid regex");
-        let buffer = br#"
-SOME LINE
-pDATA
-ANOTHER LINE
-        "#;
+    fn test_size_limit_rejects_large_patterns() -> Result<()> {
+        let mut encoded = Vec::new();
+        encode_files(
+            &mut encoded,
+            &store_path('0', "foo"),
+            &["/bin/foo".to_string()],
+        )?;
+        let data = legacy_database(&encoded)?;
+        let query = |pattern: &str| -> Result<usize> {
+            let pattern = Regex::new(pattern)?;
+            Reader::from_reader(io::Cursor::new(data.clone()))?
+                .query(&pattern)
+                .size_limit(Some(100_000))
+                .count()
+        };
 
-        let mat = next_matching_line(matcher, buffer, 0);
-        assert_eq!(mat, Some(Match::new(11, 17)));
+        assert_eq!(query("bin/foo")?, 1);
+        assert_eq!(query("^/bin/[a-z]+$")?, 1);
+        match query(r"\w{50}") {
+            Err(Error(ErrorKind::PatternTooLarge(100_000), _)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_seek_block_skips_the_previous_blocks() -> Result<()> {
+        let path = TempPath::new("seek-block");
+        // a tiny block size ends a block after each package
+        let mut writer = WriterBuilder::new().block_size(1).build(&path)?;
+        for (hash, name) in [('0', "foo"), ('1', "bar"), ('2', "baz")] {
+            let tree = directory([(name, FileTree::regular(10, true))]);
+            writer.add(store_path(hash, name), tree, b"")?;
+        }
+        writer.finish()?;
+
+        let pattern = Regex::new("^/[a-z]+$")?;
+        let packages_from = |block_index| -> Result<Vec<String>> {
+            let mut reader = Reader::open(&path)?;
+            reader.seek_block(block_index)?;
+            let matches = reader.query(&pattern).run()?;
+            matches
+                .map(|m| m.map(|m| m.store_path.name().into_owned()))
+                .collect()
+        };
+        assert_eq!(Reader::open(&path)?.block_count()?, 3);
+        assert_eq!(packages_from(0)?, ["foo", "bar", "baz"]);
+        assert_eq!(packages_from(2)?, ["baz"]);
+        match packages_from(3) {
+            Err(Error(ErrorKind::BlockOutOfRange(3, 3), _)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        Ok(())
     }
 }
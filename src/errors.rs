@@ -10,11 +10,11 @@ error_chain::error_chain! {
         }
         FetchFiles(path: StorePath) {
             description("file listing fetch error")
-            display("fetching the file listing for store path '{}' failed", path.as_str())
+            display("fetching the file listing for store path '{}' failed", path)
         }
         FetchReferences(path: StorePath) {
             description("references fetch error")
-            display("fetching the references of store path '{}' failed", path.as_str())
+            display("fetching the references of store path '{}' failed", path)
         }
         LoadPathsCache {
             description("paths.cache load error")
@@ -36,6 +36,10 @@ error_chain::error_chain! {
             description("database write error")
             display("writing to the database '{}' failed", path.to_string_lossy())
         }
+        ReadDatabase(path: PathBuf) {
+            description("database read error")
+            display("reading the existing database '{}' failed", path.to_string_lossy())
+        }
         ParseProxy(err: crate::hydra::Error){
             description("proxy parse error")
             display("Can not parse proxy settings")
@@ -0,0 +1,369 @@
+//! A C interface for searching nix-index databases.
+//!
+//! This module is only available with the `ffi` feature. Besides the Rust library, the crate is
+//! built as a shared and a static library (`libnix_index.so` and `libnix_index.a` on Linux),
+//! which export these functions if the feature is enabled:
+//!
+//! ```text
+//! cargo build --release --lib --features ffi
+//! ```
+//!
+//! A search looks like this in C:
+//!
+//! ```c
+//! NixIndex *index = nix_index_open("/home/user/.cache/nix-index/files");
+//! if (!index || nix_index_query(index, "bin/firefox$") != NIX_INDEX_OK) {
+//!     fprintf(stderr, "%s\n", nix_index_last_error());
+//! }
+//! const char *store_path, *file_path;
+//! while (nix_index_next(index, &store_path, &file_path) == NIX_INDEX_MATCH) {
+//!     printf("%s%s\n", store_path, file_path);
+//! }
+//! nix_index_free(index);
+//! ```
+//!
+//! Functions that fail return a negative error code (or a null pointer) and store a
+//! description of the error, which can be retrieved with `nix_index_last_error`. A panic does
+//! not unwind into the caller, it is reported as `NIX_INDEX_ERR_PANIC` instead.
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use regex::bytes::Regex;
+
+use crate::database::{Reader, ReaderIter};
+
+/// The function succeeded.
+pub const NIX_INDEX_OK: c_int = 0;
+/// `nix_index_next` returned the next match.
+pub const NIX_INDEX_MATCH: c_int = 1;
+/// A null pointer or a string that is not valid UTF-8 was passed, or the functions were
+/// called in the wrong order.
+pub const NIX_INDEX_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The database could not be read.
+pub const NIX_INDEX_ERR_DATABASE: c_int = -2;
+/// The pattern is not a valid regex.
+pub const NIX_INDEX_ERR_PATTERN: c_int = -3;
+/// A path of a match contains a NUL byte, so it cannot be returned as a C string.
+pub const NIX_INDEX_ERR_PATH: c_int = -4;
+/// The library panicked because of a bug. The index must not be used anymore, except for
+/// freeing it.
+pub const NIX_INDEX_ERR_PANIC: c_int = -5;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records `message` as the last error of this thread and returns `code`.
+fn set_last_error(code: c_int, message: &str) -> c_int {
+    let message = CString::new(message.replace('\0', "\\0")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+    code
+}
+
+/// Calls `f`, but records a panic as the last error and returns `on_panic` instead of unwinding
+/// into the caller, which is undefined behavior.
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown error");
+        set_last_error(NIX_INDEX_ERR_PANIC, &format!("panic: {}", message));
+        on_panic
+    })
+}
+
+/// An open database, created by `nix_index_open`.
+pub struct NixIndex {
+    state: State,
+    /// The paths of the last match, which stay valid until the next call to `nix_index_next`.
+    store_path: CString,
+    file_path: CString,
+}
+
+enum State {
+    Opened(Box<Reader>),
+    Querying(Box<ReaderIter<'static, 'static>>),
+    /// The query failed or all matches have been returned.
+    Done,
+}
+
+/// Converts a C string argument, recording an error if it is null or not valid UTF-8.
+///
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(set_last_error(
+            NIX_INDEX_ERR_INVALID_ARGUMENT,
+            &format!("{} must not be null", name),
+        ));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| {
+        set_last_error(
+            NIX_INDEX_ERR_INVALID_ARGUMENT,
+            &format!("{} is not valid UTF-8", name),
+        )
+    })
+}
+
+/// Opens the database file at `path`.
+///
+/// Returns null if the database cannot be opened.
+///
+/// # Safety
+///
+/// `path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn nix_index_open(path: *const c_char) -> *mut NixIndex {
+    catch_panic(ptr::null_mut(), || {
+        let Ok(path) = str_arg(path, "path") else {
+            return ptr::null_mut();
+        };
+        match Reader::open(path) {
+            Ok(reader) => Box::into_raw(Box::new(NixIndex {
+                state: State::Opened(Box::new(reader)),
+                store_path: CString::default(),
+                file_path: CString::default(),
+            })),
+            Err(e) => {
+                set_last_error(NIX_INDEX_ERR_DATABASE, &e.to_string());
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Starts searching the database for entries whose path matches the regex `pattern`.
+///
+/// Each database can only be searched once, open it again for another query.
+///
+/// # Safety
+///
+/// `index` must be null or a pointer returned by `nix_index_open` that was not freed yet.
+/// `pattern` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn nix_index_query(index: *mut NixIndex, pattern: *const c_char) -> c_int {
+    catch_panic(NIX_INDEX_ERR_PANIC, || {
+        let Some(index) = index.as_mut() else {
+            return set_last_error(NIX_INDEX_ERR_INVALID_ARGUMENT, "index must not be null");
+        };
+        let pattern = match str_arg(pattern, "pattern") {
+            Ok(pattern) => pattern,
+            Err(code) => return code,
+        };
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => return set_last_error(NIX_INDEX_ERR_PATTERN, &e.to_string()),
+        };
+
+        let State::Opened(reader) = std::mem::replace(&mut index.state, State::Done) else {
+            return set_last_error(
+                NIX_INDEX_ERR_INVALID_ARGUMENT,
+                "the database was already queried",
+            );
+        };
+        match reader.query(&regex).run() {
+            Ok(iter) => {
+                index.state = State::Querying(Box::new(iter.into_owned()));
+                NIX_INDEX_OK
+            }
+            Err(e) => set_last_error(NIX_INDEX_ERR_DATABASE, &e.to_string()),
+        }
+    })
+}
+
+/// Finds the next match of the query started with `nix_index_query`.
+///
+/// Returns `NIX_INDEX_MATCH` and sets `store_path` and `file_path` if there is another match,
+/// or `NIX_INDEX_OK` if all matches have been returned. The file path is relative to the store
+/// path. Both strings are owned by `index` and stay valid until the next call to this function
+/// or `nix_index_free`.
+///
+/// # Safety
+///
+/// `index` must be null or a pointer returned by `nix_index_open` that was not freed yet.
+/// `store_path` and `file_path` must be null or valid pointers to write a string pointer to.
+#[no_mangle]
+pub unsafe extern "C" fn nix_index_next(
+    index: *mut NixIndex,
+    store_path: *mut *const c_char,
+    file_path: *mut *const c_char,
+) -> c_int {
+    catch_panic(NIX_INDEX_ERR_PANIC, || {
+        let Some(index) = index.as_mut() else {
+            return set_last_error(NIX_INDEX_ERR_INVALID_ARGUMENT, "index must not be null");
+        };
+        if store_path.is_null() || file_path.is_null() {
+            return set_last_error(
+                NIX_INDEX_ERR_INVALID_ARGUMENT,
+                "store_path and file_path must not be null",
+            );
+        }
+
+        let next = match index.state {
+            State::Opened(_) => {
+                return set_last_error(
+                    NIX_INDEX_ERR_INVALID_ARGUMENT,
+                    "nix_index_query must be called first",
+                )
+            }
+            State::Querying(ref mut iter) => iter.next(),
+            State::Done => None,
+        };
+        let m = match next {
+            Some(Ok(m)) => m,
+            Some(Err(e)) => {
+                index.state = State::Done;
+                return set_last_error(NIX_INDEX_ERR_DATABASE, &e.to_string());
+            }
+            None => {
+                index.state = State::Done;
+                return NIX_INDEX_OK;
+            }
+        };
+
+        let paths = (
+            CString::new(m.store_path.as_str().into_owned()),
+            CString::new(m.entry.path),
+        );
+        let (Ok(store), Ok(file)) = paths else {
+            return set_last_error(
+                NIX_INDEX_ERR_PATH,
+                "the path of a match contains a NUL byte",
+            );
+        };
+        index.store_path = store;
+        index.file_path = file;
+        *store_path = index.store_path.as_ptr();
+        *file_path = index.file_path.as_ptr();
+        NIX_INDEX_MATCH
+    })
+}
+
+/// Closes the database and frees all memory of `index`.
+///
+/// # Safety
+///
+/// `index` must be null or a pointer returned by `nix_index_open` that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn nix_index_free(index: *mut NixIndex) {
+    catch_panic((), || {
+        if !index.is_null() {
+            drop(Box::from_raw(index));
+        }
+    })
+}
+
+/// Returns a description of the last error that happened in this thread, or null if there
+/// was none.
+///
+/// The string stays valid until the next call of a function of this library in this thread.
+#[no_mangle]
+pub extern "C" fn nix_index_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Writer;
+    use crate::files::FileTree;
+    use crate::package::{PathOrigin, StorePath};
+
+    /// Returns the last error of this thread.
+    fn last_error() -> String {
+        let error = nix_index_last_error();
+        assert!(!error.is_null());
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_query_returns_matches_through_the_c_interface() -> crate::database::Result<()> {
+        let path = std::env::temp_dir().join(format!("nix-index-ffi-test-{}", std::process::id()));
+        let origin = PathOrigin {
+            attr: "hello".to_string(),
+            output: "out".to_string(),
+            toplevel: true,
+            system: None,
+        };
+        let store_path = format!("/nix/store/{}-hello-2.12", "0".repeat(32));
+        let store_path = StorePath::parse(origin, &store_path).expect("valid store path");
+        let bin = FileTree::directory(
+            [("hello", FileTree::regular(10, true))]
+                .into_iter()
+                .map(|(name, tree)| (serde_bytes::ByteBuf::from(name.as_bytes().to_vec()), tree))
+                .collect(),
+        );
+        let tree = FileTree::directory(
+            [(serde_bytes::ByteBuf::from(b"bin".to_vec()), bin)]
+                .into_iter()
+                .collect(),
+        );
+        let mut writer = Writer::create(&path, 1)?;
+        writer.add(store_path, tree, b"")?;
+        writer.finish()?;
+
+        let c_path = CString::new(path.to_string_lossy().into_owned()).expect("no NUL byte");
+        let missing = CString::new("/nonexistent/nix-index/files").expect("no NUL byte");
+        let pattern = CString::new("bin/hel+o$").expect("no NUL byte");
+        let invalid = CString::new("bin/(").expect("no NUL byte");
+        unsafe {
+            assert!(nix_index_open(missing.as_ptr()).is_null());
+            assert!(!last_error().is_empty());
+
+            let index = nix_index_open(c_path.as_ptr());
+            let _ = std::fs::remove_file(&path);
+            assert!(!index.is_null());
+            let (mut store, mut file) = (ptr::null(), ptr::null());
+            assert_eq!(
+                nix_index_next(index, &mut store, &mut file),
+                NIX_INDEX_ERR_INVALID_ARGUMENT
+            );
+            assert_eq!(last_error(), "nix_index_query must be called first");
+            assert_eq!(
+                nix_index_query(index, invalid.as_ptr()),
+                NIX_INDEX_ERR_PATTERN
+            );
+
+            assert_eq!(nix_index_query(index, pattern.as_ptr()), NIX_INDEX_OK);
+            assert_eq!(
+                nix_index_next(index, &mut store, &mut file),
+                NIX_INDEX_MATCH
+            );
+            assert_eq!(
+                CStr::from_ptr(store).to_str(),
+                Ok(&*format!("/nix/store/{}-hello-2.12", "0".repeat(32)))
+            );
+            assert_eq!(CStr::from_ptr(file).to_str(), Ok("/bin/hello"));
+            assert_eq!(nix_index_next(index, &mut store, &mut file), NIX_INDEX_OK);
+
+            assert_eq!(
+                nix_index_query(index, pattern.as_ptr()),
+                NIX_INDEX_ERR_INVALID_ARGUMENT
+            );
+            assert_eq!(last_error(), "the database was already queried");
+            nix_index_free(index);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_panics_become_error_codes() {
+        let code = catch_panic(NIX_INDEX_ERR_PANIC, || -> c_int {
+            panic!("broken invariant")
+        });
+        assert_eq!(code, NIX_INDEX_ERR_PANIC);
+        assert_eq!(
+            catch_panic(NIX_INDEX_ERR_PANIC, || NIX_INDEX_OK),
+            NIX_INDEX_OK
+        );
+        assert_eq!(last_error(), "panic: broken invariant");
+    }
+}
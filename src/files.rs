@@ -2,17 +2,31 @@
 //!
 //! The main type here is `FileTree` which represents
 //! such as the file listing for a store path.
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+#[cfg(unix)]
+use std::ffi::OsString;
+#[cfg(unix)]
+use std::fs;
 use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
 use std::str::{self, FromStr};
 
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use clap::builder::PossibleValue;
 use clap::ValueEnum;
 use memchr::memchr;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_bytes::ByteBuf;
 
 use crate::frcode;
+use crate::package::StorePath;
 
 /// This enum represents a single node in a file tree.
 ///
@@ -61,7 +75,7 @@ pub enum FileNode<T> {
 /// An exception to this is the `executable` field for the regular type.
 /// This is needed since we present `regular` and `executable` files as different
 /// to the user, so we need a way to represent both types.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum FileType {
     Regular { executable: bool },
     Directory,
@@ -110,6 +124,58 @@ pub const ALL_FILE_TYPES: &[FileType] = &[
     FileType::Symlink,
 ];
 
+/// The byte used to escape NUL bytes and newlines in paths and symlink targets.
+///
+/// These bytes are used as separators by the frcode format, so they cannot be stored directly.
+/// A NUL byte is escaped as `ESCAPE 0`, a newline as `ESCAPE n` and the escape byte itself as
+/// `ESCAPE ESCAPE`. Since the database is searched in its encoded form, patterns that match
+/// these bytes literally might not find escaped paths.
+const ESCAPE: u8 = 0x01;
+
+/// Escapes NUL bytes and newlines so that the bytes can be stored in the database.
+pub fn escape_path(bytes: &[u8]) -> Cow<'_, [u8]> {
+    if !bytes
+        .iter()
+        .any(|&b| b == ESCAPE || b == b'\0' || b == b'\n')
+    {
+        return Cow::Borrowed(bytes);
+    }
+
+    let mut escaped = Vec::with_capacity(bytes.len() + 2);
+    for &b in bytes {
+        match b {
+            ESCAPE => escaped.extend_from_slice(&[ESCAPE, ESCAPE]),
+            b'\0' => escaped.extend_from_slice(&[ESCAPE, b'0']),
+            b'\n' => escaped.extend_from_slice(&[ESCAPE, b'n']),
+            b => escaped.push(b),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Reverses `escape_path`. Returns `None` if the bytes contain an invalid escape sequence.
+pub fn unescape_path(bytes: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if memchr(ESCAPE, bytes).is_none() {
+        return Some(Cow::Borrowed(bytes));
+    }
+
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(&b) = iter.next() {
+        if b != ESCAPE {
+            unescaped.push(b);
+            continue;
+        }
+        unescaped.push(match iter.next()? {
+            &ESCAPE => ESCAPE,
+            b'0' => b'\0',
+            b'n' => b'\n',
+            _ => return None,
+        });
+    }
+    Some(Cow::Owned(unescaped))
+}
+
 impl<T> FileNode<T> {
     /// Split this node into a node without contents and optionally the contents themselves,
     /// if the node was a directory.
@@ -146,7 +212,7 @@ impl FileNode<()> {
                 encoder.write_meta(format!("{}{}", size, e).as_bytes())?;
             }
             Symlink { ref target } => {
-                encoder.write_meta(target)?;
+                encoder.write_meta(&escape_path(target))?;
                 encoder.write_meta(b"s")?;
             }
             Directory { size, contents: () } => {
@@ -156,7 +222,29 @@ impl FileNode<()> {
         Ok(())
     }
 
+    /// Returns the type of an encoded node without decoding the rest of its metadata.
+    pub fn decode_type(buf: &[u8]) -> Option<FileType> {
+        match buf.last() {
+            Some(b'x') => Some(FileType::Regular { executable: true }),
+            Some(b'r') => Some(FileType::Regular { executable: false }),
+            Some(b's') => Some(FileType::Symlink),
+            Some(b'd') => Some(FileType::Directory),
+            _ => None,
+        }
+    }
+
+    /// Decodes an encoded node.
     pub fn decode(buf: &[u8]) -> Option<Self> {
+        FileNode::decode_impl(buf, true)
+    }
+
+    /// Decodes a node from a database created before symlink targets were escaped
+    /// (format version 3 and older).
+    pub fn decode_legacy(buf: &[u8]) -> Option<Self> {
+        FileNode::decode_impl(buf, false)
+    }
+
+    fn decode_impl(buf: &[u8], escaped: bool) -> Option<Self> {
         use self::FileNode::*;
         buf.split_last().and_then(|(kind, buf)| match *kind {
             b'x' | b'r' => {
@@ -166,6 +254,9 @@ impl FileNode<()> {
                     .and_then(|s| s.parse().ok())
                     .map(|size| Regular { executable, size })
             }
+            b's' if escaped => unescape_path(buf).map(|target| Symlink {
+                target: ByteBuf::from(target.into_owned()),
+            }),
             b's' => Some(Symlink {
                 target: ByteBuf::from(buf),
             }),
@@ -189,30 +280,183 @@ pub struct FileTree(FileNode<HashMap<ByteBuf, FileTree>>);
 ///
 /// If the entry refers to a directory, it only stores information about that
 /// directory itself. It does not contain the children of the directory.
+///
+/// When serialized, the entry is a flat map with the `path`, the `type` of the node
+/// (`regular`, `directory` or `symlink`) and the `size`, `executable` or `target` field of the
+/// node, depending on the type. Paths and symlink targets are serialized as strings. If one of
+/// them is not valid UTF-8, it is serialized as base64 instead and the map gets an additional
+/// `path_encoding` or `target_encoding` field with the value `base64`, so the original bytes can
+/// always be recovered.
+#[derive(Debug, PartialEq, Clone)]
 pub struct FileTreeEntry {
     pub path: Vec<u8>,
     pub node: FileNode<()>,
 }
 
+impl Serialize for FileTreeEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            path: Cow<'a, str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            path_encoding: Option<&'static str>,
+            #[serde(rename = "type")]
+            typ: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            size: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            executable: Option<bool>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            target: Option<Cow<'a, str>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            target_encoding: Option<&'static str>,
+        }
+
+        /// Returns the bytes as a string, or as base64 together with the encoding if they
+        /// are not valid UTF-8.
+        fn encode_bytes(bytes: &[u8]) -> (Cow<'_, str>, Option<&'static str>) {
+            match str::from_utf8(bytes) {
+                Ok(s) => (Cow::Borrowed(s), None),
+                Err(_) => (Cow::Owned(BASE64_STANDARD.encode(bytes)), Some("base64")),
+            }
+        }
+
+        let (typ, size, executable, target) = match self.node {
+            FileNode::Regular { size, executable } => {
+                ("regular", Some(size), Some(executable), None)
+            }
+            FileNode::Directory { size, .. } => ("directory", Some(size), None, None),
+            FileNode::Symlink { ref target } => ("symlink", None, None, Some(encode_bytes(target))),
+        };
+        let (path, path_encoding) = encode_bytes(&self.path);
+        let (target, target_encoding) = target.unzip();
+        Entry {
+            path,
+            path_encoding,
+            typ,
+            size,
+            executable,
+            target,
+            target_encoding: target_encoding.flatten(),
+        }
+        .serialize(serializer)
+    }
+}
+
 impl FileTreeEntry {
     pub fn encode<W: Write>(self, encoder: &mut frcode::Encoder<W>) -> io::Result<()> {
         self.node.encode(encoder)?;
-        encoder.write_path(self.path)?;
+        let path = match escape_path(&self.path) {
+            Cow::Borrowed(_) => self.path,
+            Cow::Owned(escaped) => escaped,
+        };
+        encoder.write_path(path)?;
         Ok(())
     }
 
+    /// Returns the path of the entry, replacing bytes that are not valid UTF-8 by U+FFFD.
+    ///
+    /// Store paths may contain file names that are not valid UTF-8, so this should only be used
+    /// for display. The exact path is available in the `path` field.
+    pub fn path_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.path)
+    }
+
+    /// Returns the absolute path of the entry, assuming that it is part of `store_path`.
+    ///
+    /// The name of a store path already ends with the output name for outputs other than
+    /// `out` (for example `bash-4.4-p5-man`), so the result is the path that can be found in
+    /// the nix store.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::files::{FileNode, FileTreeEntry};
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "bash".to_string(), output: "man".to_string(), toplevel: true, system: None };
+    /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5-man").unwrap();
+    /// let entry = FileTreeEntry {
+    ///     path: b"/share/man/man1/bash.1.gz".to_vec(),
+    ///     node: FileNode::Regular { size: 100, executable: false },
+    /// };
+    /// assert_eq!(
+    ///     entry.absolute_path(&store_path).to_str(),
+    ///     Some("/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5-man/share/man/man1/bash.1.gz"),
+    /// );
+    /// ```
+    pub fn absolute_path(&self, store_path: &StorePath) -> PathBuf {
+        let mut path = store_path.to_string().into_bytes();
+        if !self.path.is_empty() && !self.path.starts_with(b"/") {
+            path.push(b'/');
+        }
+        path.extend_from_slice(&self.path);
+        #[cfg(unix)]
+        let path = OsString::from_vec(path);
+        // other platforms (such as WebAssembly) have no paths made of raw bytes
+        #[cfg(not(unix))]
+        let path = String::from_utf8_lossy(&path).into_owned();
+        PathBuf::from(path)
+    }
+
+    /// Returns the type of the file that this entry refers to.
+    pub fn node_type(&self) -> FileType {
+        self.node.get_type()
+    }
+
+    /// Returns the target of this entry if it is a symlink.
+    pub fn symlink_target(&self) -> Option<&[u8]> {
+        match self.node {
+            FileNode::Symlink { ref target } => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Decodes an encoded entry. Returns `None` if the entry is malformed.
     pub fn decode(buf: &[u8]) -> Option<FileTreeEntry> {
+        FileTreeEntry::decode_impl(buf, true)
+    }
+
+    /// Decodes an entry from a database created before paths were escaped (format version 3
+    /// and older).
+    pub fn decode_legacy(buf: &[u8]) -> Option<FileTreeEntry> {
+        FileTreeEntry::decode_impl(buf, false)
+    }
+
+    fn decode_impl(buf: &[u8], escaped: bool) -> Option<FileTreeEntry> {
         memchr(b'\0', buf).and_then(|sep| {
             let path = &buf[(sep + 1)..];
             let node = &buf[0..sep];
-            FileNode::decode(node).map(|node| FileTreeEntry {
-                path: path.to_vec(),
-                node,
-            })
+            let path = if escaped {
+                unescape_path(path)?.into_owned()
+            } else {
+                path.to_vec()
+            };
+            FileNode::decode_impl(node, escaped).map(|node| FileTreeEntry { path, node })
         })
     }
 }
 
+/// The differences between two file trees, as computed by `FileTree::diff`.
+///
+/// All paths are relative to the roots of the trees, in the same form as the paths of
+/// `FileTreeEntry`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct FileTreeDiff {
+    /// Paths that only exist in the new tree.
+    pub added: BTreeSet<Vec<u8>>,
+    /// Paths that only exist in the old tree.
+    pub removed: BTreeSet<Vec<u8>>,
+    /// Paths that exist in both trees.
+    pub common: BTreeSet<Vec<u8>>,
+    /// Paths that exist in both trees, but whose metadata differs (for example the size of a
+    /// file or the target of a symlink). This is a subset of `common`.
+    pub changed: BTreeSet<Vec<u8>>,
+    /// Paths that exist in both trees, but with a different file type (for example a file that
+    /// became a symlink). This is a subset of `changed`.
+    pub type_changed: BTreeSet<Vec<u8>>,
+}
+
 impl FileTree {
     pub fn regular(size: u64, executable: bool) -> Self {
         FileTree(FileNode::Regular { size, executable })
@@ -229,6 +473,91 @@ impl FileTree {
         })
     }
 
+    /// Builds the file tree of a directory on disk by walking it recursively.
+    ///
+    /// Symlinks are recorded with their target and never followed, so symlink loops are not a
+    /// problem. Only errors for `root` itself are returned: entries whose metadata cannot be
+    /// read are left out, and directories that cannot be listed are recorded as empty.
+    ///
+    /// This is only available on Unix.
+    #[cfg(unix)]
+    pub fn from_dir<P: AsRef<Path>>(root: P) -> io::Result<FileTree> {
+        let root = root.as_ref();
+        let metadata = fs::symlink_metadata(root)?;
+        if metadata.is_dir() {
+            // make sure the root itself can be listed, so that callers notice typos
+            fs::read_dir(root)?;
+        }
+        FileTree::from_metadata(root, &metadata)
+    }
+
+    #[cfg(unix)]
+    fn from_metadata(path: &Path, metadata: &fs::Metadata) -> io::Result<FileTree> {
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            return Ok(FileTree::symlink(ByteBuf::from(
+                target.as_os_str().as_bytes().to_vec(),
+            )));
+        }
+
+        if !file_type.is_dir() {
+            let executable = metadata.permissions().mode() & 0o111 != 0;
+            return Ok(FileTree::regular(metadata.len(), executable));
+        }
+
+        let mut entries = HashMap::new();
+        if let Ok(dir) = fs::read_dir(path) {
+            for entry in dir.flatten() {
+                let path = entry.path();
+                let tree = fs::symlink_metadata(&path)
+                    .and_then(|metadata| FileTree::from_metadata(&path, &metadata));
+                if let Ok(tree) = tree {
+                    entries.insert(ByteBuf::from(entry.file_name().as_bytes().to_vec()), tree);
+                }
+            }
+        }
+        Ok(FileTree::directory(entries))
+    }
+
+    /// Compares this tree (the old one) with `other` (the new one).
+    pub fn diff(&self, other: &FileTree) -> FileTreeDiff {
+        let mut old = self
+            .to_list(b"")
+            .into_iter()
+            .map(|entry| (entry.path, entry.node))
+            .collect::<HashMap<_, _>>();
+
+        let mut diff = FileTreeDiff::default();
+        for FileTreeEntry { path, node } in other.to_list(b"") {
+            match old.remove(&path) {
+                None => {
+                    diff.added.insert(path);
+                }
+                Some(old_node) => {
+                    if old_node.get_type() != node.get_type() {
+                        diff.type_changed.insert(path.clone());
+                    }
+                    if old_node != node {
+                        diff.changed.insert(path.clone());
+                    }
+                    diff.common.insert(path);
+                }
+            }
+        }
+        diff.removed = old.into_keys().collect();
+        diff
+    }
+
+    /// Flattens the tree into a list of entries, one for each file, symlink and directory.
+    ///
+    /// Directories are listed as entries of their own (before their contents), so they are
+    /// indexed and can be found like files, for example with `database::Query::file_type`. The root of
+    /// the tree has an empty path.
+    ///
+    /// Only entries whose path starts with `filter_prefix` are returned. If the prefix ends
+    /// with a `/`, the directory it names is returned as well, so `/include/openssl/` keeps the
+    /// `/include/openssl` directory itself and not only the files below it.
     pub fn to_list(&self, filter_prefix: &[u8]) -> Vec<FileTreeEntry> {
         let mut result = Vec::new();
 
@@ -249,10 +578,58 @@ impl FileTree {
                     stack.push((path, entry));
                 }
             }
-            if path.starts_with(filter_prefix) {
+            let is_prefix_dir = contents.is_some()
+                && !path.is_empty()
+                && filter_prefix.strip_suffix(b"/") == Some(&path[..]);
+            if path.starts_with(filter_prefix) || is_prefix_dir {
                 result.push(FileTreeEntry { path, node });
             }
         }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_entry_encodes_invalid_utf8_as_base64() -> serde_json::Result<()> {
+        let entry = FileTreeEntry {
+            path: b"/share/\xff".to_vec(),
+            node: FileNode::Symlink {
+                target: ByteBuf::from(b"ok".to_vec()),
+            },
+        };
+        let json = serde_json::to_value(&entry)?;
+        assert_eq!(json["path"], "L3NoYXJlL/8=");
+        assert_eq!(json["path_encoding"], "base64");
+        assert_eq!(json["target"], "ok");
+        assert!(json.get("target_encoding").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape_path_round_trips_separators() {
+        let cases: [(&[u8], &[u8]); 4] = [
+            (b"/bin/foo", b"/bin/foo"),
+            (b"a\0b", b"a\x010b"),
+            (b"a\nb", b"a\x01nb"),
+            (b"\x01\0\n", b"\x01\x01\x010\x01n"),
+        ];
+        for (path, escaped) in cases {
+            assert_eq!(&*escape_path(path), escaped);
+            assert_eq!(unescape_path(escaped).as_deref(), Some(path));
+        }
+        // paths without special bytes are not copied
+        assert!(matches!(escape_path(b"/bin/foo"), Cow::Borrowed(_)));
+        assert!(matches!(unescape_path(b"/bin/foo"), Some(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn test_unescape_path_rejects_invalid_escapes() {
+        assert_eq!(unescape_path(b"a\x01x"), None);
+        // an escape byte at the end has no escaped byte
+        assert_eq!(unescape_path(b"a\x01"), None);
+    }
+}
@@ -52,10 +52,18 @@
 //! The last entry shares four bytes less than the second to last one did with its predecessor, so here the differential is negative.
 //!
 //! Through this encoding, the size of the index is typically reduces by a factor of 3 to 5.
+//!
+//! To detect corruption, the encoded lines can additionally be grouped into checksummed blocks
+//! with `BlockWriter` and read back with `BlockReader`. Each block is stored as its length and
+//! its CRC32 checksum (both as little endian `u32`), followed by the encoded bytes. If the offsets
+//! of the blocks are known, `Decoder::seek_block` can start decoding at any block.
 use std::cmp;
-use std::io::{self, BufRead, Write};
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut};
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use error_chain::{bail, error_chain};
 use memchr;
 
@@ -81,9 +89,48 @@ error_chain! {
         MissingPrefixDifferential {
             description("missing the shared prefix length differential for entry")
         }
+        ChecksumMismatch(block: usize) {
+            description("block checksum mismatch")
+            display("checksum mismatch in block {} (counted from where decoding started)", block)
+        }
+        BlockOutOfRange { index: usize, count: usize } {
+            description("block index out of range")
+            display("cannot seek to block {}, there are only {} blocks", index, count)
+        }
+    }
+}
+
+/// The io error returned by `BlockReader` when the checksum of a block does not match.
+///
+/// The decoder converts it to `ErrorKind::ChecksumMismatch`.
+#[derive(Debug)]
+struct BlockChecksumError {
+    block: usize,
+}
+
+impl fmt::Display for BlockChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "checksum mismatch in block {}", self.block)
     }
 }
 
+impl StdError for BlockChecksumError {}
+
+/// Converts an error of the input reader of a decoder, recognizing checksum errors.
+fn input_error(e: io::Error) -> Error {
+    let block = e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<BlockChecksumError>())
+        .map(|inner| inner.block);
+    match block {
+        Some(block) => ErrorKind::ChecksumMismatch(block).into(),
+        None => e.into(),
+    }
+}
+
+/// The default size of the blocks returned by `Decoder::decode`, in bytes.
+pub const DEFAULT_BLOCK_SIZE: usize = 1_000_000;
+
 /// A buffer that may be resizable or not. This is used for decoding,
 /// where we want to make the buffer resizable as long as we haven't decoded
 /// a full entry yet but want to lock it as soon as we got a full entry.
@@ -154,6 +201,8 @@ pub struct Decoder<R> {
     /// last decode iteration. Since this entry was partial, it hasn't been returned to
     /// the user yet and we need to continue decoding this entry in this iteration.
     partial_entry_start: usize,
+    /// Position of the first entry of the block that was returned by the last call to `decode`.
+    block_start: usize,
     /// The length of the shared prefix for the current entry. This is necessary because
     /// the shared length is stored as a difference, so we need the previous value to update it.
     shared_len: isize,
@@ -161,22 +210,46 @@ pub struct Decoder<R> {
     buf: ResizableBuf,
     /// Current write position in buf. The next decoded byte should be written to buf[pos].
     pos: usize,
+    /// The offsets of the blocks in the input, for `seek_block`.
+    block_offsets: Vec<u64>,
 }
 
 impl<R: BufRead> Decoder<R> {
     /// Construct a new decoder for the given source.
     pub fn new(reader: R) -> Decoder<R> {
-        let capacity = 1_000_000;
+        Decoder::with_block_size(reader, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Construct a new decoder that returns blocks of about `block_size` bytes.
+    ///
+    /// Larger blocks mean fewer calls to `decode` and faster searching, since the searcher
+    /// runs over more data at once. Smaller blocks use less memory and make it cheaper to stop
+    /// early, for example when a query is limited or cancelled. A block always contains at
+    /// least one entry, even if that entry is larger than `block_size`.
+    pub fn with_block_size(reader: R, block_size: usize) -> Decoder<R> {
         Decoder {
             reader,
-            buf: ResizableBuf::new(capacity),
+            buf: ResizableBuf::new(block_size),
             pos: 0,
             last_path: 0,
             shared_len: 0,
             partial_entry_start: 0,
+            block_start: 0,
+            block_offsets: Vec::new(),
         }
     }
 
+    /// Sets the offsets at which the blocks start in the input, as returned by
+    /// `BlockWriter::block_offsets`. These are needed for `seek_block`.
+    pub fn set_block_offsets(&mut self, offsets: Vec<u64>) {
+        self.block_offsets = offsets;
+    }
+
+    /// Returns the number of blocks that `seek_block` can seek to.
+    pub fn block_count(&self) -> usize {
+        self.block_offsets.len()
+    }
+
     /// Copies `self.shared_len` bytes from the previous entry's path into the output buffer.
     ///
     /// Returns false if the buffer was too small and could not be resized. In this case, no
@@ -225,7 +298,7 @@ impl<R: BufRead> Decoder<R> {
                 let input = match reader.fill_buf() {
                     Ok(data) => data,
                     Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(Error::from(e)),
+                    Err(e) => return Err(input_error(e)),
                 };
 
                 if input.is_empty() {
@@ -257,23 +330,30 @@ impl<R: BufRead> Decoder<R> {
     /// if the end of input has been reached.
     fn decode_prefix_diff(&mut self) -> Result<i16> {
         let mut buf = [0; 1];
-        self.reader
-            .read_exact(&mut buf)
-            .chain_err(|| ErrorKind::MissingPrefixDifferential)?;
+        self.read_diff_bytes(&mut buf)?;
 
         if buf[0] != 0x80 {
             Ok((buf[0] as i8) as i16)
         } else {
             let mut buf = [0; 2];
-            self.reader
-                .read_exact(&mut buf)
-                .chain_err(|| ErrorKind::MissingPrefixDifferential)?;
+            self.read_diff_bytes(&mut buf)?;
             let high = buf[0] as i16;
             let low = buf[1] as i16;
             Ok(high << 8 | low)
         }
     }
 
+    /// Reads the bytes of a prefix differential from the input reader.
+    fn read_diff_bytes(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(()),
+            Err(e) => match input_error(e) {
+                e @ Error(ErrorKind::ChecksumMismatch(_), _) => Err(e),
+                e => Err(e).chain_err(|| ErrorKind::MissingPrefixDifferential),
+            },
+        }
+    }
+
     /// Decodes some entries to fill the buffer and returns a block of decoded entries.
     ///
     /// It will decode as many entries as fit into the internal buffer, but at least one.
@@ -373,11 +453,45 @@ impl<R: BufRead> Decoder<R> {
         }
 
         // Since we don't want to return partially decoded items, we need to find the end of the last entry.
-        self.partial_entry_start = memchr::memrchr(b'\n', &self.buf[..self.pos])
-            .ok_or_else(|| ErrorKind::MissingNewline)?
-            + 1;
+        self.partial_entry_start =
+            memchr::memrchr(b'\n', &self.buf[..self.pos]).ok_or(ErrorKind::MissingNewline)? + 1;
+        self.block_start = item_start;
         Ok(&mut self.buf[item_start..self.partial_entry_start])
     }
+
+    /// Returns the block that was returned by the last call to `decode` again.
+    ///
+    /// This allows processing a block in several steps without keeping it borrowed. Before the
+    /// first call to `decode`, the returned block is empty.
+    pub fn last_block(&self) -> &[u8] {
+        &self.buf[self.block_start..self.partial_entry_start]
+    }
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Continues decoding at the start of the block with the given index.
+    ///
+    /// The offsets of the blocks must have been set with `set_block_offsets`. Entries that
+    /// were decoded before but not returned yet are discarded.
+    pub fn seek_block(&mut self, block_index: usize) -> Result<()> {
+        let count = self.block_offsets.len();
+        let Some(&offset) = self.block_offsets.get(block_index) else {
+            bail!(ErrorKind::BlockOutOfRange {
+                index: block_index,
+                count,
+            });
+        };
+        self.reader.seek(SeekFrom::Start(offset))?;
+
+        // every block starts with an entry that does not share a prefix with the previous one,
+        // so decoding can start from a clean state
+        self.pos = 0;
+        self.last_path = 0;
+        self.partial_entry_start = 0;
+        self.block_start = 0;
+        self.shared_len = 0;
+        Ok(())
+    }
 }
 
 /// This struct implements an encoder for the frcode format. The encoder
@@ -412,7 +526,8 @@ pub struct Encoder<W: Write> {
 
 impl<W: Write> Drop for Encoder<W> {
     fn drop(&mut self) {
-        self.write_footer().expect("failed to write footer")
+        // errors cannot be reported here, use `finish` to handle them
+        let _ = self.write_footer();
     }
 }
 
@@ -457,7 +572,7 @@ impl<W: Write> Encoder<W> {
     /// in the frcode format.
     fn encode_diff(&mut self, diff: i16) -> io::Result<()> {
         let low = (diff & 0xFF) as u8;
-        if diff.abs() < i8::max_value() as i16 {
+        if diff.abs() < i8::MAX as i16 {
             self.writer.write_all(&[low])?;
         } else {
             let high = ((diff >> 8) & 0xFF) as u8;
@@ -502,11 +617,11 @@ impl<W: Write> Encoder<W> {
             !path.contains(&b'\x00'),
             "entry must not contain null bytes"
         );
-        assert!(!path.contains(&b'\x00'), "entry must not contain newlines");
-        self.writer.write_all(&[b'\x00'])?;
+        assert!(!path.contains(&b'\n'), "entry must not contain newlines");
+        self.writer.write_all(b"\x00")?;
 
         let mut shared: isize = 0;
-        let max_shared = i16::max_value() as isize;
+        let max_shared = i16::MAX as isize;
         for (a, b) in self.last.iter().zip(path.iter()) {
             if a != b || shared > max_shared {
                 break;
@@ -552,10 +667,356 @@ impl<W: Write> Encoder<W> {
     /// Finishes the encoder by writing the footer entry.
     ///
     /// This function is called by drop, but calling it explictly is recommended as
-    /// drop has no way to report IO errors that may occur during writing the footer.
+    /// drop has no way to report IO errors that may occur during writing the footer
+    /// and ignores them.
     pub fn finish(mut self) -> io::Result<()> {
         self.write_footer()?;
 
         Ok(())
     }
 }
+
+/// A writer that groups the encoded data into blocks with a checksum, so that corruption can
+/// be detected when reading the data with `BlockReader`.
+///
+/// Data is buffered until `end_block` is called, which writes the buffered data as one block.
+/// To be able to start decoding at the beginning of any block, blocks should only be ended
+/// where the shared prefix length is zero, such as right after an encoder was finished.
+pub struct BlockWriter<W: Write> {
+    writer: W,
+    buf: Vec<u8>,
+    /// The number of bytes written to `writer` so far.
+    position: u64,
+    /// The offsets of all blocks written so far.
+    offsets: Vec<u64>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    /// Creates a new block writer that writes the blocks to `writer`.
+    pub fn new(writer: W) -> BlockWriter<W> {
+        BlockWriter {
+            writer,
+            buf: Vec::new(),
+            position: 0,
+            offsets: Vec::new(),
+        }
+    }
+
+    /// Returns the offsets at which the blocks that were written so far start, relative to the
+    /// start of the output of this writer.
+    pub fn block_offsets(&self) -> &[u64] {
+        &self.offsets
+    }
+
+    /// Returns the number of bytes that were written to the underlying writer so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the number of bytes that were written since the last block was ended.
+    pub fn pending_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Writes all buffered data as a block. Does nothing if no data was written since the last
+    /// block was ended.
+    pub fn end_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.writer
+            .write_u32::<LittleEndian>(self.buf.len() as u32)?;
+        self.writer
+            .write_u32::<LittleEndian>(crc32fast::hash(&self.buf))?;
+        self.writer.write_all(&self.buf)?;
+        self.offsets.push(self.position);
+        self.position += 8 + self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Ends the current block and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_block()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // the length of a block must fit into a u32
+        if self.buf.len() + buf.len() > u32::MAX as usize {
+            self.end_block()?;
+        }
+        let len = buf.len().min(u32::MAX as usize);
+        self.buf.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Flushes the underlying writer. This does not end the current block.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A reader for data written by `BlockWriter`.
+///
+/// The checksum of each block is verified before any of its data is returned. If a checksum
+/// does not match, reading fails with an error that a `Decoder` reports as
+/// `ErrorKind::ChecksumMismatch`.
+pub struct BlockReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    pos: usize,
+    /// The number of blocks that have been read so far.
+    blocks: usize,
+}
+
+impl<R: Read> BlockReader<R> {
+    /// Creates a new block reader for the given source.
+    pub fn new(reader: R) -> BlockReader<R> {
+        BlockReader {
+            reader,
+            buf: Vec::new(),
+            pos: 0,
+            blocks: 0,
+        }
+    }
+
+    /// Reads the next block into the buffer. At the end of the input, the buffer stays empty.
+    fn read_block(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.pos = 0;
+
+        let mut len = [0; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match self.reader.read(&mut len[read..]) {
+                Ok(0) if read == 0 => return Ok(()),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let len = u64::from(u32::from_le_bytes(len));
+        let checksum = self.reader.read_u32::<LittleEndian>()?;
+
+        // The length is not covered by the checksum, so the buffer only grows with the data
+        // that is actually read: a corrupt length cannot allocate more than the rest of the
+        // input. If the input ends before the block, the block is corrupt as well.
+        (&mut self.reader).take(len).read_to_end(&mut self.buf)?;
+        if self.buf.len() as u64 != len || crc32fast::hash(&self.buf) != checksum {
+            self.buf.clear();
+            let block = self.blocks;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                BlockChecksumError { block },
+            ));
+        }
+        self.blocks += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read> BufRead for BlockReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            self.read_block()?;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.buf.len());
+    }
+}
+
+/// Seeking discards the current block, so the new position must be the start of a block.
+impl<R: Read + Seek> Seek for BlockReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Current(n) => SeekFrom::Current(n - (self.buf.len() - self.pos) as i64),
+            pos => pos,
+        };
+        let new_pos = self.reader.seek(pos)?;
+        self.buf.clear();
+        self.pos = 0;
+        self.blocks = 0;
+        Ok(new_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the blocks written by a `BlockWriter` for each of the given blocks.
+    fn write_blocks(blocks: &[&[u8]]) -> io::Result<Vec<u8>> {
+        let mut writer = BlockWriter::new(Vec::new());
+        for block in blocks {
+            writer.write_all(block)?;
+            writer.end_block()?;
+        }
+        writer.finish()
+    }
+
+    /// Encodes each group of `(meta, path)` entries with its own encoder and returns the blocks
+    /// with one group per block, together with the offsets of the blocks.
+    fn encode_blocks(groups: &[&[(&str, &str)]]) -> io::Result<(Vec<u8>, Vec<u64>)> {
+        let mut writer = BlockWriter::new(Vec::new());
+        for entries in groups {
+            let mut encoder = Encoder::new(&mut writer, b"p".to_vec(), b"end".to_vec());
+            for (meta, path) in entries.iter() {
+                encoder.write_meta(meta.as_bytes())?;
+                encoder.write_path(path.as_bytes().to_vec())?;
+            }
+            encoder.finish()?;
+            writer.end_block()?;
+        }
+        let offsets = writer.block_offsets().to_vec();
+        Ok((writer.finish()?, offsets))
+    }
+
+    /// Returns the entries returned by the next call to `decode` as `meta:path`.
+    fn decode_entries<R: BufRead>(decoder: &mut Decoder<R>) -> Result<Vec<String>> {
+        let block = decoder.decode()?;
+        let Some((_, block)) = block.split_last() else {
+            return Ok(Vec::new());
+        };
+        let entries = block.split(|&b| b == b'\n').map(|line| {
+            let line = String::from_utf8_lossy(line);
+            line.replacen('\0', ":", 1)
+        });
+        Ok(entries.collect())
+    }
+
+    #[test]
+    fn test_entries_round_trip_with_shared_prefixes() -> Result<()> {
+        // the example of the module documentation
+        let entries = [
+            ("d", "/"),
+            ("d", "/foo"),
+            ("d", "/foo/bar"),
+            ("f", "/foo/bar/test.txt"),
+            ("f", "/foo/bar/text.txt"),
+            ("d", "/foo/baz"),
+        ];
+        let mut encoded = Vec::new();
+        let mut encoder = Encoder::new(&mut encoded, b"p".to_vec(), b"end".to_vec());
+        for (meta, path) in entries {
+            encoder.write_meta(meta.as_bytes())?;
+            encoder.write_path(path.as_bytes().to_vec())?;
+        }
+        encoder.finish()?;
+        assert!(encoded.starts_with(b"d\0\0/\nd\0\x01foo\nd\0\x03/bar\n"));
+
+        let mut decoder = Decoder::new(io::Cursor::new(encoded));
+        let mut expected = entries
+            .map(|(meta, path)| format!("{}:{}", meta, path))
+            .to_vec();
+        expected.push("p:end".to_string());
+        assert_eq!(decode_entries(&mut decoder)?, expected);
+        // the input is exhausted now, so the last block stays empty
+        assert!(decode_entries(&mut decoder)?.is_empty());
+        assert!(decoder.last_block().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_writer_records_offsets_and_position() -> io::Result<()> {
+        let mut writer = BlockWriter::new(Vec::new());
+        writer.write_all(b"first")?;
+        assert_eq!((writer.pending_len(), writer.position()), (5, 0));
+        writer.end_block()?;
+        // ending a block without new data does not write an empty block
+        writer.end_block()?;
+        writer.write_all(b"second")?;
+        writer.end_block()?;
+        assert_eq!(writer.block_offsets(), [0, 8 + 5]);
+        assert_eq!(
+            (writer.pending_len(), writer.position()),
+            (0, 8 + 5 + 8 + 6)
+        );
+
+        let data = writer.finish()?;
+        let mut read = Vec::new();
+        BlockReader::new(io::Cursor::new(data)).read_to_end(&mut read)?;
+        assert_eq!(read, b"firstsecond");
+        Ok(())
+    }
+
+    #[test]
+    fn test_seek_block_starts_decoding_at_any_block() -> Result<()> {
+        let (data, offsets) = encode_blocks(&[&[("f", "/foo")], &[("f", "/bar")]])?;
+        let mut decoder = Decoder::new(BlockReader::new(io::Cursor::new(data)));
+        decoder.set_block_offsets(offsets);
+        assert_eq!(decoder.block_count(), 2);
+
+        decoder.seek_block(1)?;
+        assert_eq!(decode_entries(&mut decoder)?, ["f:/bar", "p:end"]);
+        assert!(decoder.last_block().starts_with(b"f\0/bar\n"));
+        // seeking back to a block that was already decoded
+        decoder.seek_block(0)?;
+        let entries = decode_entries(&mut decoder)?;
+        assert_eq!(entries[..2], ["f:/foo", "p:end"]);
+        match decoder.seek_block(2) {
+            Err(Error(ErrorKind::BlockOutOfRange { index: 2, count: 2 }, _)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_checksum_mismatch_is_detected() -> Result<()> {
+        let (mut data, _) = encode_blocks(&[&[("1r", "/a")], &[("1r", "/b")]])?;
+        // flip a bit in the path of the second block
+        let last = data.len() - 4;
+        data[last] ^= 1;
+
+        let mut decoder = Decoder::new(BlockReader::new(io::Cursor::new(data)));
+        let err = loop {
+            match decoder.decode() {
+                Ok([]) => panic!("corrupt block was not detected"),
+                Ok(_) => {}
+                Err(e) => break e,
+            }
+        };
+        match err {
+            Error(ErrorKind::ChecksumMismatch(1), _) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_block_length_is_detected() -> io::Result<()> {
+        let mut data = write_blocks(&[b"first", b"second"])?;
+        // claim that the second block is almost 4 GiB long
+        let second = 8 + b"first".len();
+        data[second..second + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = BlockReader::new(io::Cursor::new(data));
+        let mut buf = Vec::new();
+        let err = reader
+            .read_to_end(&mut buf)
+            .expect_err("corrupt length was not detected");
+        assert_eq!(buf, b"first");
+        match input_error(err) {
+            Error(ErrorKind::ChecksumMismatch(1), _) => {}
+            err => panic!("unexpected error: {}", err),
+        }
+        Ok(())
+    }
+}
@@ -22,6 +22,7 @@ use serde::de::{Deserializer, MapAccess, Visitor};
 use serde::{self, Deserialize};
 use serde_bytes::ByteBuf;
 use serde_json;
+#[cfg(feature = "tokio")]
 use tokio::time::error::Elapsed;
 use tokio_retry::strategy::ExponentialBackoff;
 use tokio_retry::{self, Retry};
@@ -77,6 +78,7 @@ error_chain! {
     }
 }
 
+#[cfg(feature = "tokio")]
 impl From<Elapsed> for Error {
     fn from(_err: Elapsed) -> Self {
         Error::from(ErrorKind::Timeout)
@@ -132,7 +134,7 @@ impl Fetcher {
     ///
     /// This function will automatically retry the request a few times to mitigate intermittent network
     /// failures.
-    fn fetch(&self, url: String) -> BoxFuture<(String, Option<Vec<u8>>)> {
+    fn fetch(&self, url: String) -> BoxFuture<'_, (String, Option<Vec<u8>>)> {
         let strategy = ExponentialBackoff::from_millis(50)
             .max_delay(Duration::from_millis(5000))
             .take(20)
@@ -184,7 +186,7 @@ impl Fetcher {
     ///
     /// The references will be `None` if no information about the store path could be found
     /// (happens if the narinfo wasn't found which means that hydra didn't build this path).
-    pub fn fetch_references(&self, mut path: StorePath) -> BoxFuture<Option<ParsedNAR>> {
+    pub fn fetch_references(&self, mut path: StorePath) -> BoxFuture<'_, Option<ParsedNAR>> {
         let url = format!("{}/{}.narinfo", self.cache_url, path.hash());
 
         let parse_response = move |(url, data)| {
@@ -249,7 +251,7 @@ impl Fetcher {
     /// Fetches the file listing for the given store path.
     ///
     /// A file listing is a tree of the files that the given store path contains.
-    pub async fn fetch_files<'a>(&self, path: &StorePath) -> Result<Option<FileTree>> {
+    pub async fn fetch_files(&self, path: &StorePath) -> Result<Option<FileTree>> {
         let url_xz = format!("{}/{}.ls.xz", self.cache_url, path.hash());
         let url_generic = format!("{}/{}.ls", self.cache_url, path.hash());
         let name = format!("{}.json", path.hash());
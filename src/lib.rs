@@ -1,20 +1,28 @@
-#![cfg_attr(
-    feature = "cargo-clippy",
-    warn(
-        clippy::manual_filter_map,
-        clippy::map_unwrap_or,
-        clippy::module_name_repetitions,
-        clippy::print_stdout,
-        clippy::unwrap_used,
-    )
+#![warn(
+    clippy::manual_filter_map,
+    clippy::map_unwrap_or,
+    clippy::module_name_repetitions,
+    clippy::print_stdout,
+    clippy::unwrap_used
 )]
+// The error types generated by error-chain are large by design (they carry a backtrace and
+// the chained cause), so boxing them everywhere is not worth it.
+#![allow(clippy::result_large_err)]
 
 pub mod database;
+// Fetching file listings and running nix-env needs processes, threads and the network, which
+// are not available on WebAssembly. The database can still be read and queried there.
+#[cfg(not(target_arch = "wasm32"))]
 pub mod errors;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod files;
 pub mod frcode;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod hydra;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod listings;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod nixpkgs;
 pub mod package;
 pub mod util;
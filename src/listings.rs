@@ -1,11 +1,14 @@
+use std::collections::HashSet;
 use std::fs::File;
 use std::io;
 use std::iter::FromIterator;
+use std::path::Path;
 
 use futures::{Stream, StreamExt, TryFutureExt};
 use indexmap::map::Entry;
 use indexmap::IndexMap;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use regex::Regex;
 
 use crate::errors::{Error, ErrorKind, Result, ResultExt};
 use crate::files::FileTree;
@@ -48,11 +51,15 @@ impl<T> FileListingStream for T where T: Stream<Item = Result<Option<(StorePath,
 ///
 /// The `jobs` argument is used to specify how many requests should be done in parallel. No more than
 /// `jobs` requests will be in-flight at any given time.
-fn fetch_listings_impl(
-    fetcher: &Fetcher,
+///
+/// Paths whose hash is contained in `skip` are neither fetched nor returned. Their references are
+/// not followed either, since they were already processed when `skip` was built.
+fn fetch_listings_impl<'a>(
+    fetcher: &'a Fetcher,
     jobs: usize,
     starting_set: Vec<StorePath>,
-) -> (impl FileListingStream + '_, WorkSetWatch) {
+    skip: &'a HashSet<String>,
+) -> (impl FileListingStream + 'a, WorkSetWatch) {
     // Create the queue that will hold all the paths that still need processing.
     // Initially, only the starting set needs processing.
 
@@ -60,6 +67,9 @@ fn fetch_listings_impl(
     let mut map: IndexMap<String, StorePath> = IndexMap::with_capacity(starting_set.len());
 
     for path in starting_set {
+        if skip.contains(path.hash().as_ref()) {
+            continue;
+        }
         let hash = path.hash().into();
         match map.entry(hash) {
             Entry::Occupied(mut e) => {
@@ -88,6 +98,9 @@ fn fetch_listings_impl(
 
         for reference in parsed.references {
             let hash = reference.hash().into_owned();
+            if skip.contains(&hash) {
+                continue;
+            }
             handle.add_work(hash, reference);
         }
 
@@ -136,12 +149,21 @@ pub fn try_load_paths_cache() -> Result<Option<(impl FileListingStream, WorkSetW
     Ok(Some((stream, watch)))
 }
 
+/// Fetches the file listings of all packages in `nixpkgs` for the given systems, and of all
+/// store paths that they reference. The packages are listed by running `nix_env_bin`.
+///
+/// Store paths whose hash is in `skip` are not fetched. If `attr_filter` is set, only the
+/// packages whose attribute path matches it (and the store paths they reference) are fetched.
+#[allow(clippy::module_name_repetitions, clippy::too_many_arguments)]
 pub fn fetch_listings<'a>(
     fetcher: &'a Fetcher,
     jobs: usize,
     nixpkgs: &str,
+    nix_env_bin: &Path,
     systems: Vec<Option<&str>>,
     show_trace: bool,
+    skip: &'a HashSet<String>,
+    attr_filter: Option<&Regex>,
 ) -> Result<(impl FileListingStream + 'a, WorkSetWatch)> {
     let mut scopes = vec![None];
     scopes.extend(EXTRA_SCOPES.map(Some));
@@ -153,14 +175,20 @@ pub fn fetch_listings<'a>(
         }
     }
 
+    // Parsing JSON is much faster, so use it if nix-env supports it.
+    let json = nixpkgs::nix_env_supports_json(nix_env_bin);
+
     // Collect results in parallel.
     let all_paths = all_queries
         .par_iter()
         .flat_map_iter(|&(system, scope)| {
-            nixpkgs::query_packages(nixpkgs, system, scope.as_deref(), show_trace)
+            let scope = scope.as_deref();
+            nixpkgs::query_packages_with(nixpkgs, system, scope, show_trace, nix_env_bin, &[])
+                .json(json)
+                .attr_filter(attr_filter.cloned())
                 .map(|x| x.chain_err(|| ErrorKind::QueryPackages))
         })
         .collect::<Result<_>>()?;
 
-    Ok(fetch_listings_impl(fetcher, jobs, all_paths))
+    Ok(fetch_listings_impl(fetcher, jobs, all_paths, skip))
 }
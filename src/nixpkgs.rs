@@ -3,16 +3,32 @@
 //! This module implements the gathering of initial set of root store paths to fetch.
 //! We parse the output `nix-env --query` to figure out all accessible store paths with their attribute path
 //! and hashes.
+//!
+//! The output can be parsed either as XML or, for newer versions of nix, as JSON which is much
+//! cheaper to parse.
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
 use std::error;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::io::{self, Read};
-use std::process::{Child, ChildStdout, Command, Stdio};
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use flate2::bufread::MultiGzDecoder;
+use regex::Regex;
+use serde::Deserialize;
 use xml;
 use xml::common::{Position, TextPosition};
 use xml::reader::{EventReader, XmlEvent};
 
-use crate::package::{PathOrigin, StorePath};
+use crate::package::{PathOrigin, StorePath, DEFAULT_STORE_DIR};
+use crate::util;
 
 /// Calls `nix-env` to list the packages in the given nixpkgs.
 ///
@@ -20,11 +36,16 @@ use crate::package::{PathOrigin, StorePath};
 /// accepted by `nix-env -f`, such as `<nixpkgs>` or `http://example.org/nixpkgs.tar.bz`.
 ///
 /// If system is `Some(platform)`, nix-env is called with the `--argstr system <platform>` argument so that
-/// the specified platform would be used instead of the default host system platform.
+/// the specified platform would be used instead of the default host system platform. If the evaluation
+/// fails (for example, because the platform is not supported), the returned error includes the platform
+/// and everything that nix-env printed to stderr.
 ///
 /// If scope is `Some(attr)`, nix-env is called with the `-A attr` argument so only packages that are a member
 /// of `attr` are returned.
 ///
+/// The output paths must be located in the store directory given by the `NIX_STORE_DIR`
+/// environment variable (`/nix/store` by default), like nix-env itself does.
+///
 /// The function returns an Iterator over the packages returned by nix-env.
 pub fn query_packages(
     nixpkgs: &str,
@@ -32,13 +53,236 @@ pub fn query_packages(
     scope: Option<&str>,
     show_trace: bool,
 ) -> PackagesQuery<ChildStdout> {
-    let mut cmd = Command::new("nix-env");
+    query_packages_with(
+        nixpkgs,
+        system,
+        scope,
+        show_trace,
+        Path::new("nix-env"),
+        &[],
+    )
+}
+
+/// Like `query_packages`, but lets nix-env print its output as JSON instead of XML.
+///
+/// Parsing JSON is a lot faster, but requires a version of nix that supports `--json` together
+/// with `--out-path` (see `nix_env_supports_json`).
+pub fn query_packages_json(
+    nixpkgs: &str,
+    system: Option<&str>,
+    scope: Option<&str>,
+    show_trace: bool,
+) -> PackagesQuery<ChildStdout> {
+    query_packages(nixpkgs, system, scope, show_trace).json(true)
+}
+
+/// Like `query_packages`, but kills nix-env and returns an error if it does not print any output
+/// for `timeout` or if it takes longer than `timeout` in total.
+///
+/// Use `PackagesQuery::timeouts` to set different limits for stalled output and the total runtime.
+pub fn query_packages_with_timeout(
+    nixpkgs: &str,
+    system: Option<&str>,
+    scope: Option<&str>,
+    show_trace: bool,
+    timeout: Duration,
+) -> PackagesQuery<ChildStdout> {
+    query_packages(nixpkgs, system, scope, show_trace).timeouts(timeout, timeout)
+}
+
+/// Like `query_packages`, but runs nix-env again (up to `retries` times) if it fails with an
+/// error that looks transient, such as a failed download.
+///
+/// Nix-env is only retried if it failed before the first package was returned. Between the
+/// attempts, the query waits for an exponentially growing delay, starting at one second (see
+/// `RetryingPackagesQuery::initial_delay`). If nix-env was run more than once, the final error
+/// includes the number of attempts.
+pub fn query_packages_with_retries(
+    nixpkgs: &str,
+    system: Option<&str>,
+    scope: Option<&str>,
+    show_trace: bool,
+    retries: usize,
+) -> RetryingPackagesQuery {
+    RetryingPackagesQuery {
+        query: query_packages(nixpkgs, system, scope, show_trace),
+        nixpkgs: nixpkgs.to_string(),
+        system: system.map(String::from),
+        scope: scope.map(String::from),
+        show_trace,
+        nix_env_bin: PathBuf::from("nix-env"),
+        retries,
+        initial_delay: Duration::from_secs(1),
+        attempts: 1,
+        returned_package: false,
+    }
+}
+
+/// Parts of nix error messages that indicate a failure that might not happen again, mostly
+/// network errors.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "unable to download",
+    "Couldn't resolve host name",
+    "Could not resolve host",
+    "Connection timed out",
+    "Connection refused",
+    "Connection reset by peer",
+    "Timeout was reached",
+    "HTTP error 5",
+    "SSL connect error",
+];
+
+/// An iterator over the packages of nix-env that retries transient failures.
+///
+/// Use `query_packages_with_retries` to create a value of this type.
+pub struct RetryingPackagesQuery {
+    query: PackagesQuery<ChildStdout>,
+    nixpkgs: String,
+    system: Option<String>,
+    scope: Option<String>,
+    show_trace: bool,
+    nix_env_bin: PathBuf,
+    retries: usize,
+    initial_delay: Duration,
+    attempts: usize,
+    returned_package: bool,
+}
+
+impl RetryingPackagesQuery {
+    /// Waits for `initial_delay` before the first retry instead of one second.
+    ///
+    /// The delay still doubles after each attempt, up to 32 times the initial delay.
+    pub fn initial_delay(mut self, initial_delay: Duration) -> RetryingPackagesQuery {
+        self.initial_delay = initial_delay;
+        self
+    }
+}
+
+impl Iterator for RetryingPackagesQuery {
+    type Item = Result<StorePath, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.query.next() {
+                Some(Ok(store_path)) => {
+                    self.returned_package = true;
+                    return Some(Ok(store_path));
+                }
+                Some(Err(Error::Command(message))) => {
+                    let transient = TRANSIENT_ERRORS.iter().any(|e| message.contains(e));
+                    if transient && !self.returned_package && self.attempts <= self.retries {
+                        let delay = self.initial_delay * (1 << (self.attempts - 1).min(5));
+                        thread::sleep(delay);
+                        self.attempts += 1;
+                        self.query = query_packages_with(
+                            &self.nixpkgs,
+                            self.system.as_deref(),
+                            self.scope.as_deref(),
+                            self.show_trace,
+                            &self.nix_env_bin,
+                            &[],
+                        );
+                        continue;
+                    }
+
+                    if self.attempts > 1 {
+                        return Some(Err(Error::Command(format!(
+                            "{}\n(gave up after {} attempts)",
+                            message, self.attempts
+                        ))));
+                    }
+                    return Some(Err(Error::Command(message)));
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+/// Reads the packages from a file containing the output of `nix-env -qaP --out-path --xml`.
+///
+/// This allows evaluating nixpkgs once and building indexes from the saved output later. Like
+/// for `query_packages`, all store paths must be located in the store directory given by
+/// `NIX_STORE_DIR` (`/nix/store` by default).
+pub fn packages_from_xml_file<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<PackagesParser<io::BufReader<File>>> {
+    let file = File::open(path)?;
+    Ok(PackagesParser::new(
+        io::BufReader::new(file),
+        default_store_dir(),
+    ))
+}
+
+/// Reads the packages from a gzip-compressed file containing the output of
+/// `nix-env -qaP --out-path --xml`, such as one created with `nix-env ... | gzip > packages.xml.gz`.
+///
+/// This is like `packages_from_xml_file`, but keeps the saved output small. Fails with
+/// `Error::Io` if the file cannot be opened or does not start with a gzip header.
+pub fn packages_from_gz_file<P: AsRef<Path>>(
+    path: P,
+) -> Result<PackagesParser<io::BufReader<MultiGzDecoder<io::BufReader<File>>>>, Error> {
+    let mut file = io::BufReader::new(File::open(path)?);
+    if !file.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the file is not compressed with gzip",
+        )));
+    }
+    // files created by concatenating several gzip files are decoded completely
+    Ok(PackagesParser::new(
+        io::BufReader::new(MultiGzDecoder::new(file)),
+        default_store_dir(),
+    ))
+}
+
+/// Returns the store directory that nix uses, which can be changed with `NIX_STORE_DIR`.
+fn default_store_dir() -> String {
+    env::var("NIX_STORE_DIR").unwrap_or_else(|_| DEFAULT_STORE_DIR.to_string())
+}
+
+/// Returns whether the given `nix-env` binary can print its query output as JSON including the
+/// output paths, which is the case since nix 2.4.
+///
+/// Returns false if the version of nix-env could not be determined.
+pub fn nix_env_supports_json(nix_env_bin: &Path) -> bool {
+    let output = match Command::new(nix_env_bin)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return false,
+    };
+
+    // the output looks like `nix-env (Nix) 2.18.1`
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let Some(version) = stdout.split_whitespace().last() else {
+        return false;
+    };
+    let mut parts = version.split('.').map(|p| p.parse::<u32>().ok());
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(major), Some(minor)) => (major, minor) >= (2, 4),
+        _ => false,
+    }
+}
+
+/// Like `query_packages`, but runs the given `nix-env` binary instead of looking up `nix-env`
+/// in `PATH`.
+///
+/// The `extra_args` are appended to the arguments of nix-env, which can be used to pass flags
+/// such as `--option`.
+pub fn query_packages_with(
+    nixpkgs: &str,
+    system: Option<&str>,
+    scope: Option<&str>,
+    show_trace: bool,
+    nix_env_bin: &Path,
+    extra_args: &[OsString],
+) -> PackagesQuery<ChildStdout> {
+    let mut cmd = Command::new(nix_env_bin);
     cmd.arg("-qaP")
         .arg("--out-path")
-        .arg("--xml")
-        .arg("--arg")
-        .arg("config")
-        .arg("{ allowAliases = false; }") // override default nixpkgs config discovery
         .arg("--arg")
         .arg("overlays")
         .arg("[ ]")
@@ -60,32 +304,557 @@ pub fn query_packages(
         cmd.arg("--show-trace");
     }
 
-    PackagesQuery {
-        parser: None,
-        child: None,
-        cmd: Some(cmd),
+    cmd.args(extra_args);
+
+    PackagesQuery::new(cmd, "nix-env", system)
+}
+
+/// The nix expression that `query_packages_flake` applies to the package set. It returns the
+/// packages in the same format as `nix-env -qaP --out-path --json`, skipping attributes that
+/// are not derivations or fail to evaluate.
+const FLAKE_PACKAGES_EXPR: &str = r#"pkgs: builtins.listToAttrs (builtins.concatMap (name:
+  let
+    result = builtins.tryEval (
+      let
+        pkg = pkgs.${name};
+        value = {
+          system = pkg.system or "";
+          outputs = builtins.listToAttrs (map (output: {
+            name = output;
+            value = pkg.${output}.outPath;
+          }) (pkg.outputs or [ "out" ]));
+        };
+      in
+        if builtins.isAttrs pkg && (pkg.type or "") == "derivation"
+        then builtins.deepSeq value [ { inherit name value; } ]
+        else [ ]
+    );
+  in if result.success then result.value else [ ]
+) (builtins.attrNames pkgs))"#;
+
+/// Lists the packages of a flake with `nix eval`.
+///
+/// The `attr` must refer to a package set of the flake, such as `legacyPackages.x86_64-linux`.
+/// Only the direct members of the set that are derivations are returned, and the attribute path
+/// of each package includes `attr` (for example, `legacyPackages.x86_64-linux.hello`).
+///
+/// This requires a version of nix with support for flakes. The `json` and `config` settings of
+/// the returned query are ignored.
+pub fn query_packages_flake(flake_ref: &str, attr: &str) -> PackagesQuery<ChildStdout> {
+    let mut cmd = Command::new("nix");
+    cmd.arg("--extra-experimental-features")
+        .arg("nix-command flakes")
+        .arg("eval")
+        .arg("--json")
+        .arg(format!("{}#{}", flake_ref, attr))
+        .arg("--apply")
+        .arg(FLAKE_PACKAGES_EXPR)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdin(Stdio::null());
+
+    let mut query = PackagesQuery::new(cmd, "nix eval", None);
+    query.flake_attr = Some(attr.to_string());
+    query.json = true;
+    query
+}
+
+/// A nix expression that only returns every `shards`-th top-level attribute of nixpkgs, starting
+/// at the attribute with index `shard`. All other arguments are passed to nixpkgs.
+///
+/// `nixpkgs` is the same string that would be passed to `nix-env --file`: a search path such as
+/// `<nixpkgs>`, an URL or an absolute path.
+const SHARD_EXPR: &str = r#"{ nixpkgs, shard, shards, ... }@args:
+let
+  inherit (builtins) substring stringLength;
+  source =
+    if substring 0 1 nixpkgs == "<" then
+      builtins.findFile builtins.nixPath (substring 1 (stringLength nixpkgs - 2) nixpkgs)
+    else if builtins.match "[a-z]+://.*" nixpkgs != null then
+      builtins.fetchTarball nixpkgs
+    else
+      /. + nixpkgs;
+  pkgs = import source (builtins.removeAttrs args [ "nixpkgs" "shard" "shards" ]);
+  names = builtins.attrNames pkgs;
+  index = builtins.fromJSON shard;
+  step = builtins.fromJSON shards;
+  count = (builtins.length names - index + step - 1) / step;
+  selected = builtins.genList (i: builtins.elemAt names (index + i * step)) count;
+in
+  builtins.listToAttrs (map (name: { inherit name; value = pkgs.${name}; }) selected)
+"#;
+
+/// Like `query_packages` for the top-level packages of nixpkgs, but splits the top-level
+/// attributes into `shards` disjoint sets that are evaluated by separate nix-env processes
+/// running in parallel.
+///
+/// Each process runs the given `nix-env` binary, like `query_packages_with`. The nix expression
+/// that selects the attributes is written to a temporary file, which is removed when the
+/// returned iterator is dropped. Relative paths for `nixpkgs` are resolved against the current
+/// directory.
+pub fn query_packages_parallel(
+    nixpkgs: &str,
+    system: Option<&str>,
+    show_trace: bool,
+    nix_env_bin: &Path,
+    shards: usize,
+) -> io::Result<ParallelPackagesQuery> {
+    let shards = shards.max(1);
+    let is_path = !nixpkgs.starts_with('<') && !nixpkgs.contains("://");
+    let nixpkgs = if is_path {
+        std::fs::canonicalize(nixpkgs)?
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        nixpkgs.to_string()
+    };
+
+    // each query needs its own file, since the file is removed once the query is dropped
+    let expr_file = util::write_temp_file("nix-index-shards.nix", SHARD_EXPR.as_bytes())
+        .ok_or_else(|| io::Error::other("failed to write the shard expression to a file"))?;
+
+    let expr_path = expr_file.to_string_lossy().into_owned();
+    let queries = (0..shards)
+        .map(|shard| {
+            let args = [
+                "--argstr".into(),
+                "nixpkgs".into(),
+                OsString::from(&nixpkgs),
+                "--argstr".into(),
+                "shard".into(),
+                shard.to_string().into(),
+                "--argstr".into(),
+                "shards".into(),
+                shards.to_string().into(),
+            ];
+            query_packages_with(&expr_path, system, None, show_trace, nix_env_bin, &args)
+        })
+        .collect();
+
+    Ok(ParallelPackagesQuery {
+        queries,
+        started: false,
+        spawn_errors: VecDeque::new(),
+        expr_file,
+    })
+}
+
+/// An iterator over the packages of several nix-env processes that run in parallel.
+///
+/// Use `query_packages_parallel` to create a value of this type. The packages of each process
+/// are returned one process after the other. Like for `PackagesQuery`, an error does not end
+/// the iteration. If some of the processes cannot be started, the error of each of them is
+/// returned first, and the packages of the other processes follow.
+pub struct ParallelPackagesQuery {
+    queries: VecDeque<PackagesQuery<ChildStdout>>,
+    started: bool,
+    /// The errors of the processes that could not be started, which are returned first.
+    spawn_errors: VecDeque<Error>,
+    expr_file: PathBuf,
+}
+
+impl Iterator for ParallelPackagesQuery {
+    type Item = Result<StorePath, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            // spawn all processes first, so the evaluations run in parallel
+            for query in &mut self.queries {
+                if let Err(e) = query.ensure_initialized() {
+                    self.spawn_errors.push_back(e);
+                }
+            }
+        }
+        if let Some(e) = self.spawn_errors.pop_front() {
+            return Some(Err(e));
+        }
+
+        while let Some(query) = self.queries.front_mut() {
+            if let Some(item) = query.next() {
+                return Some(item);
+            }
+            self.queries.pop_front();
+        }
+        None
+    }
+}
+
+impl Drop for ParallelPackagesQuery {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.expr_file);
+    }
+}
+
+impl<R: Read> PackagesQuery<R> {
+    fn new(cmd: Command, program: &'static str, system: Option<&str>) -> PackagesQuery<R> {
+        PackagesQuery {
+            parser: None,
+            child: None,
+            cmd: Some(cmd),
+            program,
+            flake_attr: None,
+            stderr: None,
+            on_stderr: None,
+            warnings: Arc::new(Mutex::new(Vec::new())),
+            config: DEFAULT_CONFIG.to_string(),
+            system: system.map(String::from),
+            store_dir: default_store_dir(),
+            json: false,
+            timeouts: None,
+            deadline: None,
+            timed_out: Arc::new(Mutex::new(None)),
+            attr_filter: None,
+            tolerant: false,
+        }
     }
 }
 
 /// An iterator that parses the output of nix-env and returns parsed store paths.
 ///
 /// Use `query_packages` to create a value of this type.
+///
+/// If the query is dropped before all packages were returned, the nix-env process is killed.
 pub struct PackagesQuery<R: Read> {
-    parser: Option<PackagesParser<R>>,
+    parser: Option<Parser<Output<R>>>,
     child: Option<Child>,
     cmd: Option<Command>,
+    /// The name of the command, for error messages.
+    program: &'static str,
+    /// The attribute path of the package set, if the packages of a flake are queried.
+    flake_attr: Option<String>,
+    /// Collects the stderr output of nix-env. It needs to be read while stdout is parsed,
+    /// otherwise nix-env blocks once the pipe buffer is full.
+    stderr: Option<JoinHandle<Vec<u8>>>,
+    on_stderr: Option<StderrCallback>,
+    /// The warnings printed by nix-env and the warnings of the parser.
+    warnings: Arc<Mutex<Vec<String>>>,
+    /// The nix expression passed as the `config` argument of nixpkgs.
+    config: String,
+    system: Option<String>,
+    store_dir: String,
+    json: bool,
+    /// The maximum time without any output and the maximum total runtime of nix-env.
+    timeouts: Option<(Duration, Duration)>,
+    /// The time at which nix-env has to be finished, set when it is spawned.
+    deadline: Option<Instant>,
+    /// Describes the timeout that was hit, if any.
+    timed_out: Arc<Mutex<Option<String>>>,
+    /// Only packages whose attribute path matches this regex are returned.
+    attr_filter: Option<Regex>,
+    /// Whether packages with recoverable parse errors are skipped.
+    tolerant: bool,
+}
+
+/// The nixpkgs config that is used by default. It overrides the default nixpkgs config discovery, so
+/// the result does not depend on the config of the user running nix-env.
+const DEFAULT_CONFIG: &str = "{ allowAliases = false; }";
+
+/// A callback that is called with every line that nix-env prints to stderr, such as progress
+/// messages and warnings. The line does not include the trailing newline.
+pub type StderrCallback = Box<dyn FnMut(&str) + Send>;
+
+/// The stdout of nix-env, which is read through a `TimeoutReader` if timeouts are set.
+enum Output<R> {
+    Direct(R),
+    Timeout(TimeoutReader),
+}
+
+impl<R: Read> Read for Output<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Output::Direct(reader) => reader.read(buf),
+            Output::Timeout(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// A reader that fails with `io::ErrorKind::TimedOut` if no data arrives for some time or if
+/// the deadline has passed.
+///
+/// Reads from a pipe cannot time out, so the data is read by a separate thread and sent to
+/// this reader in chunks.
+struct TimeoutReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    stall_timeout: Duration,
+    total_timeout: Duration,
+    deadline: Instant,
+    timed_out: Arc<Mutex<Option<String>>>,
+}
+
+impl TimeoutReader {
+    fn new<R: Read + Send + 'static>(
+        mut reader: R,
+        stall_timeout: Duration,
+        total_timeout: Duration,
+        deadline: Instant,
+        timed_out: Arc<Mutex<Option<String>>>,
+    ) -> TimeoutReader {
+        let (sender, chunks) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; 64 * 1024];
+            let result = reader.read(&mut chunk).map(|n| {
+                chunk.truncate(n);
+                chunk
+            });
+            let done = result.as_ref().map_or(true, |chunk| chunk.is_empty());
+            // stop if the receiver is gone or the end of the output is reached
+            if sender.send(result).is_err() || done {
+                break;
+            }
+        });
+
+        TimeoutReader {
+            chunks,
+            chunk: Vec::new(),
+            pos: 0,
+            stall_timeout,
+            total_timeout,
+            deadline,
+            timed_out,
+        }
+    }
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            let remaining = self.deadline.saturating_duration_since(Instant::now());
+            let wait = remaining.min(self.stall_timeout);
+            match self.chunks.recv_timeout(wait) {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                    if self.chunk.is_empty() {
+                        return Ok(0);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                Err(RecvTimeoutError::Timeout) => {
+                    let reason = if wait < self.stall_timeout {
+                        format!("did not finish within {:?}", self.total_timeout)
+                    } else {
+                        format!("did not print any output for {:?}", self.stall_timeout)
+                    };
+                    if let Ok(mut timed_out) = self.timed_out.lock() {
+                        *timed_out = Some(reason.clone());
+                    }
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, reason));
+                }
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The parser for the output format that nix-env was asked to produce.
+enum Parser<R: Read> {
+    Xml(Box<PackagesParser<R>>),
+    Json(PackagesParserJson<R>),
+}
+
+impl<R: Read> Parser<R> {
+    fn take_warnings(&mut self) -> Vec<String> {
+        match self {
+            Parser::Xml(parser) => parser.take_warnings(),
+            Parser::Json(_) => Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Parser<R> {
+    type Item = Result<StorePath, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Parser::Xml(parser) => parser.next().map(|v| v.map_err(Error::from)),
+            Parser::Json(parser) => parser.next(),
+        }
+    }
+}
+
+// If the query is dropped before all packages were read, nix-env would keep running
+// (and block once its output pipe is full), so we need to make sure it is stopped.
+impl<R: Read> Drop for PackagesQuery<R> {
+    fn drop(&mut self) {
+        // close our end of the stdout pipe first
+        self.parser = None;
+        if let Some(mut child) = self.child.take() {
+            // the child may have exited already, in which case killing it fails
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if let Some(stderr) = self.stderr.take() {
+            let _ = stderr.join();
+        }
+    }
 }
 
 impl PackagesQuery<ChildStdout> {
+    /// Sets whether nix-env should print its output as JSON instead of XML.
+    ///
+    /// This has no effect if the first package was already requested, or for queries of
+    /// `query_packages_flake`, whose output is always JSON.
+    pub fn json(mut self, json: bool) -> PackagesQuery<ChildStdout> {
+        self.json = json;
+        self
+    }
+
+    /// Sets the nix expression that is passed to nixpkgs as its `config` argument, for example
+    /// `{ allowUnfree = true; }`.
+    ///
+    /// This replaces the default config `{ allowAliases = false; }`, so `allowAliases = false`
+    /// should usually be part of the expression as well. This has no effect if the first
+    /// package was already requested.
+    pub fn config(mut self, config: &str) -> PackagesQuery<ChildStdout> {
+        self.config = config.to_string();
+        self
+    }
+
+    /// Sets an environment variable for the nix-env process, such as `NIX_PATH`.
+    ///
+    /// This has no effect if the first package was already requested.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(
+        mut self,
+        key: K,
+        value: V,
+    ) -> PackagesQuery<ChildStdout> {
+        if let Some(cmd) = self.cmd.as_mut() {
+            cmd.env(key, value);
+        }
+        self
+    }
+
+    /// Calls `callback` for every line that nix-env prints to stderr, which makes it possible
+    /// to show the progress of the evaluation.
+    ///
+    /// The output is still included in the error if nix-env fails. This has no effect if the
+    /// first package was already requested.
+    pub fn on_stderr(mut self, callback: StderrCallback) -> PackagesQuery<ChildStdout> {
+        self.on_stderr = Some(callback);
+        self
+    }
+
+    /// Returns the warnings that were collected so far and clears them.
+    ///
+    /// These are the `warning:` and `trace:` lines that nix-env printed to stderr, as well as
+    /// warnings about packages that were skipped (see `PackagesParser::take_warnings`). All
+    /// warnings are available once the iterator has returned `None`. Warnings are not errors,
+    /// so they are also collected if nix-env succeeds.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        let mut warnings = match self.warnings.lock() {
+            Ok(mut warnings) => std::mem::take(&mut *warnings),
+            Err(_) => Vec::new(),
+        };
+        if let Some(parser) = self.parser.as_mut() {
+            warnings.extend(parser.take_warnings());
+        }
+        warnings
+    }
+
+    /// Only returns packages whose attribute path (`PathOrigin::attr`) matches `attr_filter`
+    /// if `Some`, for example `^python3Packages\.`.
+    ///
+    /// nix-env still evaluates all packages, but the skipped packages are not returned, so
+    /// their file listings do not need to be fetched.
+    pub fn attr_filter(mut self, attr_filter: Option<Regex>) -> PackagesQuery<ChildStdout> {
+        self.attr_filter = attr_filter;
+        self
+    }
+
+    /// Skips single malformed packages in the XML output of nix-env if `true`, instead of
+    /// stopping the query, see `PackagesParser::tolerant`.
+    ///
+    /// The skipped packages are reported by `take_warnings`. This has no effect if the first
+    /// package was already requested.
+    pub fn tolerant(mut self, tolerant: bool) -> PackagesQuery<ChildStdout> {
+        self.tolerant = tolerant;
+        self
+    }
+
+    /// Kills nix-env and fails with `Error::Command` if nix-env does not print any output for
+    /// `stall_timeout` or if it runs longer than `total_timeout`.
+    ///
+    /// This has no effect if the first package was already requested.
+    pub fn timeouts(
+        mut self,
+        stall_timeout: Duration,
+        total_timeout: Duration,
+    ) -> PackagesQuery<ChildStdout> {
+        self.timeouts = Some((stall_timeout, total_timeout));
+        self
+    }
+
     /// Spawns the nix-env subprocess and initializes the parser.
     ///
     /// If the subprocess was already spawned, does nothing.
     fn ensure_initialized(&mut self) -> Result<(), Error> {
         if let Some(mut cmd) = self.cmd.take() {
+            if self.flake_attr.is_none() {
+                cmd.arg("--arg").arg("config").arg(&self.config);
+                cmd.arg(if self.json { "--json" } else { "--xml" });
+            }
             let mut child = cmd.spawn()?;
 
             let stdout = child.stdout.take().expect("should have stdout pipe");
-            let parser = PackagesParser::new(stdout);
+            let mut stderr =
+                io::BufReader::new(child.stderr.take().expect("should have stderr pipe"));
+            let mut on_stderr = self.on_stderr.take();
+            let warnings = Arc::clone(&self.warnings);
+            self.stderr = Some(thread::spawn(move || {
+                let mut buf = Vec::new();
+                loop {
+                    let start = buf.len();
+                    // if reading fails, we still report the part that was read
+                    match stderr.read_until(b'\n', &mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {}
+                    }
+                    let line = buf[start..].strip_suffix(b"\n").unwrap_or(&buf[start..]);
+                    let line = String::from_utf8_lossy(line);
+                    if line.starts_with("warning:") || line.starts_with("trace:") {
+                        if let Ok(mut warnings) = warnings.lock() {
+                            warnings.push(line.to_string());
+                        }
+                    }
+                    if let Some(callback) = on_stderr.as_mut() {
+                        callback(&line);
+                    }
+                }
+                buf
+            }));
+
+            let stdout = match self.timeouts {
+                Some((stall_timeout, total_timeout)) => {
+                    let deadline = Instant::now() + total_timeout;
+                    self.deadline = Some(deadline);
+                    Output::Timeout(TimeoutReader::new(
+                        stdout,
+                        stall_timeout,
+                        total_timeout,
+                        deadline,
+                        Arc::clone(&self.timed_out),
+                    ))
+                }
+                None => Output::Direct(stdout),
+            };
+
+            let store_dir = self.store_dir.clone();
+            // `nix eval` always prints JSON, regardless of the `json` setting
+            let parser = if self.json || self.flake_attr.is_some() {
+                let mut parser = PackagesParserJson::new(stdout, store_dir);
+                parser.attr_prefix = self.flake_attr.as_ref().map(|attr| format!("{}.", attr));
+                Parser::Json(parser)
+            } else {
+                let parser = PackagesParser::new(stdout, store_dir).tolerant(self.tolerant);
+                Parser::Xml(Box::new(parser))
+            };
 
             self.child = Some(child);
             self.parser = Some(parser);
@@ -99,18 +868,47 @@ impl PackagesQuery<ChildStdout> {
     /// If the exit code was non-zero, returns Some(err), else it returns None.
     fn check_error(&mut self) -> Option<Error> {
         let mut run = || {
-            let child = match self.child.take() {
+            let mut child = match self.child.take() {
                 Some(c) => c,
                 None => return Ok(()),
             };
-            let result = child.wait_with_output()?;
+            // if the output timed out, nix-env may still be running
+            let timed_out = self.timed_out.lock().ok().and_then(|t| t.clone());
+            let status = match timed_out {
+                Some(_) => None,
+                None => wait_until(&mut child, self.deadline)?,
+            };
+            let Some(status) = status else {
+                let _ = child.kill();
+                let _ = child.wait();
+                let reason = timed_out.unwrap_or_else(|| {
+                    let total_timeout = self.timeouts.map(|(_, total)| total);
+                    format!(
+                        "did not finish within {:?}",
+                        total_timeout.unwrap_or_default()
+                    )
+                });
+                return Err(Error::Command(format!(
+                    "{} was killed because it {}",
+                    self.program, reason
+                )));
+            };
+            let stderr = self
+                .stderr
+                .take()
+                .and_then(|handle| handle.join().ok())
+                .unwrap_or_default();
 
-            if !result.status.success() {
-                let message = String::from_utf8_lossy(&result.stderr);
+            if !status.success() {
+                let message = String::from_utf8_lossy(&stderr);
+                let system = match self.system {
+                    Some(ref system) => format!(" for system {}", system),
+                    None => String::new(),
+                };
 
                 return Err(Error::Command(format!(
-                    "nix-env failed with {}:\n{}",
-                    result.status, message,
+                    "{} failed{} with {}:\n{}",
+                    self.program, system, status, message,
                 )));
             }
 
@@ -121,37 +919,87 @@ impl PackagesQuery<ChildStdout> {
     }
 }
 
+/// Waits for the child to exit, but gives up once the deadline has passed.
+///
+/// Returns `None` if the child was still running at the deadline.
+fn wait_until(child: &mut Child, deadline: Option<Instant>) -> io::Result<Option<ExitStatus>> {
+    let Some(deadline) = deadline else {
+        return child.wait().map(Some);
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
 impl Iterator for PackagesQuery<ChildStdout> {
     type Item = Result<StorePath, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_package()? {
+                Ok(path)
+                    if self
+                        .attr_filter
+                        .as_ref()
+                        .is_some_and(|filter| !filter.is_match(&path.origin_ref().attr)) => {}
+                item => return Some(item),
+            }
+        }
+    }
+}
+
+impl PackagesQuery<ChildStdout> {
+    /// Returns the next package printed by nix-env, without applying the attribute filter.
+    fn next_package(&mut self) -> Option<Result<StorePath, Error>> {
         if let Err(e) = self.ensure_initialized() {
             return Some(Err(e));
         }
-        self.parser.take().and_then(|mut parser| {
-            parser
-                .next()
-                .map(|v| {
+        self.parser
+            .take()
+            .and_then(|mut parser| match parser.next() {
+                Some(v) => {
                     self.parser = Some(parser);
                     // When the parser throws an error, we first wait for the subprocess to exit.
                     //
                     // If the subprocess returned an error, then the parser probably tried to parse garbage output
                     // so we will ignore the parser error and instead return the error printed by the subprocess.
-                    v.map_err(|e| self.check_error().unwrap_or_else(|| Error::from(e)))
-                })
-                .or_else(|| {
-                    self.parser = None;
+                    Some(v.map_err(|e| self.check_error().unwrap_or(e)))
+                }
+                None => {
+                    if let Ok(mut warnings) = self.warnings.lock() {
+                        warnings.extend(parser.take_warnings());
+                    }
                     // At the end, we should check if the subprocess exited successfully.
                     self.check_error().map(Err)
-                })
-        })
+                }
+            })
     }
 }
 
 /// Parses the XML output of `nix-env` and returns individual store paths.
-struct PackagesParser<R: Read> {
-    events: EventReader<R>,
+///
+/// The input must be the output of `nix-env -qaP --out-path --xml`, since the parser needs the
+/// attribute paths and the output paths of each package. This can be used to parse output that
+/// was saved earlier (see also `packages_from_xml_file`).
+pub struct PackagesParser<R: Read> {
+    events: EventReader<PrefixRecorder<R>>,
+    /// Whether the root element of the document has been read.
+    found_root: bool,
     current_item: Option<(String, String)>,
+    /// The number of outputs found for the current item.
+    current_outputs: usize,
+    store_dir: String,
+    warnings: Vec<String>,
+    /// Whether recoverable errors are reported as warnings instead of stopping the parser.
+    tolerant: bool,
+    /// Whether the rest of the current item is skipped, after a recoverable error in it.
+    skipping_item: bool,
 }
 
 /// A parser error that may occur during parsing `nix-env`'s output.
@@ -188,19 +1036,66 @@ pub enum ParserErrorKind {
     /// Found the end tag for `element_name` without a matching start tag.
     MissingStartTag { element_name: String },
 
+    /// The input does not look like the output of `nix-env -qaP --out-path --xml` at all,
+    /// for example because it is the plain text output of `nix-env -qa`. `found` is the
+    /// start of the input or its root element.
+    UnexpectedFormat { found: String },
+
     /// An XML syntax error.
     XmlError { error: xml::reader::Error },
 
     /// A store path in the output of `nix-env` could not be parsed. All valid store paths
-    /// need to match the format `$(STOREDIR)$(HASH)-$(NAME)`.
-    InvalidStorePath { path: String },
+    /// need to match the format `$(STOREDIR)/$(HASH)-$(NAME)`.
+    InvalidStorePath { path: String, store_dir: String },
+}
+
+impl ParserError {
+    /// Returns the kind of this error, to tell for example a missing attribute from an
+    /// invalid store path.
+    pub fn kind(&self) -> &ParserErrorKind {
+        &self.kind
+    }
+
+    /// Returns the position in the input at which the error occurred.
+    ///
+    /// For errors in the JSON output of `nix-env`, this is always the start of the input.
+    pub fn position(&self) -> TextPosition {
+        self.position
+    }
+
+    /// Returns whether the error only affects a single package, see
+    /// `ParserErrorKind::is_recoverable`.
+    pub fn is_recoverable(&self) -> bool {
+        self.kind.is_recoverable()
+    }
+}
+
+impl ParserErrorKind {
+    /// Returns whether an error of this kind only affects a single `<item>` of the output.
+    ///
+    /// This is the case for missing attributes and invalid store paths, so an indexer can
+    /// skip the package and continue with the next one. All other errors mean that the
+    /// structure of the document is broken, so parsing cannot continue.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            ParserErrorKind::MissingAttribute { .. } | ParserErrorKind::InvalidStorePath { .. }
+        )
+    }
 }
 
 impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "error at {}: {}", self.position, self.kind)
+    }
+}
+
+impl error::Error for ParserError {}
+
+impl fmt::Display for ParserErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         use self::ParserErrorKind::*;
-        write!(f, "error at {}: ", self.position)?;
-        match self.kind {
+        match *self {
             MissingParent {
                 ref element_name,
                 ref expected_parent,
@@ -234,12 +1129,20 @@ impl fmt::Display for ParserError {
             MissingStartTag { ref element_name } => {
                 write!(f, "element {} does not have a start tag", element_name)
             }
+            UnexpectedFormat { ref found } => write!(
+                f,
+                "expected the XML output of `nix-env -qaP --out-path --xml`, but found {:?}",
+                found
+            ),
             XmlError { ref error } => write!(f, "document not well-formed: {}", error),
-            InvalidStorePath { ref path } => {
+            InvalidStorePath {
+                ref path,
+                ref store_dir,
+            } => {
                 write!(
                     f,
-                    "store path does not match expected format /prefix/hash-name: {}",
-                    path
+                    "store path does not match expected format {}/hash-name: {}",
+                    store_dir, path
                 )
             }
         }
@@ -248,13 +1151,41 @@ impl fmt::Display for ParserError {
 
 impl<R: Read> PackagesParser<R> {
     /// Creates a new parser that reads the `nix-env` XML output from the given reader.
-    pub fn new(reader: R) -> PackagesParser<R> {
+    ///
+    /// All store paths in the output must be located in `store_dir`.
+    pub fn new(reader: R, store_dir: String) -> PackagesParser<R> {
         PackagesParser {
-            events: EventReader::new(reader),
+            events: EventReader::new(PrefixRecorder {
+                inner: reader,
+                prefix: Vec::new(),
+            }),
+            found_root: false,
             current_item: None,
+            current_outputs: 0,
+            store_dir,
+            warnings: Vec::new(),
+            tolerant: false,
+            skipping_item: false,
         }
     }
 
+    /// Skips packages with recoverable errors (see `ParserError::is_recoverable`) if `true`,
+    /// instead of failing.
+    ///
+    /// The error is added to the warnings (see `take_warnings`) and parsing continues with the
+    /// next `<item>`, so the other outputs of the broken item are skipped as well. Errors that
+    /// mean that the document is not well-formed still stop the parser.
+    pub fn tolerant(mut self, tolerant: bool) -> PackagesParser<R> {
+        self.tolerant = tolerant;
+        self
+    }
+
+    /// Returns the warnings about unusual input that were found so far, such as packages
+    /// without any outputs, and clears them.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
     /// Shorthand for exiting with an error at the current position.
     fn err(&self, kind: ParserErrorKind) -> ParserError {
         ParserError {
@@ -275,11 +1206,33 @@ impl<R: Read> PackagesParser<R> {
         use self::XmlEvent::*;
 
         loop {
-            let event = self
-                .events
-                .next()
-                .map_err(|e| self.err(XmlError { error: e }))?;
+            let event = match self.events.next() {
+                Ok(event) => event,
+                // a syntax error before the root element means that the input is not XML
+                Err(_) if !self.found_root => {
+                    let found = self.events.source_mut().first_line();
+                    return Err(self.err(UnexpectedFormat { found }));
+                }
+                Err(e) => return Err(self.err(XmlError { error: e })),
+            };
+            if self.skipping_item {
+                if let EndElement { ref name } = event {
+                    if name.local_name == "item" {
+                        self.skipping_item = false;
+                        self.current_item = None;
+                    }
+                }
+                continue;
+            }
             match event {
+                StartElement { ref name, .. } if !self.found_root => {
+                    if name.local_name != "items" {
+                        return Err(self.err(UnexpectedFormat {
+                            found: format!("<{}>", name.local_name),
+                        }));
+                    }
+                    self.found_root = true;
+                }
                 StartElement {
                     name: element_name,
                     attributes,
@@ -323,6 +1276,7 @@ impl<R: Read> PackagesParser<R> {
                         })?;
 
                         self.current_item = Some((attr_path, system));
+                        self.current_outputs = 0;
                         continue;
                     }
 
@@ -363,10 +1317,19 @@ impl<R: Read> PackagesParser<R> {
                                 toplevel: true,
                                 system: Some(system),
                             };
-                            let store_path = StorePath::parse(origin, &output_path);
-                            let store_path = store_path
-                                .ok_or_else(|| self.err(InvalidStorePath { path: output_path }))?;
+                            let store_path = StorePath::parse_with_store_dir(
+                                origin,
+                                &output_path,
+                                &self.store_dir,
+                            );
+                            let store_path = store_path.ok_or_else(|| {
+                                self.err(InvalidStorePath {
+                                    path: output_path,
+                                    store_dir: self.store_dir.clone(),
+                                })
+                            })?;
 
+                            self.current_outputs += 1;
                             return Ok(Some(store_path));
                         } else {
                             return Err(self.err(MissingParent {
@@ -377,14 +1340,18 @@ impl<R: Read> PackagesParser<R> {
                     }
                 }
 
-                EndElement { name: element_name } => {
-                    if element_name.local_name == "item" {
-                        if self.current_item.is_none() {
-                            return Err(self.err(MissingStartTag {
-                                element_name: "item".into(),
-                            }));
-                        }
-                        self.current_item = None
+                EndElement { name: element_name } if element_name.local_name == "item" => {
+                    let Some((attr_path, _)) = self.current_item.take() else {
+                        return Err(self.err(MissingStartTag {
+                            element_name: "item".into(),
+                        }));
+                    };
+                    if self.current_outputs == 0 {
+                        let position = self.events.position();
+                        self.warnings.push(format!(
+                            "package {} at {} has no outputs and was skipped",
+                            attr_path, position
+                        ));
                     }
                 }
 
@@ -398,15 +1365,144 @@ impl<R: Read> PackagesParser<R> {
     }
 }
 
+/// A reader that keeps a copy of the first bytes that were read from it, so that the parser
+/// can show the start of input that is not in the expected format.
+struct PrefixRecorder<R> {
+    inner: R,
+    prefix: Vec<u8>,
+}
+
+/// The number of bytes that a `PrefixRecorder` keeps.
+const PREFIX_LEN: usize = 64;
+
+impl<R: Read> PrefixRecorder<R> {
+    /// Returns the first non-empty line of the input, reading more of it if necessary.
+    fn first_line(&mut self) -> String {
+        let mut buf = [0; PREFIX_LEN];
+        while self.prefix.len() < PREFIX_LEN {
+            match self.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+        let prefix = String::from_utf8_lossy(&self.prefix);
+        prefix
+            .trim_start()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+impl<R: Read> Read for PrefixRecorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let missing = PREFIX_LEN.saturating_sub(self.prefix.len()).min(n);
+        self.prefix.extend_from_slice(&buf[..missing]);
+        Ok(n)
+    }
+}
+
 impl<R: Read> Iterator for PackagesParser<R> {
     type Item = Result<StorePath, ParserError>;
 
     fn next(&mut self) -> Option<Result<StorePath, ParserError>> {
-        match self.next_err() {
-            Err(e) => Some(Err(e)),
-            Ok(Some(i)) => Some(Ok(i)),
-            Ok(None) => None,
+        loop {
+            match self.next_err() {
+                Err(e) if self.tolerant && e.is_recoverable() => {
+                    self.warnings
+                        .push(format!("{}, the package was skipped", e));
+                    self.skipping_item = true;
+                }
+                Err(e) => return Some(Err(e)),
+                Ok(Some(i)) => return Some(Ok(i)),
+                Ok(None) => return None,
+            }
+        }
+    }
+}
+
+/// A package in the JSON output of `nix-env -qaP --out-path --json`.
+#[derive(Deserialize)]
+struct JsonPackage {
+    system: String,
+    outputs: BTreeMap<String, String>,
+}
+
+/// Parses the JSON output of `nix-env` and returns individual store paths.
+///
+/// The output is a single JSON object, so it is read completely when the first store path
+/// is requested.
+struct PackagesParserJson<R: Read> {
+    reader: Option<R>,
+    packages: std::vec::IntoIter<(String, String, String, String)>,
+    store_dir: String,
+    /// Prepended to the attribute path of each package.
+    attr_prefix: Option<String>,
+}
+
+impl<R: Read> PackagesParserJson<R> {
+    /// Creates a new parser that reads the `nix-env` JSON output from the given reader.
+    ///
+    /// All store paths in the output must be located in `store_dir`.
+    pub fn new(reader: R, store_dir: String) -> PackagesParserJson<R> {
+        PackagesParserJson {
+            reader: Some(reader),
+            packages: Vec::new().into_iter(),
+            store_dir,
+            attr_prefix: None,
+        }
+    }
+
+    /// Reads the whole output, if that has not been done yet.
+    fn ensure_read(&mut self) -> Result<(), Error> {
+        if let Some(reader) = self.reader.take() {
+            let packages: BTreeMap<String, JsonPackage> =
+                serde_json::from_reader(io::BufReader::new(reader)).map_err(Error::Json)?;
+            let mut flat = Vec::new();
+            for (attr, package) in packages {
+                let attr = match self.attr_prefix {
+                    Some(ref prefix) => format!("{}{}", prefix, attr),
+                    None => attr,
+                };
+                for (output, path) in package.outputs {
+                    flat.push((attr.clone(), package.system.clone(), output, path));
+                }
+            }
+            self.packages = flat.into_iter();
         }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for PackagesParserJson<R> {
+    type Item = Result<StorePath, Error>;
+
+    fn next(&mut self) -> Option<Result<StorePath, Error>> {
+        if let Err(e) = self.ensure_read() {
+            return Some(Err(e));
+        }
+        let (attr, system, output, path) = self.packages.next()?;
+        let origin = PathOrigin {
+            attr,
+            output,
+            toplevel: true,
+            system: Some(system),
+        };
+        Some(
+            StorePath::parse_with_store_dir(origin, &path, &self.store_dir).ok_or_else(|| {
+                // the JSON output is read completely before, so there is no position to report
+                let kind = ParserErrorKind::InvalidStorePath {
+                    path,
+                    store_dir: self.store_dir.clone(),
+                };
+                Error::Parse(ParserError {
+                    position: TextPosition::new(),
+                    kind,
+                })
+            }),
+        )
     }
 }
 
@@ -416,6 +1512,9 @@ pub enum Error {
     /// Parsing of the output failed
     Parse(ParserError),
 
+    /// Parsing of the JSON output failed
+    Json(serde_json::Error),
+
     /// An IO error occurred
     Io(io::Error),
 
@@ -427,6 +1526,7 @@ impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
             Error::Parse(_) => "nix-env output parse error",
+            Error::Json(_) => "nix-env JSON output parse error",
             Error::Io(_) => "io error",
             Error::Command(_) => "nix-env error",
         }
@@ -438,6 +1538,7 @@ impl fmt::Display for Error {
         use self::Error::*;
         match *self {
             Parse(ref e) => write!(f, "parsing XML output of nix-env failed: {}", e),
+            Json(ref e) => write!(f, "parsing JSON output of nix-env failed: {}", e),
             Io(ref e) => write!(f, "IO error: {}", e),
             Command(ref e) => write!(f, "nix-env failed with error: {}", e),
         }
@@ -455,3 +1556,402 @@ impl From<ParserError> for Error {
         Error::Parse(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Only one fake program is used at a time. Otherwise, a process that is spawned by one
+    /// test could inherit the handle of a script that another test is writing, so the script
+    /// would be busy when that test runs it.
+    #[cfg(unix)]
+    static FAKE_PROGRAM_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A shell script in the temp directory that stands in for nix-env or nix. It is removed
+    /// when this is dropped.
+    #[cfg(unix)]
+    struct FakeProgram {
+        path: PathBuf,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    #[cfg(unix)]
+    impl FakeProgram {
+        fn new(script: &str) -> io::Result<FakeProgram> {
+            use std::os::unix::fs::PermissionsExt;
+
+            let lock = FAKE_PROGRAM_LOCK
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let script = format!("#!/bin/sh\n{}\n", script);
+            let path = util::write_temp_file("nix-index-fake-program", script.as_bytes())
+                .ok_or_else(|| io::Error::other("could not write the fake program"))?;
+            let program = FakeProgram { path, _lock: lock };
+            std::fs::set_permissions(&program.path, std::fs::Permissions::from_mode(0o755))?;
+            Ok(program)
+        }
+
+        /// Returns a command that runs this program like `PackagesQuery` runs nix-env.
+        fn command(&self) -> Command {
+            let mut cmd = Command::new(&self.path);
+            cmd.stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .stdin(Stdio::null());
+            cmd
+        }
+    }
+
+    #[cfg(unix)]
+    impl Drop for FakeProgram {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Returns a store path in the default store dir with a hash made of `hash`.
+    fn store_path(hash: char, name: &str) -> String {
+        format!(
+            "{}/{}-{}",
+            DEFAULT_STORE_DIR,
+            hash.to_string().repeat(32),
+            name
+        )
+    }
+
+    /// Returns the attribute path, output and store path of each package.
+    fn summarize<I, E>(packages: I) -> Result<Vec<(String, String, String)>, Error>
+    where
+        I: IntoIterator<Item = Result<StorePath, E>>,
+        Error: From<E>,
+    {
+        packages
+            .into_iter()
+            .map(|path| {
+                let path = path?;
+                let origin = path.origin_ref();
+                Ok((
+                    origin.attr.clone(),
+                    origin.output.clone(),
+                    path.as_str().into_owned(),
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_flake_query_parses_json_and_prefixes_attr_paths() -> Result<(), Error> {
+        // the format that `FLAKE_PACKAGES_EXPR` evaluates to
+        let output = format!(
+            r#"{{"hello":{{"system":"x86_64-linux","outputs":{{"out":"{}","man":"{}"}}}}}}"#,
+            store_path('0', "hello-2.12"),
+            store_path('1', "hello-2.12-man"),
+        );
+        let nix = FakeProgram::new(&format!("echo '{}'", output))?;
+        // JSON is parsed even if XML was requested
+        let mut query = query_packages_flake("nixpkgs", "legacyPackages.x86_64-linux").json(false);
+        query.cmd = Some(nix.command());
+        query.store_dir = DEFAULT_STORE_DIR.to_string();
+
+        let attr = "legacyPackages.x86_64-linux.hello".to_string();
+        let expected = [
+            (
+                attr.clone(),
+                "man".to_string(),
+                store_path('1', "hello-2.12-man"),
+            ),
+            (attr, "out".to_string(), store_path('0', "hello-2.12")),
+        ];
+        assert_eq!(summarize(query)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_parser_returns_all_outputs_with_attr_prefix() -> Result<(), Error> {
+        let json = format!(
+            r#"{{
+  "openssl": {{"system": "x86_64-linux", "outputs": {{"out": "{}", "dev": "{}", "man": "{}"}}}},
+  "hello": {{"system": "x86_64-linux", "outputs": {{"out": "{}"}}}}
+}}"#,
+            store_path('0', "openssl-3.0"),
+            store_path('1', "openssl-3.0-dev"),
+            store_path('2', "openssl-3.0-man"),
+            store_path('3', "hello-2.12"),
+        );
+        let mut parser = PackagesParserJson::new(json.as_bytes(), DEFAULT_STORE_DIR.to_string());
+        parser.attr_prefix = Some("pkgs.".to_string());
+        let expected = [
+            ("pkgs.hello", "out", store_path('3', "hello-2.12")),
+            ("pkgs.openssl", "dev", store_path('1', "openssl-3.0-dev")),
+            ("pkgs.openssl", "man", store_path('2', "openssl-3.0-man")),
+            ("pkgs.openssl", "out", store_path('0', "openssl-3.0")),
+        ]
+        .map(|(attr, output, path)| (attr.to_string(), output.to_string(), path));
+        assert_eq!(summarize(parser)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_parser_reports_invalid_store_paths_as_recoverable() -> Result<(), Error> {
+        let json = format!(
+            r#"{{
+  "broken": {{"system": "x86_64-linux", "outputs": {{"out": "/tmp/broken-1.0"}}}},
+  "hello": {{"system": "x86_64-linux", "outputs": {{"out": "{}"}}}}
+}}"#,
+            store_path('0', "hello-2.12"),
+        );
+        let mut parser = PackagesParserJson::new(json.as_bytes(), DEFAULT_STORE_DIR.to_string());
+        match parser.next() {
+            Some(Err(Error::Parse(e))) => {
+                assert!(e.is_recoverable());
+                match e.kind() {
+                    ParserErrorKind::InvalidStorePath { path, .. } => {
+                        assert_eq!(path, "/tmp/broken-1.0")
+                    }
+                    kind => panic!("unexpected error: {}", kind),
+                }
+            }
+            result => panic!("unexpected result: {:?}", result.map(|r| r.map(|_| ()))),
+        }
+        // the other packages are still returned after the error
+        let expected = [(
+            "hello".to_string(),
+            "out".to_string(),
+            store_path('0', "hello-2.12"),
+        )];
+        assert_eq!(summarize(parser)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_nix_env_is_killed_when_it_times_out() -> Result<(), Error> {
+        let query = |script: &str, stall_timeout, total_timeout| -> Result<String, Error> {
+            let nix_env = FakeProgram::new(script)?;
+            let start = Instant::now();
+            let mut query = query_packages_with("<nixpkgs>", None, None, false, &nix_env.path, &[])
+                .timeouts(stall_timeout, total_timeout);
+            let result = query.next();
+            // the script would run for far longer if it was not killed
+            assert!(start.elapsed() < Duration::from_secs(5));
+            match result {
+                Some(Err(Error::Command(message))) => Ok(message),
+                result => panic!("unexpected result: {:?}", result.map(|r| r.map(|_| ()))),
+            }
+        };
+
+        let silent = query(
+            "exec sleep 30",
+            Duration::from_millis(200),
+            Duration::from_secs(30),
+        )?;
+        assert_eq!(
+            silent,
+            "nix-env was killed because it did not print any output for 200ms"
+        );
+        let endless = query(
+            "while :; do echo; sleep 0.05; done",
+            Duration::from_secs(30),
+            Duration::from_millis(300),
+        )?;
+        assert_eq!(
+            endless,
+            "nix-env was killed because it did not finish within 300ms"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tolerant_parser_skips_broken_items() -> Result<(), Error> {
+        let xml = format!(
+            r#"<?xml version='1.0' encoding='utf-8'?>
+<items>
+  <item attrPath="first" name="a" system="x86_64-linux">
+    <output name="out" path="{}" />
+  </item>
+  <item name="no-attr-path" system="x86_64-linux">
+    <output name="out" path="{}" />
+  </item>
+  <item attrPath="second" name="b" system="x86_64-linux">
+    <output name="out" path="{}" />
+  </item>
+  <item attrPath="broken" name="c" system="x86_64-linux">
+    <output name="out" path="/tmp/broken-1.0" />
+    <output name="dev" path="{}" />
+  </item>
+  <item attrPath="third" name="d" system="x86_64-linux">
+    <output name="out" path="{}" />
+  </item>
+</items>"#,
+            store_path('0', "first"),
+            store_path('1', "no-attr-path"),
+            store_path('2', "second"),
+            store_path('3', "broken-1.0-dev"),
+            store_path('4', "third"),
+        );
+        let mut parser =
+            PackagesParser::new(xml.as_bytes(), DEFAULT_STORE_DIR.to_string()).tolerant(true);
+        let expected = [
+            ("first", store_path('0', "first")),
+            ("second", store_path('2', "second")),
+            ("third", store_path('4', "third")),
+        ]
+        .map(|(attr, path)| (attr.to_string(), "out".to_string(), path));
+        assert_eq!(summarize(&mut parser)?, expected);
+
+        let warnings = parser.take_warnings();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0].contains("attrPath"), "{}", warnings[0]);
+        assert!(warnings[1].contains("/tmp/broken-1.0"), "{}", warnings[1]);
+
+        // without tolerance, the first broken item stops the parser
+        let mut parser = PackagesParser::new(xml.as_bytes(), DEFAULT_STORE_DIR.to_string());
+        assert!(matches!(parser.next(), Some(Ok(_))));
+        match parser.next() {
+            Some(Err(e)) => assert!(e.is_recoverable()),
+            result => panic!("unexpected result: {:?}", result.map(|r| r.map(|_| ()))),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tolerant_parser_stops_at_xml_errors() {
+        let xml = format!(
+            r#"<?xml version='1.0' encoding='utf-8'?>
+<items>
+  <item attrPath="first" name="a" system="x86_64-linux">
+    <output name="out" path="{}" />
+  </item>
+  <item attrPath="second" name="b" system="x86_64-linux">
+    <output name="out" path="{}" /
+  </item>
+</items>"#,
+            store_path('0', "first"),
+            store_path('1', "second"),
+        );
+        let mut parser =
+            PackagesParser::new(xml.as_bytes(), DEFAULT_STORE_DIR.to_string()).tolerant(true);
+        assert!(matches!(parser.next(), Some(Ok(_))));
+        match parser.next() {
+            Some(Err(e)) => {
+                assert!(
+                    matches!(e.kind(), ParserErrorKind::XmlError { .. }),
+                    "{}",
+                    e
+                );
+                assert!(!e.is_recoverable());
+            }
+            result => panic!("unexpected result: {:?}", result.map(|r| r.map(|_| ()))),
+        }
+        assert!(parser.take_warnings().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_transient_errors_are_retried() -> Result<(), Error> {
+        let runs = std::env::temp_dir().join(format!("nix-index-retries-{}", std::process::id()));
+        let nix_env = FakeProgram::new(&format!(
+            "echo run >> '{}'\necho \"error: unable to download 'https://example.org'\" >&2\nexit 1",
+            runs.display()
+        ))?;
+        let mut query = query_packages_with_retries("<nixpkgs>", None, None, false, 2)
+            .initial_delay(Duration::from_millis(1));
+        query.nix_env_bin = nix_env.path.clone();
+        query.query = query_packages_with("<nixpkgs>", None, None, false, &nix_env.path, &[]);
+
+        let result = query.next();
+        let count = std::fs::read_to_string(&runs).map(|runs| runs.lines().count());
+        let _ = std::fs::remove_file(&runs);
+        assert_eq!(count?, 3);
+        match result {
+            Some(Err(Error::Command(message))) => {
+                assert!(message.contains("unable to download"), "{}", message);
+                assert!(
+                    message.ends_with("(gave up after 3 attempts)"),
+                    "{}",
+                    message
+                );
+            }
+            result => panic!("unexpected result: {:?}", result.map(|r| r.map(|_| ()))),
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_parallel_query_returns_the_packages_of_all_shards() -> Result<(), Error> {
+        // prints one package whose attribute path and store path tell the arguments apart
+        let nix_env = FakeProgram::new(
+            r#"while [ $# -gt 0 ]; do
+  case "$1 $2" in
+    "--argstr nixpkgs") nixpkgs=$3 ;;
+    "--argstr shard") shard=$3 ;;
+    "--argstr shards") shards=$3 ;;
+  esac
+  shift
+done
+cat <<EOF
+<?xml version='1.0' encoding='utf-8'?>
+<items>
+  <item attrPath="$nixpkgs.shard-$shard-of-$shards" name="p" system="x86_64-linux">
+    <output name="out" path="/nix/store/$(printf '%032d' 0 | tr 0 "$shard")-shard" />
+  </item>
+</items>
+EOF"#,
+        )?;
+        let nixpkgs = "https://example.org/nixpkgs.tar.gz";
+        let query = |broken_shard: Option<usize>| -> Result<_, Error> {
+            let mut query = query_packages_parallel(nixpkgs, None, false, &nix_env.path, 3)?;
+            for query in &mut query.queries {
+                query.store_dir = DEFAULT_STORE_DIR.to_string();
+            }
+            if let Some(shard) = broken_shard {
+                query.queries[shard].cmd = Some(Command::new("/nonexistent/nix-env"));
+            }
+            Ok(query.collect::<Vec<_>>())
+        };
+        let expected = |shard: usize| {
+            (
+                format!("{}.shard-{}-of-3", nixpkgs, shard),
+                "out".to_string(),
+                store_path(char::from(b'0' + shard as u8), "shard"),
+            )
+        };
+
+        assert_eq!(summarize(query(None)?)?, [0, 1, 2].map(expected));
+
+        // the other shards still run if one of them cannot be started
+        let mut results = query(Some(1))?.into_iter();
+        assert!(matches!(results.next(), Some(Err(Error::Io(_)))));
+        assert_eq!(summarize(results)?, [0, 2].map(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_xml_entities_and_unicode_are_decoded() -> Result<(), Error> {
+        let xml = format!(
+            r#"<?xml version='1.0' encoding='utf-8'?>
+<items>
+  <item attrPath="libsForQt5.callPackage &amp; friends" name="a" system="x86_64-linux">
+    <output name="out" path="{}" />
+  </item>
+  <item attrPath="pkgs.&quot;héllo wörld&quot;" name="b" system="x86_64-linux">
+    <output name="out" path="{}" />
+  </item>
+</items>"#,
+            store_path('0', "caf&#233; au lait-1.0"),
+            store_path('1', "h&#xE9;llo &amp; w&#246;rld"),
+        );
+        let parser = PackagesParser::new(xml.as_bytes(), DEFAULT_STORE_DIR.to_string());
+        let expected = [
+            (
+                "libsForQt5.callPackage & friends",
+                store_path('0', "café au lait-1.0"),
+            ),
+            ("pkgs.\"héllo wörld\"", store_path('1', "héllo & wörld")),
+        ]
+        .map(|(attr, path)| (attr.to_string(), "out".to_string(), path));
+        assert_eq!(summarize(parser)?, expected);
+        Ok(())
+    }
+}
@@ -4,11 +4,16 @@
 //! some nix derivation. We also sometimes call a `StorePath` a package, to avoid
 //! confusion with file paths.
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
 use std::io::{self, Write};
 use std::str;
 
 use serde::{Deserialize, Serialize};
 
+/// The default location of the nix store, used unless `NIX_STORE_DIR` is set.
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+
 /// A type for describing how to reach a given store path.
 ///
 /// When building an index, we collect store paths from various sources, such
@@ -108,6 +113,15 @@ impl PathOrigin {
     }
 }
 
+/// The characters used by the base32 encoding of nix hashes (which omits `e`, `o`, `u` and `t`).
+const NIX_BASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Returns whether the given string is a valid store path hash, which consists of exactly
+/// 32 characters of the nix base32 alphabet.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 32 && hash.bytes().all(|c| NIX_BASE32_CHARS.contains(&c))
+}
+
 /// Represents a store path which is something that is produced by `nix-build`.
 ///
 /// A store path represents an output in the nix store, matching the pattern
@@ -129,7 +143,11 @@ impl PathOrigin {
 /// ```
 ///
 /// To avoid any confusion with file paths, we sometimes also refer to a store path as a *package*.
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+///
+/// Store paths are ordered by name, then by hash and then by the output of their origin.
+/// Ties are broken by the store dir and the remaining fields of the origin. This ordering
+/// is stable and can be relied on for sorting results.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub struct StorePath {
     store_dir: String,
     hash: String,
@@ -144,27 +162,65 @@ impl StorePath {
     /// `origin` as an argument.
     ///
     /// This function returns `None` if the path could not be parsed as a
-    /// store path. You should not rely on that to check whether a path is a store
-    /// path though, since it only does minimal validation (for one example, it does
-    /// not check the length of the hash).
+    /// store path, which includes paths whose hash is not a valid nix hash (32 characters
+    /// of the nix base32 alphabet). You should not rely on that to check whether a path is
+    /// a store path though, since it does not check anything else.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "dummy".to_string(), output: "out".to_string(), toplevel: true, system: None };
+    /// assert!(StorePath::parse(origin.clone(), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").is_some());
+    /// assert!(StorePath::parse(origin.clone(), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2y-bash-4.4-p5").is_none());
+    /// assert!(StorePath::parse(origin, "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-bash-4.4-p5").is_none());
+    /// ```
     pub fn parse(origin: PathOrigin, path: &str) -> Option<StorePath> {
-        let mut parts = path.splitn(2, '-');
-        parts.next().and_then(|prefix| {
-            parts.next().and_then(|name| {
-                let mut iter = prefix.rsplitn(2, '/');
-                iter.next().map(|hash| {
-                    let store_dir = iter.next().unwrap_or("");
-                    StorePath {
-                        store_dir: store_dir.to_string(),
-                        hash: hash.to_string(),
-                        name: name.to_string(),
-                        origin,
-                    }
-                })
-            })
+        let (store_dir, base) = path.rsplit_once('/').unwrap_or(("", path));
+        let (hash, name) = base.split_once('-')?;
+        if !is_valid_hash(hash) {
+            return None;
+        }
+        Some(StorePath {
+            store_dir: store_dir.to_string(),
+            hash: hash.to_string(),
+            name: name.to_string(),
+            origin,
         })
     }
 
+    /// Parse a store path from an absolute file path, requiring it to be a direct child of
+    /// the given store directory.
+    ///
+    /// Unlike `parse`, which accepts paths from any store directory, this function returns
+    /// `None` if the path is not of the form `store_dir/hash-name`. A trailing slash in
+    /// `store_dir` is ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "dummy".to_string(), output: "out".to_string(), toplevel: true, system: None };
+    /// let path = "/home/user/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5";
+    /// let store_path = StorePath::parse_with_store_dir(origin.clone(), path, "/home/user/store").unwrap();
+    /// assert_eq!(&store_path.store_dir(), "/home/user/store");
+    /// assert!(StorePath::parse_with_store_dir(origin, path, "/nix/store").is_none());
+    /// ```
+    pub fn parse_with_store_dir(
+        origin: PathOrigin,
+        path: &str,
+        store_dir: &str,
+    ) -> Option<StorePath> {
+        let store_dir = store_dir.strip_suffix('/').unwrap_or(store_dir);
+        let base = path.strip_prefix(store_dir)?.strip_prefix('/')?;
+        if base.contains('/') {
+            return None;
+        }
+        StorePath::parse(origin, path).filter(|p| !p.name.is_empty())
+    }
+
     /// Encodes a store path as a sequence of bytes, so that it can be decoded with `decode`.
     ///
     /// The encoding does not use the bytes `0x00` nor `0x01`, as long as none of the fields of
@@ -208,7 +264,7 @@ impl StorePath {
     /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
     /// assert_eq!(&store_path.name(), "bash-4.4-p5");
     /// ```
-    pub fn name(&self) -> Cow<str> {
+    pub fn name(&self) -> Cow<'_, str> {
         Cow::Borrowed(&self.name)
     }
 
@@ -224,10 +280,68 @@ impl StorePath {
     /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
     /// assert_eq!(&store_path.name(), "bash-4.4-p5");
     /// ```
-    pub fn hash(&self) -> Cow<str> {
+    pub fn hash(&self) -> Cow<'_, str> {
         Cow::Borrowed(&self.hash)
     }
 
+    /// The name of the package without its version, following the conventions of
+    /// `builtins.parseDrvName`: the version starts after the first dash that is not followed
+    /// by a letter. If the store path is a non-default output (for example, `man`), the output
+    /// suffix is not part of the result.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "dummy".to_string(), output: "man".to_string(), toplevel: true, system: None };
+    /// let store_path = StorePath::parse(origin.clone(), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5-man").unwrap();
+    /// assert_eq!(store_path.pname(), "bash");
+    /// let store_path = StorePath::parse(origin.clone(), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-gtk+-2.24").unwrap();
+    /// assert_eq!(store_path.pname(), "gtk+");
+    /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-xorg-server").unwrap();
+    /// assert_eq!(store_path.pname(), "xorg-server");
+    /// ```
+    pub fn pname(&self) -> &str {
+        self.split_name().0
+    }
+
+    /// The version of the package, if the name contains one. See `pname` for how the
+    /// name is split.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "dummy".to_string(), output: "man".to_string(), toplevel: true, system: None };
+    /// let store_path = StorePath::parse(origin.clone(), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5-man").unwrap();
+    /// assert_eq!(store_path.version(), Some("4.4-p5"));
+    /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-hello").unwrap();
+    /// assert_eq!(store_path.version(), None);
+    /// ```
+    pub fn version(&self) -> Option<&str> {
+        self.split_name().1
+    }
+
+    /// Splits the name (without the output suffix) into the package name and the version.
+    fn split_name(&self) -> (&str, Option<&str>) {
+        let output = &self.origin.output;
+        let name = match self.name.strip_suffix(output.as_str()) {
+            Some(rest) if output != "out" => rest.strip_suffix('-').unwrap_or(&self.name),
+            _ => &self.name,
+        };
+
+        let bytes = name.as_bytes();
+        let split = (0..bytes.len()).find(|&i| {
+            bytes[i] == b'-' && bytes.get(i + 1).is_some_and(|c| !c.is_ascii_alphabetic())
+        });
+        match split {
+            Some(i) => (&name[..i], Some(&name[i + 1..])),
+            None => (name, None),
+        }
+    }
+
     /// The store dir for which this store path was built.
     ///
     /// Currently, this will be `/nix/store` in almost all cases, but
@@ -242,7 +356,7 @@ impl StorePath {
     /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
     /// assert_eq!(&store_path.store_dir(), "/nix/store");
     /// ```
-    pub fn store_dir(&self) -> Cow<str> {
+    pub fn store_dir(&self) -> Cow<'_, str> {
         Cow::Borrowed(&self.store_dir)
     }
 
@@ -257,8 +371,30 @@ impl StorePath {
     /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
     /// assert_eq!(&store_path.as_str(), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5");
     /// ```
-    pub fn as_str(&self) -> Cow<str> {
-        Cow::Owned(format!("{}/{}-{}", self.store_dir, self.hash, self.name))
+    pub fn as_str(&self) -> Cow<'_, str> {
+        Cow::Owned(self.to_string())
+    }
+
+    /// Moves the store path to another store dir, keeping its hash, name and origin.
+    ///
+    /// This is useful to show the paths of a database that was built for `/nix/store`
+    /// relative to a custom store dir. A trailing slash in `store_dir` is ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "dummy".to_string(), output: "out".to_string(), toplevel: true, system: None };
+    /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
+    /// let store_path = store_path.with_store_dir("/home/user/store/");
+    /// assert_eq!(store_path.to_string(), "/home/user/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5");
+    /// ```
+    pub fn with_store_dir(self, store_dir: &str) -> StorePath {
+        StorePath {
+            store_dir: store_dir.strip_suffix('/').unwrap_or(store_dir).to_string(),
+            ..self
+        }
     }
 
     /// Returns the origin that describes how we discovered this store path.
@@ -274,7 +410,57 @@ impl StorePath {
     /// let store_path = StorePath::parse(origin.clone(), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
     /// assert_eq!(store_path.origin().as_ref(), &origin);
     /// ```
-    pub fn origin(&self) -> Cow<PathOrigin> {
+    pub fn origin(&self) -> Cow<'_, PathOrigin> {
         Cow::Borrowed(&self.origin)
     }
+
+    /// Returns a reference to the origin that describes how we discovered this store path.
+    ///
+    /// This is the same as `origin`, but is useful if you need a plain reference, for example
+    /// to get at the `output` field:
+    ///
+    /// ```
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "dummy".to_string(), output: "man".to_string(), toplevel: true, system: None };
+    /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5-man").unwrap();
+    /// assert_eq!(store_path.origin_ref().output, "man");
+    /// ```
+    pub fn origin_ref(&self) -> &PathOrigin {
+        &self.origin
+    }
+}
+
+/// Formats the store path as an absolute path, `store_dir/hash-name`.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::package::{PathOrigin, StorePath};
+///
+/// let origin = PathOrigin { attr: "dummy".to_string(), output: "out".to_string(), toplevel: true, system: None };
+/// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
+/// assert_eq!(format!("{}/bin/bash", store_path), "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5/bin/bash");
+/// ```
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}-{}", self.store_dir, self.hash, self.name)
+    }
+}
+
+impl Ord for StorePath {
+    fn cmp(&self, other: &StorePath) -> Ordering {
+        self.name
+            .cmp(&other.name)
+            .then_with(|| self.hash.cmp(&other.hash))
+            .then_with(|| self.origin.output.cmp(&other.origin.output))
+            .then_with(|| self.store_dir.cmp(&other.store_dir))
+            .then_with(|| self.origin.cmp(&other.origin))
+    }
+}
+
+impl PartialOrd for StorePath {
+    fn partial_cmp(&self, other: &StorePath) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }